@@ -0,0 +1,324 @@
+use chrono::{Local, NaiveTime};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::error::RecvError;
+use tokio_compat_02::FutureExt;
+
+use telegram_bot::{prelude::*, Api, ChatId, InputFileRef, InputFileUpload};
+
+use crate::dispatcher::Dispatcher;
+use crate::error::BotError;
+use crate::history::{HistoryEntry, Outcome};
+use crate::motion::{MotionEvent, MotionEvents};
+use crate::mp4::Mp4RecorderOptions;
+use crate::recording_coordinator::{RecordingCoordinator, RecordingStart};
+use crate::send_video_command::{get_camera_configs, video_file_id, Camera, CameraConfig};
+use crate::store::Store;
+use crate::subscriptions::Subscription;
+
+fn default_min_interval() -> u64 {
+    300
+}
+
+fn default_clip_duration() -> u64 {
+    10
+}
+
+/// How motion alerts are delivered to subscribed chats.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct AlertsConfig {
+    /// Seconds between two alerts of the same camera, motion in between is ignored.
+    #[serde(default = "default_min_interval")]
+    pub min_interval: u64,
+
+    /// Length in seconds of the clip sent with each alert.
+    #[serde(default = "default_clip_duration")]
+    pub clip_duration: u64,
+
+    /// Local time range without alerts, e.g. `22:00` to `07:00`.
+    #[serde(default)]
+    pub quiet_hours: Option<QuietHours>,
+}
+
+impl Default for AlertsConfig {
+    fn default() -> Self {
+        AlertsConfig {
+            min_interval: default_min_interval(),
+            clip_duration: default_clip_duration(),
+            quiet_hours: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QuietHours {
+    #[serde(with = "hh_mm")]
+    pub start: NaiveTime,
+    #[serde(with = "hh_mm")]
+    pub end: NaiveTime,
+}
+
+impl QuietHours {
+    /// Whether `time` is in the range, which may span midnight.
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// `HH:MM` or `HH:MM:SS` times in the config.
+mod hh_mm {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(time: &NaiveTime, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&time.format("%H:%M:%S").to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+        let time = String::deserialize(deserializer)?;
+
+        NaiveTime::parse_from_str(&time, "%H:%M:%S")
+            .or_else(|_| NaiveTime::parse_from_str(&time, "%H:%M"))
+            .map_err(|_| {
+                serde::de::Error::custom(format!("invalid time '{}', expected HH:MM", time))
+            })
+    }
+}
+
+/// Subscriptions whose user may still see `camera`, as access can change after subscribing.
+fn allowed_subscriptions(
+    camera_config: &CameraConfig,
+    camera: &Camera,
+    subscriptions: Vec<Subscription>,
+) -> Vec<Subscription> {
+    let access = &camera_config.access;
+
+    subscriptions
+        .into_iter()
+        .filter(|subscription| {
+            access.is_allowed(subscription.user_id, subscription.chat_id)
+                && access.can_view_camera(camera, subscription.user_id)
+        })
+        .collect()
+}
+
+/// Records a clip of the camera and sends it to every subscribed chat,
+/// uploading it once and re-sending it by file id.
+async fn send_alert(
+    api: Api,
+    camera: Camera,
+    event: MotionEvent,
+    subscriptions: Vec<Subscription>,
+    clip_duration: u64,
+    coordinator: Arc<RecordingCoordinator>,
+    store: Arc<Store>,
+) -> Result<(), BotError> {
+    let mut options = Mp4RecorderOptions::try_from(camera.clone())?;
+    options.duration = clip_duration;
+    let (recording, start) = coordinator.record(&camera.name, options);
+    let clip_duration = match start {
+        RecordingStart::New => clip_duration,
+        RecordingStart::Joined { duration } => duration,
+    };
+
+    let started = Instant::now();
+    let new_entry = |chat_id| {
+        let mut entry = HistoryEntry::new(camera.name.clone(), None, chat_id);
+        entry.started_at = event.at;
+        entry.duration = clip_duration;
+        entry
+    };
+
+    let recording = match recording.compat().await {
+        Ok(recording) => recording,
+        Err(source) => {
+            let recording_error = BotError::Recording {
+                camera: camera.name.clone(),
+                source,
+            };
+
+            for subscription in &subscriptions {
+                let mut entry = new_entry(subscription.chat_id);
+                entry.outcome = Outcome::Failed {
+                    reason: recording_error.to_string(),
+                };
+                entry.elapsed_ms = started.elapsed().as_millis() as u64;
+                store.history.record(entry).await;
+            }
+
+            return Err(recording_error);
+        }
+    };
+
+    let caption = format!(
+        "Motion detected on camera {} at {}",
+        camera.name,
+        event.at.with_timezone(&Local).format("%H:%M:%S")
+    );
+
+    for subscription in subscriptions {
+        let chat = ChatId::new(subscription.chat_id);
+
+        let mut request = match recording.file_id.get() {
+            Some(file_id) => chat.video(InputFileRef::new(file_id.clone())),
+            None => chat.video(InputFileUpload::with_path(
+                recording
+                    .output
+                    .clone()
+                    .into_os_string()
+                    .into_string()
+                    .unwrap(),
+            )),
+        };
+        request.caption(&caption);
+
+        let mut entry = new_entry(subscription.chat_id);
+        entry.set_recording(&recording).await;
+
+        match api.send(request).await {
+            Ok(message) => {
+                if let Some(file_id) = video_file_id(&message) {
+                    let _ = recording.file_id.set(file_id);
+                }
+            }
+            Err(send_error) => {
+                log::error!(
+                    "Failed to send motion alert of camera {} to chat {}: {}",
+                    camera.name,
+                    subscription.chat_id,
+                    send_error
+                );
+                entry.outcome = Outcome::Failed {
+                    reason: send_error.to_string(),
+                };
+            }
+        }
+
+        entry.elapsed_ms = started.elapsed().as_millis() as u64;
+        store.history.record(entry).await;
+    }
+
+    Ok(())
+}
+
+/// Sends a clip to the subscribed chats for every motion event, except for disarmed
+/// cameras, during quiet hours or when the camera alerted less than `minInterval` ago.
+///
+/// Alerts are sent through `dispatcher`, so they count towards the concurrency
+/// limit and are waited for on shutdown like commands.
+pub async fn run_alerts(
+    api: Api,
+    events: MotionEvents,
+    coordinator: Arc<RecordingCoordinator>,
+    store: Arc<Store>,
+    dispatcher: Arc<Dispatcher>,
+) {
+    let mut receiver = events.subscribe();
+    let mut last_alerts: HashMap<String, Instant> = HashMap::new();
+
+    loop {
+        let event = match receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                log::warn!("Skipped {} motion events", skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        let camera_subscriptions = store.subscriptions.for_camera(&event.camera);
+        if camera_subscriptions.is_empty() {
+            continue;
+        }
+
+        let camera_config = match get_camera_configs(&store) {
+            Ok(camera_config) => camera_config,
+            Err(config_error) => {
+                log::error!("Failed to load camera config. Ignoring motion event.");
+                log::error!("{:?}", config_error);
+                continue;
+            }
+        };
+
+        let Some(camera) = camera_config
+            .cameras
+            .iter()
+            .find(|camera| camera.name == event.camera)
+            .cloned()
+        else {
+            continue;
+        };
+
+        if !store.arming.is_armed(&camera) {
+            log::debug!("Ignoring motion on disarmed camera {}", camera.name);
+            continue;
+        }
+
+        let alerts = &camera_config.alerts;
+        let now = Local::now().time();
+        if alerts
+            .quiet_hours
+            .as_ref()
+            .is_some_and(|quiet_hours| quiet_hours.contains(now))
+        {
+            log::debug!(
+                "Ignoring motion on camera {} during quiet hours",
+                camera.name
+            );
+            continue;
+        }
+
+        let min_interval = Duration::from_secs(alerts.min_interval);
+        if last_alerts
+            .get(&camera.name)
+            .is_some_and(|last_alert| last_alert.elapsed() < min_interval)
+        {
+            log::debug!(
+                "Ignoring motion on camera {}, it alerted recently",
+                camera.name
+            );
+            continue;
+        }
+
+        let camera_subscriptions =
+            allowed_subscriptions(&camera_config, &camera, camera_subscriptions);
+        if camera_subscriptions.is_empty() {
+            continue;
+        }
+
+        last_alerts.insert(camera.name.clone(), Instant::now());
+        log::info!(
+            "Sending motion alert of camera {} to {} chat(s)",
+            camera.name,
+            camera_subscriptions.len()
+        );
+
+        let camera_name = camera.name.clone();
+        let alert = send_alert(
+            api.clone(),
+            camera,
+            event,
+            camera_subscriptions,
+            alerts.clip_duration,
+            coordinator.clone(),
+            store.clone(),
+        );
+
+        dispatcher.dispatch_background(async move {
+            if let Err(err) = alert.compat().await {
+                log::error!(
+                    "Failed to send motion alert of camera {}: {}",
+                    camera_name,
+                    err
+                );
+            }
+        });
+    }
+}
@@ -0,0 +1,86 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::time::sleep;
+
+use crate::error::BotError;
+use crate::send_video_command::Camera;
+use crate::store::JsonFile;
+
+/// How often tasks waiting for a camera to be armed or disarmed check its state.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ArmingState {
+    /// Disarming globally disarms every camera, whatever their own state.
+    armed: bool,
+
+    /// Overrides of the `armed` field of cameras, by camera name.
+    cameras: BTreeMap<String, bool>,
+}
+
+impl Default for ArmingState {
+    fn default() -> Self {
+        ArmingState {
+            armed: true,
+            cameras: BTreeMap::new(),
+        }
+    }
+}
+
+/// Whether cameras may be watched, set with the arm and disarm commands.
+pub struct Arming {
+    state: JsonFile<ArmingState>,
+}
+
+impl Arming {
+    /// A missing file means everything is armed as configured.
+    pub fn open(path: PathBuf) -> Result<Self, BotError> {
+        Ok(Arming {
+            state: JsonFile::open(path)?,
+        })
+    }
+
+    pub fn is_globally_armed(&self) -> bool {
+        self.state.read(|state| state.armed)
+    }
+
+    /// The state of the camera itself, ignoring the global one.
+    pub fn is_camera_armed(&self, camera: &Camera) -> bool {
+        self.state
+            .read(|state| state.cameras.get(&camera.name).copied())
+            .unwrap_or(camera.armed)
+    }
+
+    pub fn is_armed(&self, camera: &Camera) -> bool {
+        self.is_globally_armed() && self.is_camera_armed(camera)
+    }
+
+    /// Returns once `camera` is armed, or disarmed when `armed` is false.
+    pub async fn wait_until(&self, camera: &Camera, armed: bool) {
+        while self.is_armed(camera) != armed {
+            sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    /// Splits `cameras` into the armed and the disarmed ones.
+    pub fn partition(&self, cameras: Vec<Camera>) -> (Vec<Camera>, Vec<Camera>) {
+        cameras
+            .into_iter()
+            .partition(|camera| self.is_armed(camera))
+    }
+
+    pub async fn set_globally_armed(&self, armed: bool) -> Result<(), BotError> {
+        self.state.update(|state| state.armed = armed).await
+    }
+
+    pub async fn set_camera_armed(&self, camera_name: &str, armed: bool) -> Result<(), BotError> {
+        self.state
+            .update(|state| {
+                state.cameras.insert(camera_name.to_string(), armed);
+            })
+            .await
+    }
+}
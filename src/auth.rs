@@ -0,0 +1,82 @@
+use serde::{Deserialize, Serialize};
+use telegram_bot::{prelude::*, Api, ChatId, Message};
+
+use crate::send_video_command::Camera;
+use crate::store::{Role, Roles};
+
+/// Who is allowed to talk to the bot.
+///
+/// A message is authorized when its sender is listed in `allowed_user_ids` or
+/// `admin_ids`, or when it was sent in one of the `allowed_chat_ids`.
+/// Nobody is authorized when every list is empty.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default, deny_unknown_fields)]
+pub struct AccessConfig {
+    pub allowed_user_ids: Vec<i64>,
+    pub allowed_chat_ids: Vec<i64>,
+    pub admin_ids: Vec<i64>,
+
+    /// Chat that gets notified about unauthorized attempts, if any.
+    pub report_chat_id: Option<i64>,
+}
+
+impl AccessConfig {
+    /// Adds the users granted a role with the role command.
+    pub fn add_roles(&mut self, roles: &Roles) {
+        for (user_id, role) in roles {
+            match role {
+                Role::Admin => self.admin_ids.push(*user_id),
+                Role::User => self.allowed_user_ids.push(*user_id),
+            }
+        }
+    }
+
+    pub fn is_admin(&self, user_id: i64) -> bool {
+        self.admin_ids.contains(&user_id)
+    }
+
+    pub fn is_authorized(&self, message: &Message) -> bool {
+        self.is_allowed(i64::from(message.from.id), i64::from(message.chat.id()))
+    }
+
+    /// Same as `is_authorized`, for a user and chat known by id only.
+    pub fn is_allowed(&self, user_id: i64, chat_id: i64) -> bool {
+        self.is_admin(user_id)
+            || self.allowed_user_ids.contains(&user_id)
+            || self.allowed_chat_ids.contains(&chat_id)
+    }
+
+    /// Checks the per-camera ACL. Cameras without `allowedUserIds` are
+    /// visible to everyone authorized to use the bot, and admins see everything.
+    pub fn can_view_camera(&self, camera: &Camera, user_id: i64) -> bool {
+        camera.allowed_user_ids.is_empty()
+            || camera.allowed_user_ids.contains(&user_id)
+            || self.is_admin(user_id)
+    }
+}
+
+/// Logs an unauthorized attempt and forwards it to the report chat when one is configured.
+pub async fn report_unauthorized(api: &Api, access: &AccessConfig, message: &Message, text: &str) {
+    let user = &message.from;
+    let report = format!(
+        "Unauthorized command '{}' from user {} ({}) in chat {}",
+        text,
+        user.id,
+        user.username.as_deref().unwrap_or(&user.first_name),
+        message.chat.id()
+    );
+
+    log::warn!("{}", report);
+
+    if let Some(report_chat_id) = access.report_chat_id {
+        let result = api.send(ChatId::new(report_chat_id).text(report)).await;
+
+        if let Err(report_error) = result {
+            log::error!(
+                "Failed to report unauthorized attempt to chat '{}'",
+                report_chat_id
+            );
+            log::error!("{:?}", report_error);
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! Background tasks of each camera: its live feed, continuous recorder and motion
+//! detector, kept in line with the camera config as it is reloaded.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use tokio::task::JoinSet;
+
+use crate::config;
+use crate::error::BotError;
+use crate::live_feed::{self, LiveFeeds};
+use crate::motion::{self, MotionEvents};
+use crate::nvr;
+use crate::send_video_command::{Camera, CameraConfig};
+use crate::store::Store;
+
+/// Cameras whose background tasks were started, restarted or stopped by a reload.
+#[derive(Debug, Default)]
+pub struct TaskChanges {
+    pub started: Vec<String>,
+    pub restarted: Vec<String>,
+    pub stopped: Vec<String>,
+}
+
+impl TaskChanges {
+    pub fn is_empty(&self) -> bool {
+        self.started.is_empty() && self.restarted.is_empty() && self.stopped.is_empty()
+    }
+}
+
+impl fmt::Display for TaskChanges {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "no background task changes");
+        }
+
+        let sections = [
+            ("started", &self.started),
+            ("restarted", &self.restarted),
+            ("stopped", &self.stopped),
+        ];
+        let parts: Vec<String> = sections
+            .iter()
+            .filter(|(_, names)| !names.is_empty())
+            .map(|(label, names)| format!("{}: {}", label, names.join(", ")))
+            .collect();
+
+        write!(f, "background tasks {}", parts.join("; "))
+    }
+}
+
+/// The tasks of one camera, started from `camera`. Dropping the set stops them.
+struct CameraTask {
+    camera: Camera,
+
+    /// Only held to be dropped.
+    _tasks: JoinSet<()>,
+}
+
+/// Whether `camera` needs any background task.
+fn has_tasks(camera: &Camera) -> bool {
+    camera.nvr.is_some() || camera.pre_roll.is_some() || camera.motion.is_some()
+}
+
+/// The camera without the settings only commands read, so editing them doesn't
+/// restart its recorder and drop the footage in between.
+fn task_settings(camera: &Camera) -> Camera {
+    Camera {
+        allowed_user_ids: Vec::new(),
+        schedules: Vec::new(),
+        duration: 0,
+        max_duration: None,
+        ..camera.clone()
+    }
+}
+
+/// Runs the background tasks of every camera, by name.
+pub struct CameraTasks {
+    store: Arc<Store>,
+    live_feeds: LiveFeeds,
+    motion_events: MotionEvents,
+    running: Mutex<HashMap<String, CameraTask>>,
+}
+
+impl CameraTasks {
+    /// No task runs until a config is applied.
+    pub fn new(store: Arc<Store>) -> Self {
+        CameraTasks {
+            store,
+            live_feeds: LiveFeeds::default(),
+            motion_events: motion::event_channel(),
+            running: Mutex::default(),
+        }
+    }
+
+    /// The live feeds of the running cameras, updated as the config is applied.
+    pub fn live_feeds(&self) -> LiveFeeds {
+        self.live_feeds.clone()
+    }
+
+    /// Where the motion detectors of every camera send their events.
+    pub fn motion_events(&self) -> MotionEvents {
+        self.motion_events.clone()
+    }
+
+    /// Applies the camera config loaded last, see [`CameraTasks::apply`].
+    pub fn apply_current(&self) -> Result<TaskChanges, BotError> {
+        Ok(self.apply(&*config::current()?))
+    }
+
+    /// Starts the tasks of new cameras, restarts those of changed ones and stops
+    /// those of removed ones. Cameras whose task settings are unchanged keep running.
+    pub fn apply(&self, camera_config: &CameraConfig) -> TaskChanges {
+        let mut running = self.running.lock().unwrap();
+        let mut changes = TaskChanges::default();
+
+        let wanted: HashMap<&str, Camera> = camera_config
+            .cameras
+            .iter()
+            .filter(|camera| has_tasks(camera))
+            .map(|camera| (camera.name.as_str(), task_settings(camera)))
+            .collect();
+
+        // Dropping a camera's set aborts its tasks.
+        running.retain(|name, _| {
+            let keep = wanted.contains_key(name.as_str());
+            if !keep {
+                log::info!("Stopping the background tasks of camera {}", name);
+                self.live_feeds.write().unwrap().remove(name);
+                changes.stopped.push(name.clone());
+            }
+            keep
+        });
+
+        for camera in &camera_config.cameras {
+            let Some(settings) = wanted.get(camera.name.as_str()) else {
+                continue;
+            };
+
+            match running.get(&camera.name) {
+                Some(task) if task.camera == *settings => continue,
+                Some(_) => {
+                    log::info!("Restarting the background tasks of camera {}", camera.name);
+                    changes.restarted.push(camera.name.clone());
+                }
+                None => changes.started.push(camera.name.clone()),
+            }
+
+            // Dropping the previous set aborts its tasks before the new ones first run.
+            running.insert(camera.name.clone(), self.spawn(camera, settings.clone()));
+        }
+
+        changes.started.sort();
+        changes.restarted.sort();
+        changes.stopped.sort();
+
+        changes
+    }
+
+    fn spawn(&self, camera: &Camera, settings: Camera) -> CameraTask {
+        let mut tasks = JoinSet::new();
+
+        nvr::spawn_recorder(camera, &self.store, &mut tasks);
+
+        let feed = live_feed::spawn_feed(camera, &self.store, &mut tasks);
+        let mut live_feeds = self.live_feeds.write().unwrap();
+        match feed {
+            Some(feed) => {
+                motion::spawn_detector(camera, &feed, &self.motion_events, &mut tasks);
+                live_feeds.insert(camera.name.clone(), feed);
+            }
+            None => {
+                live_feeds.remove(&camera.name);
+            }
+        }
+
+        CameraTask {
+            camera: settings,
+            _tasks: tasks,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn camera(json: &str) -> Camera {
+        let config: CameraConfig =
+            serde_json::from_str(&format!(r#"{{"cameras": [{}]}}"#, json)).unwrap();
+        config.cameras.into_iter().next().unwrap()
+    }
+
+    #[test]
+    fn command_settings_dont_restart_tasks() {
+        let running = camera(r#"{"name": "garden", "url": "rtsp://garden/stream", "preRoll": 5}"#);
+
+        let more_users = camera(
+            r#"{"name": "garden", "url": "rtsp://garden/stream", "preRoll": 5,
+                "allowedUserIds": [1], "duration": 30}"#,
+        );
+        let longer_pre_roll =
+            camera(r#"{"name": "garden", "url": "rtsp://garden/stream", "preRoll": 10}"#);
+
+        assert_eq!(task_settings(&running), task_settings(&more_users));
+        assert_ne!(task_settings(&running), task_settings(&longer_pre_roll));
+    }
+}
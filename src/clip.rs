@@ -0,0 +1,183 @@
+use anyhow::{bail, Error};
+use chrono::{DateTime, Utc};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::mp4;
+use crate::mp4_reader::Mp4Reader;
+use crate::mp4_writer::{Layout, Mp4Writer};
+use crate::nvr::{list_segments, SegmentFile};
+
+/// Timescale of the video tracks written by `Mp4Writer`.
+const VIDEO_TIMESCALE: i64 = 90_000;
+
+/// Converts a duration in `timescale` units to another timescale.
+fn rescale(value: i64, from_timescale: i64, to_timescale: i64) -> i64 {
+    (i128::from(value) * i128::from(to_timescale) / i128::from(from_timescale)) as i64
+}
+
+/// Offset of `time` from `origin`, in `timescale` units.
+fn offset_since(origin: DateTime<Utc>, time: DateTime<Utc>, timescale: i64) -> i64 {
+    let micros = time
+        .signed_duration_since(origin)
+        .num_microseconds()
+        .unwrap_or(0);
+    rescale(micros, 1_000_000, timescale)
+}
+
+/// Picks the segments covering `[from, to)`: the last one starting at or
+/// before `from`, and every one after it starting before `to`.
+fn covering_segments(
+    segments: &[SegmentFile],
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> &[SegmentFile] {
+    let first = segments
+        .iter()
+        .rposition(|segment| segment.start <= from)
+        .unwrap_or(0);
+    let last = segments
+        .iter()
+        .rposition(|segment| segment.start < to)
+        .map_or(first, |last| last + 1);
+
+    segments.get(first..last.max(first)).unwrap_or_default()
+}
+
+/// Keeps the timestamps of a track strictly increasing across segment boundaries,
+/// where the wall-clock start of a segment may not match the end of the previous one.
+#[derive(Default)]
+struct MonotonicPts {
+    last: Option<i64>,
+}
+
+impl MonotonicPts {
+    fn next(&mut self, pts: i64) -> i64 {
+        let pts = match self.last {
+            Some(last) if pts <= last => last + 1,
+            _ => pts,
+        };
+        self.last = Some(pts);
+        pts
+    }
+}
+
+/// Writes the stored footage of a camera between `from` and `from + duration` into `output`.
+///
+/// The clip starts at the nearest keyframe at or before `from`, so it can be played
+/// without the frames it depends on. Segments are never decoded, only remuxed.
+pub async fn write_clip(
+    camera_dir: &Path,
+    camera_name: &str,
+    from: DateTime<Utc>,
+    duration: Duration,
+    output: &Path,
+) -> Result<(), Error> {
+    let to = from + chrono::Duration::from_std(duration)?;
+    let segments = list_segments(camera_dir, camera_name).await?;
+    let segments = covering_segments(&segments, from, to);
+
+    if segments.is_empty() {
+        bail!("No footage stored for that time");
+    }
+
+    let first_reader = Mp4Reader::open(&segments[0].path).await?;
+    let audio_sample_entry = first_reader
+        .audio
+        .as_ref()
+        .map(|audio| audio.sample_entry.clone());
+
+    let file = mp4::create_output(output).await?;
+    let mut writer = Mp4Writer::with_audio_sample_entry(
+        audio_sample_entry.clone(),
+        true,
+        Layout::Progressive,
+        file,
+    )
+    .await?;
+
+    let mut clip_origin = None;
+    let mut video_pts = MonotonicPts::default();
+    let mut audio_pts = MonotonicPts::default();
+    let mut reader = Some(first_reader);
+
+    for segment in segments {
+        let mut segment_reader = match reader.take() {
+            Some(reader) => reader,
+            None => Mp4Reader::open(&segment.path).await?,
+        };
+
+        let Some(video) = segment_reader.video.take() else {
+            continue;
+        };
+        let video_timescale = i64::from(video.timescale);
+        let sample_time = |dts: i64| {
+            segment.start + chrono::Duration::microseconds(rescale(dts, video_timescale, 1_000_000))
+        };
+
+        // The first sample written is the last keyframe at or before `from`.
+        let first_sample = match clip_origin {
+            Some(_) => 0,
+            None => video
+                .samples
+                .iter()
+                .rposition(|sample| sample.is_sync && sample_time(sample.dts) <= from)
+                .unwrap_or(0),
+        };
+
+        for sample in &video.samples[first_sample..] {
+            let time = sample_time(sample.dts);
+            if time >= to {
+                break;
+            }
+            if clip_origin.is_none() && !sample.is_sync {
+                continue;
+            }
+
+            let origin = *clip_origin.get_or_insert(time);
+            let Some(sample_entry) = (sample.sample_description_index as usize)
+                .checked_sub(1)
+                .and_then(|index| video.sample_entries.get(index))
+            else {
+                bail!("Sample refers to a missing sample entry");
+            };
+            let data = segment_reader.read_sample(sample).await?;
+            let pts = video_pts.next(offset_since(origin, time, VIDEO_TIMESCALE));
+
+            writer
+                .video_sample(sample_entry, &data, pts, sample.is_sync)
+                .await?;
+        }
+
+        let (Some(origin), Some(audio)) = (clip_origin, segment_reader.audio.take()) else {
+            continue;
+        };
+
+        // Audio in a different format than the first segment can't go in the same track.
+        if Some(&audio.sample_entry) != audio_sample_entry.as_ref() {
+            continue;
+        }
+
+        let audio_timescale = i64::from(audio.sample_entry.clock_rate);
+        for sample in &audio.samples {
+            let time = segment.start
+                + chrono::Duration::microseconds(rescale(sample.dts, audio_timescale, 1_000_000));
+            if time < origin {
+                continue;
+            }
+            if time >= to {
+                break;
+            }
+
+            let data = segment_reader.read_sample(sample).await?;
+            let pts = audio_pts.next(offset_since(origin, time, audio_timescale));
+            writer.audio_sample(&data, pts).await?;
+        }
+    }
+
+    if clip_origin.is_none() {
+        bail!("No footage stored for that time");
+    }
+
+    writer.finish().await
+}
@@ -0,0 +1,691 @@
+use anyhow::anyhow;
+use retina::client::{InitialTimestampPolicy, TeardownPolicy, Transport};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use std::{env, fs};
+use tokio::time::sleep;
+use url::Url;
+
+use crate::camera_tasks::CameraTasks;
+use crate::error::BotError;
+use crate::h265;
+use crate::send_video_command::{Camera, CameraConfig};
+
+/// How often the camera config file is checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The last valid camera config, `None` until one loads.
+///
+/// It is process-wide so commands keep reading it through `get_camera_configs`.
+static CURRENT: RwLock<Option<Arc<CameraConfig>>> = RwLock::new(None);
+
+/// Cameras that differ between two configs, by name, and whether the
+/// `access` and `alerts` sections changed.
+#[derive(Debug, Default)]
+pub struct ConfigDiff {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+    pub access_changed: bool,
+    pub alerts_changed: bool,
+}
+
+impl ConfigDiff {
+    fn between(previous: Option<&CameraConfig>, next: &CameraConfig) -> Self {
+        let mut diff = ConfigDiff {
+            access_changed: previous.is_some_and(|previous| previous.access != next.access),
+            alerts_changed: previous.is_some_and(|previous| previous.alerts != next.alerts),
+            ..ConfigDiff::default()
+        };
+
+        let previous: BTreeMap<&str, _> = previous
+            .map(|config| {
+                config
+                    .cameras
+                    .iter()
+                    .map(|camera| (camera.name.as_str(), camera))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let next: BTreeMap<&str, _> = next
+            .cameras
+            .iter()
+            .map(|camera| (camera.name.as_str(), camera))
+            .collect();
+
+        for (name, camera) in &next {
+            match previous.get(name) {
+                None => diff.added.push(name.to_string()),
+                Some(previous_camera) if previous_camera != camera => {
+                    diff.changed.push(name.to_string())
+                }
+                Some(_) => {}
+            }
+        }
+
+        for name in previous.keys() {
+            if !next.contains_key(name) {
+                diff.removed.push(name.to_string());
+            }
+        }
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+            && !self.access_changed
+            && !self.alerts_changed
+    }
+}
+
+impl fmt::Display for ConfigDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "no changes");
+        }
+
+        let sections = [
+            ("added", &self.added),
+            ("removed", &self.removed),
+            ("changed", &self.changed),
+        ];
+        let mut parts: Vec<String> = sections
+            .iter()
+            .filter(|(_, names)| !names.is_empty())
+            .map(|(label, names)| format!("{}: {}", label, names.join(", ")))
+            .collect();
+
+        if self.access_changed {
+            parts.push("access changed".to_string());
+        }
+        if self.alerts_changed {
+            parts.push("alerts changed".to_string());
+        }
+
+        write!(f, "{}", parts.join("; "))
+    }
+}
+
+fn config_path() -> Result<PathBuf, BotError> {
+    env::var("CAMERA_CONFIG_PATH")
+        .map(PathBuf::from)
+        .map_err(|_| BotError::Config(anyhow!("CAMERA_CONFIG_PATH not set")))
+}
+
+fn modified_at(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
+
+/// Parses the camera config in the format given by the extension of `path`, JSON by default.
+fn parse_config(path: &Path, text: &str) -> Result<CameraConfig, BotError> {
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    let (format, parsed): (&str, Result<CameraConfig, anyhow::Error>) = match extension {
+        Some("toml") => ("TOML", toml::from_str(text).map_err(anyhow::Error::new)),
+        Some("yaml") | Some("yml") => (
+            "YAML",
+            serde_yaml::from_str(text).map_err(anyhow::Error::new),
+        ),
+        _ => (
+            "JSON",
+            serde_json::from_str(text).map_err(anyhow::Error::new),
+        ),
+    };
+
+    parsed.map_err(|err| {
+        BotError::Config(err.context(format!("camera config {} is malformed", format)))
+    })
+}
+
+/// Reads the camera config at `path`, resolves its secrets and validates it.
+fn read_config(path: &Path) -> Result<CameraConfig, BotError> {
+    let config_text = fs::read_to_string(path).map_err(|err| {
+        BotError::Config(anyhow::Error::new(err).context(format!(
+            "failed to read camera config at '{}'",
+            path.display()
+        )))
+    })?;
+    let mut config = parse_config(path, &config_text)?;
+
+    let mut problems = resolve_secrets(&mut config);
+    if let Err(ConfigProblems(invalid)) = config.validate() {
+        problems.extend(invalid);
+    }
+
+    if !problems.is_empty() {
+        return Err(BotError::Config(
+            anyhow::Error::new(ConfigProblems(problems)).context("camera config is invalid"),
+        ));
+    }
+
+    Ok(config)
+}
+
+/// Resolves a `file:<path>` reference to the contents of the file, without the
+/// trailing newline, or replaces every `${VAR}` with the environment variable.
+///
+/// `$${` is a literal `${`, and a value starting with `file::` is taken as is
+/// without one of the colons.
+fn resolve_secret(value: &str) -> Result<String, String> {
+    if let Some(literal) = value.strip_prefix("file::") {
+        return Ok(format!("file:{}", literal));
+    }
+
+    if let Some(secret_path) = value.strip_prefix("file:") {
+        return fs::read_to_string(secret_path)
+            .map(|secret| secret.trim_end_matches(['\r', '\n']).to_string())
+            .map_err(|err| format!("failed to read '{}': {}", secret_path, err));
+    }
+
+    let mut resolved = String::new();
+    let mut rest = value;
+
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            resolved.push_str(&rest[..start - 1]);
+            resolved.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or_else(|| format!("unclosed '${{' in '{}'", value))?;
+        let name = &rest[start + 2..end];
+        let variable =
+            env::var(name).map_err(|_| format!("environment variable {} is not set", name))?;
+
+        resolved.push_str(&rest[..start]);
+        resolved.push_str(&variable);
+        rest = &rest[end + 1..];
+    }
+
+    resolved.push_str(rest);
+
+    Ok(resolved)
+}
+
+/// Resolves the `username` and `password` references of every camera in place.
+fn resolve_secrets(config: &mut CameraConfig) -> Vec<ConfigProblem> {
+    let mut problems = Vec::new();
+
+    for (index, camera) in config.cameras.iter_mut().enumerate() {
+        for (field, value) in [
+            ("username", &mut camera.username),
+            ("password", &mut camera.password),
+        ] {
+            match resolve_secret(value) {
+                Ok(resolved) => *value = resolved,
+                Err(message) => problems.push(ConfigProblem {
+                    path: format!("cameras[{}].{}", index, field),
+                    message,
+                }),
+            }
+        }
+    }
+
+    problems
+}
+
+/// A problem with one field of the camera config.
+#[derive(Debug)]
+pub struct ConfigProblem {
+    /// Where the field is, e.g. `cameras[1].transport`.
+    pub path: String,
+    pub message: String,
+}
+
+/// Every problem found in a camera config, so they can all be fixed at once.
+#[derive(Debug)]
+pub struct ConfigProblems(pub Vec<ConfigProblem>);
+
+impl fmt::Display for ConfigProblems {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} problem(s) found", self.0.len())?;
+
+        for problem in &self.0 {
+            write!(f, "\n  {}: {}", problem.path, problem.message)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigProblems {}
+
+/// Problems of the camera at `path` that would only show up once it is recorded.
+///
+/// Settings inherited from `defaults` are reported under the camera too.
+fn camera_problems(camera: &Camera, path: &str) -> Vec<ConfigProblem> {
+    let mut problems = Vec::new();
+    let mut problem = |field: &str, message: String| {
+        problems.push(ConfigProblem {
+            path: format!("{}.{}", path, field),
+            message,
+        })
+    };
+
+    match Url::parse(&camera.url) {
+        Ok(url) if url.scheme() != "rtsp" => problem(
+            "url",
+            format!("unsupported scheme '{}', expected rtsp://", url.scheme()),
+        ),
+        Ok(_) => {}
+        Err(err) => problem("url", format!("invalid url: {}", err)),
+    }
+
+    if Transport::from_str(&camera.transport).is_err() {
+        problem(
+            "transport",
+            format!(
+                "unknown transport '{}', expected tcp or udp",
+                camera.transport
+            ),
+        );
+    }
+
+    if InitialTimestampPolicy::from_str(&camera.initial_timestamp).is_err() {
+        problem(
+            "initialTimestamp",
+            format!(
+                "unknown policy '{}', expected default, require, ignore or permissive",
+                camera.initial_timestamp
+            ),
+        );
+    }
+
+    if TeardownPolicy::from_str(&camera.teardown).is_err() {
+        problem(
+            "teardown",
+            format!(
+                "unknown policy '{}', expected auto, always or never",
+                camera.teardown
+            ),
+        );
+    }
+
+    if camera.connect_timeout == Some(0) {
+        problem("connectTimeout", "must be greater than 0".to_string());
+    }
+
+    if camera.snapshot_timeout == 0 {
+        problem("snapshotTimeout", "must be greater than 0".to_string());
+    }
+
+    if camera.duration == 0 {
+        problem("duration", "must be greater than 0".to_string());
+    }
+
+    match camera.max_duration {
+        Some(0) => problem("maxDuration", "must be greater than 0".to_string()),
+        Some(max_duration) if max_duration < camera.duration => problem(
+            "maxDuration",
+            format!(
+                "{} is shorter than the duration of {} seconds",
+                max_duration, camera.duration
+            ),
+        ),
+        _ => {}
+    }
+
+    if let Some(nvr) = &camera.nvr {
+        if nvr.segment_duration == 0 {
+            problem("nvr.segmentDuration", "must be greater than 0".to_string());
+        }
+    }
+
+    if let Some(motion) = &camera.motion {
+        if !(0.0..=1.0).contains(&motion.sensitivity) {
+            problem(
+                "motion.sensitivity",
+                format!("{} is out of range, expected 0 to 1", motion.sensitivity),
+            );
+        }
+
+        if motion.cooldown == 0 {
+            problem("motion.cooldown", "must be greater than 0".to_string());
+        }
+    }
+
+    if let Some(parameter_sets) = &camera.h265_parameter_sets {
+        if let Err(err) = h265::ParameterSets::from_str(parameter_sets) {
+            problem("h265ParameterSets", err.to_string());
+        }
+    }
+
+    if camera.no_audio && camera.no_video {
+        problem(
+            "noVideo",
+            "noAudio and noVideo are both set, nothing would be recorded".to_string(),
+        );
+    }
+
+    problems
+}
+
+impl CameraConfig {
+    /// Checks every camera, reporting all the problems found rather than the first one.
+    pub fn validate(&self) -> Result<(), ConfigProblems> {
+        let mut problems = Vec::new();
+        let mut first_index_by_name: BTreeMap<&str, usize> = BTreeMap::new();
+
+        for (index, camera) in self.cameras.iter().enumerate() {
+            let path = format!("cameras[{}]", index);
+
+            match first_index_by_name.get(camera.name.as_str()) {
+                Some(first_index) => problems.push(ConfigProblem {
+                    path: format!("{}.name", path),
+                    message: format!(
+                        "duplicate name '{}', already used by cameras[{}]",
+                        camera.name, first_index
+                    ),
+                }),
+                None => {
+                    first_index_by_name.insert(camera.name.as_str(), index);
+                }
+            }
+
+            problems.extend(camera_problems(camera, &path));
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigProblems(problems))
+        }
+    }
+}
+
+/// Reads and validates the camera config at `CAMERA_CONFIG_PATH`, without loading it.
+pub fn check() -> Result<CameraConfig, BotError> {
+    read_config(&config_path()?)
+}
+
+/// The camera config loaded last, without the roles granted through the bot.
+pub fn current() -> Result<Arc<CameraConfig>, BotError> {
+    CURRENT
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone()
+        .ok_or_else(|| BotError::Config(anyhow!("no valid camera config has been loaded")))
+}
+
+/// Loads the camera config again, keeping the previous one if the new one is invalid.
+pub fn reload() -> Result<ConfigDiff, BotError> {
+    let next = read_config(&config_path()?)?;
+    let mut current = CURRENT
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    let diff = ConfigDiff::between(current.as_deref(), &next);
+
+    *current = Some(Arc::new(next));
+
+    Ok(diff)
+}
+
+/// Reloads the camera config whenever its file changes, restarting the
+/// background tasks of the cameras that changed.
+pub async fn watch(camera_tasks: Arc<CameraTasks>) {
+    let path = match config_path() {
+        Ok(path) => path,
+        Err(err) => {
+            log::error!("Not watching the camera config: {}", err);
+            return;
+        }
+    };
+    let mut last_modified = modified_at(&path);
+
+    loop {
+        sleep(POLL_INTERVAL).await;
+
+        let modified = modified_at(&path);
+
+        if modified.is_none() || modified == last_modified {
+            continue;
+        }
+
+        last_modified = modified;
+
+        match reload() {
+            Ok(diff) => {
+                log::info!("Reloaded the camera config, {}", diff);
+                match camera_tasks.apply_current() {
+                    Ok(changes) => log::info!("Applied the camera config, {}", changes),
+                    Err(err) => log::error!("Failed to apply the camera config: {}", err),
+                }
+            }
+            Err(err) => log::error!(
+                "Keeping the previous camera config, the changed one is invalid: {}",
+                err
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(json: &str) -> CameraConfig {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn diff_lists_cameras_and_sections_that_changed() {
+        let previous = config(
+            r#"{"cameras": [
+                {"name": "garden", "url": "rtsp://garden/stream"},
+                {"name": "porch", "url": "rtsp://porch/stream"}
+            ]}"#,
+        );
+        let next = config(
+            r#"{"cameras": [
+                {"name": "garden", "url": "rtsp://garden/stream2"},
+                {"name": "door", "url": "rtsp://door/stream"}
+            ], "access": {"adminIds": [1]}}"#,
+        );
+
+        let diff = ConfigDiff::between(Some(&previous), &next);
+
+        assert_eq!(diff.added, ["door"]);
+        assert_eq!(diff.removed, ["porch"]);
+        assert_eq!(diff.changed, ["garden"]);
+        assert!(diff.access_changed);
+        assert!(!diff.alerts_changed);
+        assert_eq!(
+            diff.to_string(),
+            "added: door; removed: porch; changed: garden; access changed"
+        );
+    }
+
+    #[test]
+    fn diff_of_the_first_config_only_adds_cameras() {
+        let next = config(
+            r#"{"cameras": [{"name": "garden", "url": "rtsp://garden/stream"}],
+                "access": {"adminIds": [1]}}"#,
+        );
+
+        let diff = ConfigDiff::between(None, &next);
+
+        assert_eq!(diff.to_string(), "added: garden");
+        assert!(ConfigDiff::between(Some(&next), &next).is_empty());
+    }
+
+    /// The problems of a config with `cameras`, as `path: message`.
+    fn camera_problems_of(cameras: &str) -> Vec<String> {
+        let config = config(&format!(r#"{{"cameras": [{}]}}"#, cameras));
+
+        match config.validate() {
+            Ok(()) => Vec::new(),
+            Err(ConfigProblems(problems)) => problems
+                .iter()
+                .map(|problem| format!("{}: {}", problem.path, problem.message))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn validate_reports_each_problem_with_its_path() {
+        let cases = [
+            (
+                r#"{"name": "garden", "url": "http://garden/stream"}"#,
+                "cameras[0].url: unsupported scheme 'http', expected rtsp://",
+            ),
+            (
+                r#"{"name": "garden", "url": "garden"}"#,
+                "cameras[0].url: invalid url",
+            ),
+            (
+                r#"{"name": "garden", "url": "rtsp://garden/stream", "transport": "tpc"}"#,
+                "cameras[0].transport: unknown transport 'tpc', expected tcp or udp",
+            ),
+            (
+                r#"{"name": "garden", "url": "rtsp://garden/stream"},
+                   {"name": "garden", "url": "rtsp://porch/stream"}"#,
+                "cameras[1].name: duplicate name 'garden', already used by cameras[0]",
+            ),
+            (
+                r#"{"name": "garden", "url": "rtsp://garden/stream", "duration": 0}"#,
+                "cameras[0].duration: must be greater than 0",
+            ),
+            (
+                r#"{"name": "garden", "url": "rtsp://garden/stream", "duration": 30, "maxDuration": 10}"#,
+                "cameras[0].maxDuration: 10 is shorter than the duration of 30 seconds",
+            ),
+            (
+                r#"{"name": "garden", "url": "rtsp://garden/stream", "noAudio": true, "noVideo": true}"#,
+                "cameras[0].noVideo: noAudio and noVideo are both set, nothing would be recorded",
+            ),
+            (
+                r#"{"name": "garden", "url": "rtsp://garden/stream", "nvr": {"segmentDuration": 0}}"#,
+                "cameras[0].nvr.segmentDuration: must be greater than 0",
+            ),
+            (
+                r#"{"name": "garden", "url": "rtsp://garden/stream", "motion": {"sensitivity": 1.5}}"#,
+                "cameras[0].motion.sensitivity: 1.5 is out of range, expected 0 to 1",
+            ),
+            (
+                r#"{"name": "garden", "url": "rtsp://garden/stream", "h265ParameterSets": "sprop-vps=QAEM"}"#,
+                "cameras[0].h265ParameterSets: sprop-sps is missing",
+            ),
+            (
+                r#"{"name": "garden", "url": "rtsp://garden/stream", "motion": {"cooldown": 0}}"#,
+                "cameras[0].motion.cooldown: must be greater than 0",
+            ),
+        ];
+
+        for (cameras, expected) in cases {
+            let problems = camera_problems_of(cameras);
+            assert!(
+                problems.iter().any(|problem| problem.starts_with(expected)),
+                "expected '{}' among {:?}",
+                expected,
+                problems
+            );
+        }
+    }
+
+    #[test]
+    fn validate_reports_every_problem_at_once() {
+        let problems = camera_problems_of(
+            r#"{"name": "garden", "url": "http://garden/stream", "duration": 0},
+               {"name": "porch", "url": "rtsp://porch/stream", "snapshotTimeout": 0}"#,
+        );
+
+        for path in [
+            "cameras[0].url",
+            "cameras[0].duration",
+            "cameras[1].snapshotTimeout",
+        ] {
+            assert!(
+                problems.iter().any(|problem| problem.starts_with(path)),
+                "expected {} among {:?}",
+                path,
+                problems
+            );
+        }
+    }
+
+    #[test]
+    fn secrets_are_read_from_the_environment() {
+        env::set_var("IPCAMERA_BOT_TEST_SUFFIX", "secret");
+
+        assert_eq!(
+            resolve_secret("admin-${IPCAMERA_BOT_TEST_SUFFIX}").unwrap(),
+            "admin-secret"
+        );
+        assert_eq!(
+            resolve_secret("${IPCAMERA_BOT_TEST_SUFFIX}${IPCAMERA_BOT_TEST_SUFFIX}").unwrap(),
+            "secretsecret"
+        );
+        assert_eq!(
+            resolve_secret("${IPCAMERA_BOT_TEST_MISSING}").unwrap_err(),
+            "environment variable IPCAMERA_BOT_TEST_MISSING is not set"
+        );
+        assert_eq!(
+            resolve_secret("admin-${IPCAMERA_BOT_TEST_SUFFIX").unwrap_err(),
+            "unclosed '${' in 'admin-${IPCAMERA_BOT_TEST_SUFFIX'"
+        );
+    }
+
+    #[test]
+    fn secrets_are_read_from_files() {
+        let path = env::temp_dir().join(format!("ipcamera_bot_secret_{}", std::process::id()));
+        fs::write(&path, "hunter2\n").unwrap();
+
+        assert_eq!(
+            resolve_secret(&format!("file:{}", path.display())).unwrap(),
+            "hunter2"
+        );
+        fs::remove_file(&path).unwrap();
+
+        assert!(resolve_secret(&format!("file:{}", path.display()))
+            .unwrap_err()
+            .starts_with(&format!("failed to read '{}'", path.display())));
+    }
+
+    #[test]
+    fn escaped_references_are_kept_literally() {
+        assert_eq!(resolve_secret("pa$${ss}").unwrap(), "pa${ss}");
+        assert_eq!(resolve_secret("$$${").unwrap(), "$${");
+        assert_eq!(resolve_secret("file::abc").unwrap(), "file:abc");
+        assert_eq!(resolve_secret("file::${abc}").unwrap(), "file:${abc}");
+        assert_eq!(resolve_secret("pa$$word").unwrap(), "pa$$word");
+    }
+
+    #[test]
+    fn config_format_follows_the_extension() {
+        let toml = r#"
+            [[cameras]]
+            name = "garden"
+            url = "rtsp://garden/stream"
+            preRoll = 5
+        "#;
+        let yaml = "
+            cameras:
+              - name: garden
+                url: rtsp://garden/stream
+                preRoll: 5
+        ";
+        let json =
+            r#"{"cameras": [{"name": "garden", "url": "rtsp://garden/stream", "preRoll": 5}]}"#;
+
+        let from_toml = parse_config(Path::new("cameras.toml"), toml).unwrap();
+        let from_yaml = parse_config(Path::new("cameras.yml"), yaml).unwrap();
+        let from_json = parse_config(Path::new("cameras.json"), json).unwrap();
+
+        assert_eq!(from_toml, from_json);
+        assert_eq!(from_yaml, from_json);
+        assert_eq!(from_json.cameras[0].pre_roll, Some(5));
+
+        let malformed = parse_config(Path::new("cameras.toml"), json).unwrap_err();
+        assert!(format!("{:#}", malformed).contains("camera config TOML is malformed"));
+    }
+}
@@ -0,0 +1,54 @@
+use std::borrow::Cow;
+
+use telegram_bot::prelude::*;
+use telegram_bot::{ChatId, InputFile, Message, SendMessage, SendVideo};
+
+#[cfg(feature = "ffmpeg-snapshot")]
+use telegram_bot::SendPhoto;
+
+/// Where the output of a camera goes: a reply to the command that asked for it,
+/// or a plain message in a chat, for scheduled recordings.
+#[derive(Debug, Clone)]
+pub enum Destination {
+    Reply(Message),
+    Chat(ChatId),
+}
+
+impl Destination {
+    pub fn chat_id(&self) -> ChatId {
+        match self {
+            Destination::Reply(message) => message.chat.id(),
+            Destination::Chat(chat_id) => *chat_id,
+        }
+    }
+
+    /// The user who asked for the output, none for scheduled recordings.
+    pub fn requester(&self) -> Option<i64> {
+        match self {
+            Destination::Reply(message) => Some(i64::from(message.from.id)),
+            Destination::Chat(_) => None,
+        }
+    }
+
+    pub fn text<'s>(&self, text: impl Into<Cow<'s, str>>) -> SendMessage<'s> {
+        match self {
+            Destination::Reply(message) => message.text_reply(text),
+            Destination::Chat(chat_id) => chat_id.text(text),
+        }
+    }
+
+    pub fn video<'c>(&self, video: impl Into<InputFile>) -> SendVideo<'c> {
+        match self {
+            Destination::Reply(message) => message.video_reply(video),
+            Destination::Chat(chat_id) => chat_id.video(video),
+        }
+    }
+
+    #[cfg(feature = "ffmpeg-snapshot")]
+    pub fn photo<'c>(&self, photo: impl Into<InputFile>) -> SendPhoto<'c> {
+        match self {
+            Destination::Reply(message) => message.photo_reply(photo),
+            Destination::Chat(chat_id) => chat_id.photo(photo),
+        }
+    }
+}
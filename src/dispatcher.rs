@@ -0,0 +1,136 @@
+use futures::FutureExt as _;
+use std::env;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio_compat_02::FutureExt;
+
+use telegram_bot::{prelude::*, Api, Message};
+
+/// Default for `MAX_CONCURRENT_COMMANDS`.
+const DEFAULT_MAX_CONCURRENT_COMMANDS: usize = 2;
+
+/// Runs commands on their own tasks, with at most `max_concurrent` of them at once.
+///
+/// Commands that arrive while every slot is taken wait in FIFO order, and the
+/// requester is told about their position in the queue.
+pub struct Dispatcher {
+    semaphore: Arc<Semaphore>,
+    waiting: Arc<AtomicUsize>,
+    tasks: Mutex<JoinSet<()>>,
+}
+
+impl Dispatcher {
+    pub fn new(max_concurrent: usize) -> Self {
+        Dispatcher {
+            semaphore: Arc::new(Semaphore::new(max_concurrent)),
+            waiting: Arc::new(AtomicUsize::new(0)),
+            tasks: Mutex::new(JoinSet::new()),
+        }
+    }
+
+    /// Reads the concurrency limit from the `MAX_CONCURRENT_COMMANDS` env var.
+    pub fn from_env() -> Self {
+        let max_concurrent = env::var("MAX_CONCURRENT_COMMANDS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|value| *value > 0)
+            .unwrap_or(DEFAULT_MAX_CONCURRENT_COMMANDS);
+
+        log::info!("Handling up to {} commands concurrently", max_concurrent);
+
+        Self::new(max_concurrent)
+    }
+
+    /// Spawns `command` as soon as a slot is free, replying to `message` when it has to wait.
+    pub fn dispatch<F>(&self, api: Api, message: Message, command: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.spawn(Some((api, message)), command);
+    }
+
+    /// Spawns work nobody asked for, like motion alerts, as soon as a slot is free.
+    /// It waits in the same queue as commands, without telling anyone.
+    pub fn dispatch_background<F>(&self, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.spawn(None, task);
+    }
+
+    fn spawn<F>(&self, requester: Option<(Api, Message)>, command: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let semaphore = self.semaphore.clone();
+        let waiting = self.waiting.clone();
+
+        let mut tasks = self.tasks.lock().unwrap();
+
+        // Reap finished tasks so the set doesn't grow forever.
+        while let Some(Some(result)) = tasks.join_next().now_or_never() {
+            log_task_result(result);
+        }
+
+        tasks.spawn(async move {
+            let permit = match semaphore.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    let position = waiting.fetch_add(1, Ordering::SeqCst) + 1;
+
+                    if let Some((api, message)) = requester {
+                        let queued_reply = api
+                            .send(message.text_reply(format!(
+                                "The bot is busy. Your request is #{} in the queue.",
+                                position
+                            )))
+                            .compat()
+                            .await;
+
+                        if let Err(reply_error) = queued_reply {
+                            log::error!("Failed to send queue position: {:?}", reply_error);
+                        }
+                    }
+
+                    let permit = semaphore.acquire_owned().await;
+                    waiting.fetch_sub(1, Ordering::SeqCst);
+
+                    match permit {
+                        Ok(permit) => permit,
+                        Err(_) => {
+                            log::info!("Dropping queued command because the bot is shutting down");
+                            return;
+                        }
+                    }
+                }
+            };
+
+            command.await;
+            drop(permit);
+        });
+    }
+
+    /// Stops accepting queued commands and waits for in-flight ones to finish.
+    pub async fn drain(&self) {
+        self.semaphore.close();
+
+        let mut tasks = std::mem::take(&mut *self.tasks.lock().unwrap());
+
+        if !tasks.is_empty() {
+            log::info!("Waiting for {} in-flight commands to finish..", tasks.len());
+        }
+
+        while let Some(result) = tasks.join_next().await {
+            log_task_result(result);
+        }
+    }
+}
+
+fn log_task_result(result: Result<(), tokio::task::JoinError>) {
+    if let Err(join_error) = result {
+        log::error!("Command task failed: {}", join_error);
+    }
+}
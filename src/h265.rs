@@ -0,0 +1,836 @@
+//! H.265 (HEVC) streams.
+//!
+//! retina only depacketizes H.264, so H.265 video is read from the raw RTP
+//! packets and reassembled here into access units, following RFC 7798. The
+//! parameter sets (VPS, SPS and PPS) are taken out of the stream, or out of the
+//! camera config for cameras that only send them in the SDP, and placed into
+//! the `hvcC` box of an `hvc1` sample entry, see ISO/IEC 14496-15.
+
+use anyhow::{anyhow, bail, Error};
+use bytes::{BufMut, Bytes, BytesMut};
+use log::debug;
+use retina::Timestamp;
+use std::collections::VecDeque;
+use std::str::FromStr;
+
+use crate::mp4_writer::VideoSampleEntry;
+
+const VPS: u8 = 32;
+const SPS: u8 = 33;
+const PPS: u8 = 34;
+
+/// Aggregation packet: several NAL units in one RTP packet.
+const AP: u8 = 48;
+
+/// Fragmentation unit: one NAL unit split across RTP packets.
+const FU: u8 = 49;
+
+/// PACI packet, which no camera is known to send.
+const PACI: u8 = 50;
+
+fn nal_type(header: u8) -> u8 {
+    (header >> 1) & 0x3f
+}
+
+/// Whether the NAL unit is a slice of an intra random access point (BLA, IDR or CRA) picture.
+fn is_irap(nal_type: u8) -> bool {
+    (16..=23).contains(&nal_type)
+}
+
+/// Whether the NAL unit is a coded slice.
+pub fn is_vcl(nal_type: u8) -> bool {
+    nal_type < 32
+}
+
+/// The type of an H.265 NAL unit from its two-byte header.
+pub fn nal_unit_type(nal: &[u8]) -> Option<u8> {
+    nal.first().map(|&header| nal_type(header))
+}
+
+/// A picture, as NAL units prefixed with their 4-byte length.
+#[derive(Debug)]
+pub struct AccessUnit {
+    pub sample_entry: VideoSampleEntry,
+    pub data: Bytes,
+    pub timestamp: Timestamp,
+
+    /// RTP packets lost since the previous access unit.
+    pub loss: u16,
+    pub is_random_access_point: bool,
+}
+
+/// Reassembles RTP packets of an H.265 stream into access units.
+#[derive(Default)]
+pub struct Depacketizer {
+    /// NAL units of the access unit being received, without parameter sets.
+    nals: Vec<Bytes>,
+
+    /// The NAL unit being reassembled from fragmentation units.
+    fragment: Option<BytesMut>,
+    timestamp: Option<Timestamp>,
+    loss: u16,
+
+    vps: Option<Bytes>,
+    sps: Option<Bytes>,
+    pps: Option<Bytes>,
+    parameters_changed: bool,
+    sample_entry: Option<VideoSampleEntry>,
+
+    ready: VecDeque<AccessUnit>,
+}
+
+impl Depacketizer {
+    /// Starts with `parameter_sets`, if given, so pictures can be written before
+    /// the stream repeats them. Parameter sets in the stream still replace them.
+    pub fn new(parameter_sets: Option<&ParameterSets>) -> Self {
+        let Some(parameter_sets) = parameter_sets else {
+            return Depacketizer::default();
+        };
+
+        Depacketizer {
+            vps: Some(parameter_sets.vps.clone()),
+            sps: Some(parameter_sets.sps.clone()),
+            pps: Some(parameter_sets.pps.clone()),
+            sample_entry: Some(parameter_sets.sample_entry.clone()),
+            ..Depacketizer::default()
+        }
+    }
+
+    /// Feeds the payload of an RTP packet. Completed access units are returned by [`Self::pull`].
+    pub fn push(
+        &mut self,
+        payload: &[u8],
+        timestamp: Timestamp,
+        mark: bool,
+        loss: u16,
+    ) -> Result<(), Error> {
+        // A new timestamp starts a new picture, even if the marker of the previous one was lost.
+        if self
+            .timestamp
+            .is_some_and(|current| current.timestamp() != timestamp.timestamp())
+        {
+            self.finish_access_unit()?;
+        }
+
+        if loss > 0 {
+            self.loss = self.loss.saturating_add(loss);
+
+            // The middle of the fragmented NAL unit may be missing.
+            if self.fragment.take().is_some() {
+                debug!(
+                    "Dropping a fragmented NAL unit after losing {} packets",
+                    loss
+                );
+            }
+        }
+
+        self.timestamp = Some(timestamp);
+
+        if payload.len() < 2 {
+            bail!("H.265 RTP payload of {} bytes is too short", payload.len());
+        }
+
+        match nal_type(payload[0]) {
+            AP => {
+                let mut rest = &payload[2..];
+                while !rest.is_empty() {
+                    if rest.len() < 2 {
+                        bail!("truncated H.265 aggregation packet");
+                    }
+                    let len = usize::from(u16::from_be_bytes([rest[0], rest[1]]));
+                    let nal = rest
+                        .get(2..2 + len)
+                        .ok_or_else(|| anyhow!("truncated H.265 aggregation packet"))?;
+                    self.add_nal(Bytes::copy_from_slice(nal));
+                    rest = &rest[2 + len..];
+                }
+            }
+            FU => {
+                let Some(&fu_header) = payload.get(2) else {
+                    bail!("truncated H.265 fragmentation unit");
+                };
+                let is_start = fu_header & 0x80 != 0;
+                let is_end = fu_header & 0x40 != 0;
+
+                if is_start {
+                    // The NAL unit header is the payload header with the type of the FU header.
+                    let mut nal = BytesMut::with_capacity(payload.len() * 4);
+                    nal.put_u8((payload[0] & 0x81) | ((fu_header & 0x3f) << 1));
+                    nal.put_u8(payload[1]);
+                    nal.extend_from_slice(&payload[3..]);
+                    self.fragment = Some(nal);
+                } else if let Some(fragment) = self.fragment.as_mut() {
+                    fragment.extend_from_slice(&payload[3..]);
+                } else {
+                    debug!("Dropping an H.265 fragmentation unit without its start");
+                }
+
+                if is_end {
+                    if let Some(fragment) = self.fragment.take() {
+                        self.add_nal(fragment.freeze());
+                    }
+                }
+            }
+            PACI => debug!("Dropping an unsupported H.265 PACI packet"),
+            _ => self.add_nal(Bytes::copy_from_slice(payload)),
+        }
+
+        if mark {
+            self.finish_access_unit()?;
+        }
+
+        Ok(())
+    }
+
+    /// The next completed access unit, if any.
+    pub fn pull(&mut self) -> Option<AccessUnit> {
+        self.ready.pop_front()
+    }
+
+    fn add_nal(&mut self, nal: Bytes) {
+        let parameter_set = match nal_unit_type(&nal) {
+            Some(VPS) => &mut self.vps,
+            Some(SPS) => &mut self.sps,
+            Some(PPS) => &mut self.pps,
+            _ => {
+                self.nals.push(nal);
+                return;
+            }
+        };
+
+        if parameter_set.as_ref() != Some(&nal) {
+            *parameter_set = Some(nal);
+            self.parameters_changed = true;
+        }
+    }
+
+    fn finish_access_unit(&mut self) -> Result<(), Error> {
+        let nals = std::mem::take(&mut self.nals);
+        let timestamp = self.timestamp.take();
+        let loss = std::mem::take(&mut self.loss);
+
+        if self.fragment.take().is_some() {
+            debug!("Dropping a fragmented NAL unit without its end");
+        }
+
+        if self.parameters_changed {
+            if let (Some(vps), Some(sps), Some(pps)) = (&self.vps, &self.sps, &self.pps) {
+                self.sample_entry = Some(hvc1_sample_entry(vps, sps, pps)?);
+                self.parameters_changed = false;
+            }
+        }
+
+        let Some(timestamp) = timestamp else {
+            return Ok(());
+        };
+
+        if nals.is_empty() {
+            return Ok(());
+        }
+
+        // Pictures received before the parameter sets can't be written.
+        let Some(sample_entry) = self.sample_entry.clone() else {
+            debug!("Discarding H.265 access unit received before the parameter sets");
+            return Ok(());
+        };
+
+        let is_random_access_point = nals
+            .iter()
+            .filter_map(|nal| nal_unit_type(nal))
+            .any(is_irap);
+
+        let mut data = BytesMut::with_capacity(nals.iter().map(|nal| 4 + nal.len()).sum());
+        for nal in &nals {
+            data.put_u32(u32::try_from(nal.len())?);
+            data.extend_from_slice(nal);
+        }
+
+        self.ready.push_back(AccessUnit {
+            sample_entry,
+            data: data.freeze(),
+            timestamp,
+            loss,
+            is_random_access_point,
+        });
+
+        Ok(())
+    }
+}
+
+/// The parameter sets of a stream as given out of band, in the syntax of the
+/// `sprop-vps`, `sprop-sps` and `sprop-pps` parameters of an SDP `fmtp` line,
+/// e.g. `sprop-vps=QAEMAf//...; sprop-sps=QgEBAWAA...; sprop-pps=RAHBcrRiQA==`.
+#[derive(Debug, Clone)]
+pub struct ParameterSets {
+    vps: Bytes,
+    sps: Bytes,
+    pps: Bytes,
+    sample_entry: VideoSampleEntry,
+}
+
+impl FromStr for ParameterSets {
+    type Err = Error;
+
+    /// Other `fmtp` parameters are ignored. Of several comma-separated NAL units,
+    /// the first one is used.
+    fn from_str(fmtp: &str) -> Result<Self, Self::Err> {
+        let parameter = |name: &str| -> Result<Bytes, Error> {
+            let value = fmtp
+                .split(';')
+                .filter_map(|parameter| parameter.trim().split_once('='))
+                .find(|(key, _)| key.trim() == name)
+                .map(|(_, value)| value.trim())
+                .ok_or_else(|| anyhow!("{} is missing", name))?;
+            let first = value.split(',').next().unwrap_or_default();
+
+            decode_base64(first)
+                .map(Bytes::from)
+                .ok_or_else(|| anyhow!("{} isn't valid base64", name))
+        };
+
+        let vps = parameter("sprop-vps")?;
+        let sps = parameter("sprop-sps")?;
+        let pps = parameter("sprop-pps")?;
+
+        for (name, nal, expected) in [
+            ("sprop-vps", &vps, VPS),
+            ("sprop-sps", &sps, SPS),
+            ("sprop-pps", &pps, PPS),
+        ] {
+            if nal_unit_type(nal) != Some(expected) {
+                bail!("{} isn't a NAL unit of type {}", name, expected);
+            }
+        }
+
+        let sample_entry = hvc1_sample_entry(&vps, &sps, &pps)?;
+
+        Ok(ParameterSets {
+            vps,
+            sps,
+            pps,
+            sample_entry,
+        })
+    }
+}
+
+/// Decodes standard base64, with or without padding.
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(text.len() * 3 / 4);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for byte in text.trim_end_matches('=').bytes() {
+        let value = match byte {
+            b'A'..=b'Z' => byte - b'A',
+            b'a'..=b'z' => byte - b'a' + 26,
+            b'0'..=b'9' => byte - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+
+        buffer = (buffer << 6) | u32::from(value);
+        bits += 6;
+
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(decoded)
+}
+
+/// Reads bits of an RBSP, most significant first.
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Bits<'_> {
+    fn read_bit(&mut self) -> Result<u32, Error> {
+        let byte = self
+            .data
+            .get(self.pos / 8)
+            .ok_or_else(|| anyhow!("truncated H.265 SPS"))?;
+        let bit = (byte >> (7 - self.pos % 8)) & 1;
+        self.pos += 1;
+        Ok(u32::from(bit))
+    }
+
+    fn read_bits(&mut self, count: u32) -> Result<u32, Error> {
+        let mut value = 0;
+        for _ in 0..count {
+            value = (value << 1) | self.read_bit()?;
+        }
+        Ok(value)
+    }
+
+    fn skip(&mut self, count: usize) {
+        self.pos += count;
+    }
+
+    /// Reads an unsigned Exp-Golomb code, see ITU-T H.265 section 9.2.
+    fn read_ue(&mut self) -> Result<u32, Error> {
+        let mut leading_zeros = 0;
+        while self.read_bit()? == 0 {
+            leading_zeros += 1;
+            if leading_zeros > 31 {
+                bail!("invalid Exp-Golomb code in H.265 SPS");
+            }
+        }
+
+        Ok((1 << leading_zeros) - 1 + self.read_bits(leading_zeros)?)
+    }
+}
+
+/// Strips the emulation prevention bytes (`00 00 03`) of a NAL unit payload.
+fn rbsp(nal_payload: &[u8]) -> Vec<u8> {
+    let mut rbsp = Vec::with_capacity(nal_payload.len());
+    let mut zeros = 0;
+
+    for &byte in nal_payload {
+        if zeros >= 2 && byte == 3 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if byte == 0 { zeros + 1 } else { 0 };
+        rbsp.push(byte);
+    }
+
+    rbsp
+}
+
+/// The parts of an SPS needed for the `hvcC` box and the sample entry.
+#[derive(Debug)]
+struct Sps {
+    /// `general_profile_space` through `general_level_idc`, as in the `hvcC` box.
+    general_profile_tier_level: [u8; 12],
+    max_sub_layers: u8,
+    temporal_id_nested: bool,
+    chroma_format_idc: u8,
+    bit_depth_luma_minus8: u8,
+    bit_depth_chroma_minus8: u8,
+    width: u16,
+    height: u16,
+}
+
+/// Parses an SPS NAL unit, see ITU-T H.265 section 7.3.2.2.
+fn parse_sps(nal: &[u8]) -> Result<Sps, Error> {
+    let rbsp = rbsp(nal.get(2..).unwrap_or_default());
+    if rbsp.len() < 13 {
+        bail!("truncated H.265 SPS");
+    }
+
+    let max_sub_layers_minus1 = (rbsp[0] >> 1) & 0x07;
+    let temporal_id_nested = rbsp[0] & 0x01 != 0;
+    let mut general_profile_tier_level = [0; 12];
+    general_profile_tier_level.copy_from_slice(&rbsp[1..13]);
+
+    let mut bits = Bits {
+        data: &rbsp,
+        pos: 13 * 8,
+    };
+
+    let mut sub_layers_present = Vec::new();
+    for _ in 0..max_sub_layers_minus1 {
+        let profile_present = bits.read_bit()? == 1;
+        let level_present = bits.read_bit()? == 1;
+        sub_layers_present.push((profile_present, level_present));
+    }
+    if max_sub_layers_minus1 > 0 {
+        bits.skip(2 * (8 - usize::from(max_sub_layers_minus1)));
+    }
+    for (profile_present, level_present) in sub_layers_present {
+        if profile_present {
+            bits.skip(88);
+        }
+        if level_present {
+            bits.skip(8);
+        }
+    }
+
+    let _sps_seq_parameter_set_id = bits.read_ue()?;
+    let chroma_format_idc = bits.read_ue()?;
+    let separate_colour_plane = chroma_format_idc == 3 && bits.read_bit()? == 1;
+    let mut width = bits.read_ue()?;
+    let mut height = bits.read_ue()?;
+
+    if bits.read_bit()? == 1 {
+        // Conformance window offsets are in chroma samples.
+        let (sub_width, sub_height): (u32, u32) = match (chroma_format_idc, separate_colour_plane) {
+            (1, false) => (2, 2),
+            (2, false) => (2, 1),
+            _ => (1, 1),
+        };
+        let left = bits.read_ue()?;
+        let right = bits.read_ue()?;
+        let top = bits.read_ue()?;
+        let bottom = bits.read_ue()?;
+        width = width.saturating_sub(sub_width.saturating_mul(left.saturating_add(right)));
+        height = height.saturating_sub(sub_height.saturating_mul(top.saturating_add(bottom)));
+    }
+
+    let bit_depth_luma_minus8 = bits.read_ue()?;
+    let bit_depth_chroma_minus8 = bits.read_ue()?;
+
+    Ok(Sps {
+        general_profile_tier_level,
+        max_sub_layers: max_sub_layers_minus1 + 1,
+        temporal_id_nested,
+        chroma_format_idc: u8::try_from(chroma_format_idc)?,
+        bit_depth_luma_minus8: u8::try_from(bit_depth_luma_minus8)?,
+        bit_depth_chroma_minus8: u8::try_from(bit_depth_chroma_minus8)?,
+        width: u16::try_from(width)?,
+        height: u16::try_from(height)?,
+    })
+}
+
+/// Builds the `hvc1` sample entry of a stream from its parameter sets.
+fn hvc1_sample_entry(vps: &[u8], sps: &[u8], pps: &[u8]) -> Result<VideoSampleEntry, Error> {
+    let parsed = parse_sps(sps)?;
+    let mut hvcc = BytesMut::new();
+
+    hvcc.put_u8(1); // configurationVersion
+    hvcc.extend_from_slice(&parsed.general_profile_tier_level);
+    hvcc.put_u16(0xf000); // reserved + min_spatial_segmentation_idc = 0
+    hvcc.put_u8(0xfc); // reserved + parallelismType = unknown
+    hvcc.put_u8(0xfc | (parsed.chroma_format_idc & 0x03));
+    hvcc.put_u8(0xf8 | (parsed.bit_depth_luma_minus8 & 0x07));
+    hvcc.put_u8(0xf8 | (parsed.bit_depth_chroma_minus8 & 0x07));
+    hvcc.put_u16(0); // avgFrameRate = unknown
+    hvcc.put_u8(
+        ((parsed.max_sub_layers & 0x07) << 3) // constantFrameRate = 0, numTemporalLayers
+            | (u8::from(parsed.temporal_id_nested) << 2)
+            | 0x03, // lengthSizeMinusOne: 4-byte NAL unit lengths
+    );
+    hvcc.put_u8(3); // numOfArrays
+
+    for (nal_type, nal) in [(VPS, vps), (SPS, sps), (PPS, pps)] {
+        hvcc.put_u8(0x80 | nal_type); // array_completeness = 1, as none are left in the samples
+        hvcc.put_u16(1); // numNalus
+        hvcc.put_u16(u16::try_from(nal.len())?);
+        hvcc.extend_from_slice(nal);
+    }
+
+    VideoSampleEntry::hevc(parsed.width, parsed.height, &hvcc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::num::NonZeroU32;
+
+    // Parameter sets of a 1920x1080 Main profile stream encoded by x265.
+    const VPS_NAL: &[u8] = &[
+        0x40, 0x01, 0x0c, 0x01, 0xff, 0xff, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00, 0x90, 0x00, 0x00,
+        0x03, 0x00, 0x00, 0x03, 0x00, 0x78, 0x99, 0x98, 0x09,
+    ];
+    const SPS_NAL: &[u8] = &[
+        0x42, 0x01, 0x01, 0x01, 0x60, 0x00, 0x00, 0x03, 0x00, 0x90, 0x00, 0x00, 0x03, 0x00, 0x00,
+        0x03, 0x00, 0x78, 0xa0, 0x03, 0xc0, 0x80, 0x10, 0xe5, 0x96, 0x66, 0x69, 0x24, 0xca, 0xe0,
+        0x10, 0x00, 0x00, 0x03, 0x00, 0x10, 0x00, 0x00, 0x03, 0x01, 0xe0, 0x80,
+    ];
+    const PPS_NAL: &[u8] = &[0x44, 0x01, 0xc1, 0x72, 0xb4, 0x62, 0x40];
+
+    /// An IDR_W_RADL slice, which starts a random access point.
+    const IDR_NAL: &[u8] = &[0x26, 0x01, 0xaf, 0x06, 0xb8, 0x61, 0x3f, 0x02];
+
+    /// A TRAIL_R slice.
+    const TRAIL_NAL: &[u8] = &[0x02, 0x01, 0xd0, 0x09, 0x7e];
+
+    fn timestamp(timestamp: i64) -> Timestamp {
+        Timestamp::new(timestamp, NonZeroU32::new(90_000).unwrap(), 0).unwrap()
+    }
+
+    /// An aggregation packet holding `nals`.
+    fn aggregation_packet(nals: &[&[u8]]) -> Vec<u8> {
+        let mut packet = vec![AP << 1, 0x01];
+        for nal in nals {
+            packet.extend_from_slice(&(nal.len() as u16).to_be_bytes());
+            packet.extend_from_slice(nal);
+        }
+        packet
+    }
+
+    /// `nal` split into fragmentation units with up to `len` bytes of it each.
+    fn fragmentation_units(nal: &[u8], len: usize) -> Vec<Vec<u8>> {
+        let chunks: Vec<&[u8]> = nal[2..].chunks(len).collect();
+        chunks
+            .iter()
+            .enumerate()
+            .map(|(index, chunk)| {
+                let mut fu_header = nal_type(nal[0]);
+                if index == 0 {
+                    fu_header |= 0x80;
+                }
+                if index == chunks.len() - 1 {
+                    fu_header |= 0x40;
+                }
+
+                let mut packet = vec![(nal[0] & 0x81) | (FU << 1), nal[1], fu_header];
+                packet.extend_from_slice(chunk);
+                packet
+            })
+            .collect()
+    }
+
+    /// `nals` prefixed with their 4-byte length, as in the samples.
+    fn length_prefixed(nals: &[&[u8]]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for nal in nals {
+            data.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+            data.extend_from_slice(nal);
+        }
+        data
+    }
+
+    fn depacketizer_with_parameter_sets() -> Depacketizer {
+        let mut depacketizer = Depacketizer::default();
+        for nal in [VPS_NAL, SPS_NAL, PPS_NAL] {
+            depacketizer.push(nal, timestamp(0), false, 0).unwrap();
+        }
+        depacketizer
+    }
+
+    #[test]
+    fn rbsp_strips_emulation_prevention_bytes() {
+        assert_eq!(
+            rbsp(&[0x00, 0x00, 0x03, 0x01, 0x00, 0x00, 0x03, 0x00, 0x03]),
+            vec![0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x03]
+        );
+    }
+
+    #[test]
+    fn parse_sps_reads_a_1080p_main_profile_sps() {
+        let sps = parse_sps(SPS_NAL).unwrap();
+
+        assert_eq!(
+            sps.general_profile_tier_level,
+            [0x01, 0x60, 0x00, 0x00, 0x00, 0x90, 0x00, 0x00, 0x00, 0x00, 0x00, 0x78]
+        );
+        assert_eq!(sps.max_sub_layers, 1);
+        assert!(sps.temporal_id_nested);
+        assert_eq!(sps.chroma_format_idc, 1);
+        assert_eq!(sps.bit_depth_luma_minus8, 0);
+        assert_eq!(sps.bit_depth_chroma_minus8, 0);
+        assert_eq!((sps.width, sps.height), (1920, 1080));
+    }
+
+    #[test]
+    fn parse_sps_rejects_truncated_ones() {
+        assert!(parse_sps(&SPS_NAL[..12]).is_err());
+        assert!(parse_sps(&SPS_NAL[..20]).is_err());
+    }
+
+    #[test]
+    fn hvc1_sample_entry_holds_the_parameter_sets_in_hvcc() {
+        let entry = hvc1_sample_entry(VPS_NAL, SPS_NAL, PPS_NAL).unwrap();
+
+        assert!(entry.is_hevc());
+        assert_eq!((entry.width, entry.height), (1920, 1080));
+
+        // The `hvcC` box follows the 78 bytes of visual sample entry fields.
+        let hvcc_box = &entry.data[8 + 78..];
+        assert_eq!(&hvcc_box[4..8], b"hvcC");
+        assert_eq!(
+            u32::from_be_bytes(hvcc_box[..4].try_into().unwrap()) as usize,
+            hvcc_box.len()
+        );
+
+        let mut expected = vec![0x01];
+        expected.extend_from_slice(&parse_sps(SPS_NAL).unwrap().general_profile_tier_level);
+        expected.extend_from_slice(&[
+            0xf0, 0x00, // min_spatial_segmentation_idc
+            0xfc, // parallelismType
+            0xfd, // chroma_format_idc = 1
+            0xf8, 0xf8, // bit depths minus 8
+            0x00, 0x00, // avgFrameRate
+            0x0f, // numTemporalLayers = 1, temporalIdNested, lengthSizeMinusOne = 3
+            0x03, // numOfArrays
+        ]);
+        for (nal_type, nal) in [(VPS, VPS_NAL), (SPS, SPS_NAL), (PPS, PPS_NAL)] {
+            expected.extend_from_slice(&[0x80 | nal_type, 0x00, 0x01]);
+            expected.extend_from_slice(&(nal.len() as u16).to_be_bytes());
+            expected.extend_from_slice(nal);
+        }
+
+        assert_eq!(&hvcc_box[8..], &expected[..]);
+    }
+
+    #[test]
+    fn depacketizer_takes_parameter_sets_out_of_single_nal_packets() {
+        let mut depacketizer = depacketizer_with_parameter_sets();
+        depacketizer.push(IDR_NAL, timestamp(0), true, 0).unwrap();
+        depacketizer
+            .push(TRAIL_NAL, timestamp(3000), true, 0)
+            .unwrap();
+
+        let idr = depacketizer.pull().unwrap();
+        assert_eq!(&idr.data[..], &length_prefixed(&[IDR_NAL])[..]);
+        assert_eq!(idr.timestamp.timestamp(), 0);
+        assert!(idr.is_random_access_point);
+        assert!(idr.sample_entry.is_hevc());
+        assert_eq!(
+            (idr.sample_entry.width, idr.sample_entry.height),
+            (1920, 1080)
+        );
+
+        let trail = depacketizer.pull().unwrap();
+        assert_eq!(&trail.data[..], &length_prefixed(&[TRAIL_NAL])[..]);
+        assert_eq!(trail.timestamp.timestamp(), 3000);
+        assert!(!trail.is_random_access_point);
+        assert_eq!(trail.sample_entry, idr.sample_entry);
+
+        assert!(depacketizer.pull().is_none());
+    }
+
+    #[test]
+    fn depacketizer_reassembles_aggregation_and_fragmentation_units() {
+        let mut depacketizer = Depacketizer::default();
+        let parameter_sets = aggregation_packet(&[VPS_NAL, SPS_NAL, PPS_NAL]);
+        depacketizer
+            .push(&parameter_sets, timestamp(0), false, 0)
+            .unwrap();
+
+        let fragments = fragmentation_units(IDR_NAL, 2);
+        assert_eq!(fragments.len(), 3);
+        for (index, fragment) in fragments.iter().enumerate() {
+            let mark = index == fragments.len() - 1;
+            depacketizer.push(fragment, timestamp(0), mark, 0).unwrap();
+        }
+
+        let slices = aggregation_packet(&[TRAIL_NAL, TRAIL_NAL]);
+        depacketizer
+            .push(&slices, timestamp(3000), true, 0)
+            .unwrap();
+
+        let idr = depacketizer.pull().unwrap();
+        assert_eq!(&idr.data[..], &length_prefixed(&[IDR_NAL])[..]);
+        assert!(idr.is_random_access_point);
+
+        let trail = depacketizer.pull().unwrap();
+        assert_eq!(
+            &trail.data[..],
+            &length_prefixed(&[TRAIL_NAL, TRAIL_NAL])[..]
+        );
+        assert!(!trail.is_random_access_point);
+    }
+
+    #[test]
+    fn depacketizer_rejects_truncated_aggregation_packets() {
+        let mut packet = aggregation_packet(&[VPS_NAL]);
+        packet.pop();
+
+        assert!(Depacketizer::default()
+            .push(&packet, timestamp(0), true, 0)
+            .is_err());
+    }
+
+    #[test]
+    fn depacketizer_drops_fragments_after_a_loss() {
+        let mut depacketizer = depacketizer_with_parameter_sets();
+        let fragments = fragmentation_units(IDR_NAL, 2);
+
+        depacketizer
+            .push(&fragments[0], timestamp(0), false, 0)
+            .unwrap();
+        depacketizer
+            .push(&fragments[1], timestamp(0), false, 1)
+            .unwrap();
+        depacketizer
+            .push(&fragments[2], timestamp(0), false, 0)
+            .unwrap();
+        depacketizer.push(TRAIL_NAL, timestamp(0), true, 0).unwrap();
+
+        let access_unit = depacketizer.pull().unwrap();
+        assert_eq!(&access_unit.data[..], &length_prefixed(&[TRAIL_NAL])[..]);
+        assert_eq!(access_unit.loss, 1);
+        assert!(!access_unit.is_random_access_point);
+    }
+
+    #[test]
+    fn depacketizer_ends_access_units_on_a_new_timestamp() {
+        let mut depacketizer = depacketizer_with_parameter_sets();
+        depacketizer.push(IDR_NAL, timestamp(0), false, 0).unwrap();
+        depacketizer
+            .push(TRAIL_NAL, timestamp(3000), false, 0)
+            .unwrap();
+
+        let idr = depacketizer.pull().unwrap();
+        assert_eq!(idr.timestamp.timestamp(), 0);
+        assert!(idr.is_random_access_point);
+        assert!(depacketizer.pull().is_none());
+    }
+
+    #[test]
+    fn depacketizer_discards_pictures_before_the_parameter_sets_and_paci_packets() {
+        let mut depacketizer = Depacketizer::default();
+        depacketizer.push(IDR_NAL, timestamp(0), true, 0).unwrap();
+
+        let paci = [PACI << 1, 0x01, 0x00, 0x00, 0xaa];
+        depacketizer.push(&paci, timestamp(3000), true, 0).unwrap();
+
+        assert!(depacketizer.pull().is_none());
+    }
+
+    #[test]
+    fn irap_detection_covers_bla_idr_and_cra_slices() {
+        assert!(!is_irap(1)); // TRAIL_R
+        assert!(is_irap(16)); // BLA_W_LP
+        assert!(is_irap(19)); // IDR_W_RADL
+        assert!(is_irap(21)); // CRA_NUT
+        assert!(!is_irap(VPS));
+        assert_eq!(nal_unit_type(IDR_NAL), Some(19));
+    }
+
+    /// The parameter sets above, as a camera would describe them in its SDP.
+    const FMTP: &str = "profile-id=1; sprop-vps=QAEMAf//AWAAAAMAkAAAAwAAAwB4mZgJ; \
+        sprop-sps=QgEBAWAAAAMAkAAAAwAAAwB4oAPAgBDllmZpJMrgEAAAAwAQAAADAeCA; sprop-pps=RAHBcrRiQA==";
+
+    #[test]
+    fn decode_base64_accepts_missing_padding() {
+        assert_eq!(
+            decode_base64("RAHBcrRiQA==").unwrap(),
+            [0x44, 0x01, 0xc1, 0x72, 0xb4, 0x62, 0x40]
+        );
+        assert_eq!(
+            decode_base64("RAHBcrRiQA").unwrap(),
+            [0x44, 0x01, 0xc1, 0x72, 0xb4, 0x62, 0x40]
+        );
+        assert_eq!(decode_base64("RA%B"), None);
+    }
+
+    #[test]
+    fn parameter_sets_are_read_from_the_fmtp_line() {
+        let parameter_sets: ParameterSets = FMTP.parse().unwrap();
+
+        assert_eq!(parameter_sets.vps, VPS_NAL);
+        assert_eq!(parameter_sets.sps, SPS_NAL);
+        assert_eq!(parameter_sets.pps, PPS_NAL);
+
+        let missing = "sprop-vps=QAEMAf//AWAAAAMAkAAAAwAAAwB4mZgJ".parse::<ParameterSets>();
+        assert_eq!(missing.unwrap_err().to_string(), "sprop-sps is missing");
+
+        let swapped = FMTP
+            .replace("sprop-vps", "sprop-x")
+            .replace("sprop-pps", "sprop-vps");
+        let swapped = format!("{}; sprop-pps=RAHBcrRiQA==", swapped).parse::<ParameterSets>();
+        assert_eq!(
+            swapped.unwrap_err().to_string(),
+            "sprop-vps isn't a NAL unit of type 32"
+        );
+    }
+
+    #[test]
+    fn depacketizer_seeded_with_parameter_sets_writes_the_first_picture() {
+        let parameter_sets: ParameterSets = FMTP.parse().unwrap();
+        let mut depacketizer = Depacketizer::new(Some(&parameter_sets));
+
+        depacketizer.push(IDR_NAL, timestamp(0), true, 0).unwrap();
+
+        let access_unit = depacketizer.pull().unwrap();
+        assert!(access_unit.is_random_access_point);
+        assert_eq!(access_unit.data, length_prefixed(&[IDR_NAL]));
+    }
+}
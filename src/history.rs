@@ -0,0 +1,329 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::{fs, io};
+use tokio::io::AsyncWriteExt;
+
+use crate::error::BotError;
+use crate::recording_coordinator::Recording;
+use crate::store::read_json_file;
+
+/// How many recordings the history keeps, the oldest are dropped first.
+const MAX_ENTRIES: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", tag = "status")]
+pub enum Outcome {
+    Sent,
+    Failed { reason: String },
+}
+
+/// What was sent: a video recorded from the camera, a snapshot or a clip of past footage.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Kind {
+    #[default]
+    Video,
+    Snapshot,
+    Clip,
+}
+
+/// A recording sent (or not) to a chat.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryEntry {
+    pub camera: String,
+
+    /// Entries written before snapshots and clips were recorded are all videos.
+    #[serde(default)]
+    pub kind: Kind,
+
+    /// The user who asked for it, none for motion alerts and schedules.
+    pub requester: Option<i64>,
+    pub chat_id: i64,
+    pub started_at: DateTime<Utc>,
+
+    /// Requested duration in seconds.
+    pub duration: u64,
+
+    /// Size of the file in bytes, if it was written.
+    pub size: Option<u64>,
+
+    /// Frames written into the file, if it was written.
+    pub video_frames: Option<u32>,
+    pub audio_frames: Option<u32>,
+
+    /// How long recording and sending took, in milliseconds.
+    #[serde(default)]
+    pub elapsed_ms: u64,
+    pub outcome: Outcome,
+}
+
+impl HistoryEntry {
+    /// Fills in what is known about the recording once it is written.
+    pub async fn set_recording(&mut self, recording: &Recording) {
+        self.set_size(&recording.output).await;
+        self.video_frames = Some(recording.stats.video_frames);
+        self.audio_frames = Some(recording.stats.audio_frames);
+    }
+
+    /// Sets the size of the file at `output`, once it is written.
+    pub async fn set_size(&mut self, output: &Path) {
+        self.size = tokio::fs::metadata(output)
+            .await
+            .ok()
+            .map(|metadata| metadata.len());
+    }
+
+    pub fn new(camera: String, requester: Option<i64>, chat_id: i64) -> Self {
+        HistoryEntry {
+            camera,
+            kind: Kind::Video,
+            requester,
+            chat_id,
+            started_at: Utc::now(),
+            duration: 0,
+            size: None,
+            video_frames: None,
+            audio_frames: None,
+            elapsed_ms: 0,
+            outcome: Outcome::Sent,
+        }
+    }
+}
+
+/// The last `MAX_ENTRIES` recordings, appended to a JSON Lines file as they're made.
+///
+/// The file is rewritten with only the entries kept once it holds twice as many,
+/// rather than on every recording.
+pub struct History {
+    path: PathBuf,
+    entries: Mutex<VecDeque<HistoryEntry>>,
+
+    /// Lines in the file, locked while writing it.
+    file_lines: tokio::sync::Mutex<usize>,
+}
+
+impl History {
+    /// Reads the entries of `path`, or of the former `history.json` next to it when
+    /// there's no `path` yet. Lines that can't be read, like one cut short by a crash,
+    /// are skipped.
+    pub fn open(path: PathBuf) -> Result<Self, BotError> {
+        let (entries, file_lines) = match fs::read_to_string(&path) {
+            Ok(lines) => {
+                let mut entries = VecDeque::new();
+                let mut file_lines = 0;
+
+                for line in lines.lines() {
+                    file_lines += 1;
+                    match serde_json::from_str(line) {
+                        Ok(entry) => push_entry(&mut entries, entry),
+                        Err(parse_error) => log::warn!(
+                            "Skipping line {} of '{}': {}",
+                            file_lines,
+                            path.display(),
+                            parse_error
+                        ),
+                    }
+                }
+
+                (entries, file_lines)
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                (read_json_file(&path.with_extension("json"))?, 0)
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        Ok(History {
+            path,
+            entries: Mutex::new(entries),
+            file_lines: tokio::sync::Mutex::new(file_lines),
+        })
+    }
+
+    /// The last `count` entries, newest first, matching `filter`.
+    pub fn recent(
+        &self,
+        count: usize,
+        filter: impl Fn(&HistoryEntry) -> bool,
+    ) -> Vec<HistoryEntry> {
+        let entries = self.entries.lock().unwrap();
+
+        entries
+            .iter()
+            .rev()
+            .filter(|entry| filter(entry))
+            .take(count)
+            .cloned()
+            .collect()
+    }
+
+    /// Adds an entry. Failures are only logged, history must not get in the way of recordings.
+    pub async fn record(&self, entry: HistoryEntry) {
+        let mut file_lines = self.file_lines.lock().await;
+
+        // Also rewrites a legacy `history.json`, which left the file empty.
+        let result = if *file_lines >= 2 * MAX_ENTRIES || *file_lines == 0 {
+            let mut entries = self.entries.lock().unwrap().clone();
+            push_entry(&mut entries, entry.clone());
+            self.rewrite(&entries).await.map(|()| entries.len())
+        } else {
+            self.append(&entry).await.map(|()| *file_lines + 1)
+        };
+
+        match result {
+            Ok(lines) => {
+                *file_lines = lines;
+                push_entry(&mut self.entries.lock().unwrap(), entry);
+            }
+            Err(history_error) => {
+                log::error!("Failed to save recording history: {}", history_error);
+            }
+        }
+    }
+
+    async fn append(&self, entry: &HistoryEntry) -> Result<(), anyhow::Error> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+
+        // tokio writes in the background, a file dropped before flushing may lose the line.
+        file.flush().await?;
+
+        Ok(())
+    }
+
+    /// Written next to its final path first, so a crash never leaves it half written.
+    async fn rewrite(&self, entries: &VecDeque<HistoryEntry>) -> Result<(), anyhow::Error> {
+        let mut lines = String::new();
+        for entry in entries {
+            lines.push_str(&serde_json::to_string(entry)?);
+            lines.push('\n');
+        }
+
+        let tmp_path = self.path.with_extension("jsonl.tmp");
+        tokio::fs::write(&tmp_path, lines).await?;
+        tokio::fs::rename(&tmp_path, &self.path).await?;
+
+        Ok(())
+    }
+}
+
+/// Adds `entry`, dropping the oldest ones past `MAX_ENTRIES`.
+fn push_entry(entries: &mut VecDeque<HistoryEntry>, entry: HistoryEntry) {
+    entries.push_back(entry);
+
+    while entries.len() > MAX_ENTRIES {
+        entries.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn history_path(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("ipcamera_bot_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("history.jsonl")
+    }
+
+    fn entry(chat_id: i64) -> HistoryEntry {
+        HistoryEntry::new("garden".to_string(), None, chat_id)
+    }
+
+    fn chat_ids(history: &History) -> Vec<i64> {
+        history
+            .recent(usize::MAX, |_| true)
+            .iter()
+            .map(|entry| entry.chat_id)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn record_appends_a_line_per_entry() {
+        let path = history_path("history_append");
+        let history = History::open(path.clone()).unwrap();
+
+        for chat_id in 1..=3 {
+            history.record(entry(chat_id)).await;
+        }
+
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 3);
+        assert_eq!(chat_ids(&History::open(path.clone()).unwrap()), [3, 2, 1]);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn open_skips_a_truncated_line() {
+        let path = history_path("history_truncated");
+        let history = History::open(path.clone()).unwrap();
+        history.record(entry(1)).await;
+
+        let mut lines = fs::read_to_string(&path).unwrap();
+        lines.push_str("{\"camera\":\"gar");
+        fs::write(&path, lines).unwrap();
+
+        assert_eq!(chat_ids(&History::open(path.clone()).unwrap()), [1]);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn the_file_is_compacted_to_the_kept_entries() {
+        let path = history_path("history_compact");
+        let history = History::open(path.clone()).unwrap();
+
+        for chat_id in 0..2 * MAX_ENTRIES as i64 + 1 {
+            history.record(entry(chat_id)).await;
+        }
+
+        assert_eq!(
+            fs::read_to_string(&path).unwrap().lines().count(),
+            MAX_ENTRIES
+        );
+        let reopened = History::open(path.clone()).unwrap();
+        assert_eq!(chat_ids(&reopened), chat_ids(&history));
+        assert_eq!(chat_ids(&reopened)[0], 2 * MAX_ENTRIES as i64);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_legacy_json_history_is_carried_over() {
+        let path = history_path("history_legacy");
+        let legacy: VecDeque<_> = [entry(1), entry(2)].into();
+        fs::write(
+            path.with_extension("json"),
+            serde_json::to_string(&legacy).unwrap(),
+        )
+        .unwrap();
+
+        let history = History::open(path.clone()).unwrap();
+        history.record(entry(3)).await;
+
+        assert_eq!(chat_ids(&History::open(path.clone()).unwrap()), [3, 2, 1]);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn entries_from_before_snapshots_were_recorded_are_videos() {
+        let entry: HistoryEntry = serde_json::from_str(
+            r#"{"camera":"garden","requester":null,"chatId":1,"startedAt":"2024-01-01T00:00:00Z","duration":5,"size":null,"outcome":{"status":"sent"}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(entry.kind, Kind::Video);
+        assert_eq!(entry.video_frames, None);
+        assert_eq!(entry.elapsed_ms, 0);
+    }
+}
@@ -0,0 +1,360 @@
+use anyhow::{bail, Context, Error};
+use std::collections::{HashMap, VecDeque};
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::fs::File;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::JoinSet;
+use tokio::time::sleep;
+
+use crate::mp4::{self, MediaSample, Mp4RecorderOptions};
+use crate::mp4_writer::{AudioSampleEntry, Layout, Mp4Writer, WriterStats};
+use crate::send_video_command::Camera;
+use crate::store::Store;
+
+/// How many live samples a slow recording may lag behind before it loses some.
+const BROADCAST_CAPACITY: usize = 1024;
+
+/// Delay before reconnecting after the first session failure.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Upper bound for the exponential backoff between session failures.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Live feeds by camera name, for the cameras configured with a `preRoll` or `motion`.
+/// Kept up to date with the camera config by [`CameraTasks`](crate::camera_tasks::CameraTasks).
+pub type LiveFeeds = Arc<RwLock<HashMap<String, Arc<LiveFeed>>>>;
+
+struct FeedState {
+    /// The last `pre_roll` seconds of samples, starting with a keyframe once full.
+    ring: VecDeque<MediaSample>,
+    audio_sample_entry: Option<AudioSampleEntry>,
+
+    /// Incremented on every new RTSP session, 0 until the first one.
+    /// Timestamps of samples from different sessions aren't comparable.
+    session: u64,
+
+    /// Whether the current session is playing.
+    connected: bool,
+}
+
+impl FeedState {
+    /// Drops samples older than `pre_roll`, keeping the buffer aligned to a keyframe.
+    fn trim(&mut self, pre_roll: Duration) {
+        let Some(newest_secs) = self.ring.back().map(MediaSample::time_secs) else {
+            return;
+        };
+        let cutoff_secs = newest_secs - pre_roll.as_secs_f64();
+
+        // Everything before the latest keyframe at or before the cutoff can go,
+        // since nothing after it depends on it.
+        let keep_from = self.ring.iter().rposition(|sample| {
+            sample.is_random_access_point() && sample.time_secs() <= cutoff_secs
+        });
+
+        if let Some(keep_from) = keep_from {
+            self.ring.drain(..keep_from);
+        }
+    }
+
+    /// The buffered samples from the first keyframe on.
+    fn pre_roll_samples(&self) -> Vec<MediaSample> {
+        self.ring
+            .iter()
+            .skip_while(|sample| !sample.is_random_access_point())
+            .cloned()
+            .collect()
+    }
+}
+
+/// An always-connected camera session that keeps the last few seconds in memory,
+/// so recordings can include what happened right before they were requested.
+pub struct LiveFeed {
+    camera_name: String,
+    pre_roll: Duration,
+    state: Mutex<FeedState>,
+    sender: broadcast::Sender<(u64, MediaSample)>,
+}
+
+impl LiveFeed {
+    pub fn new(camera_name: String, pre_roll: Duration) -> Self {
+        let (sender, _) = broadcast::channel(BROADCAST_CAPACITY);
+
+        LiveFeed {
+            camera_name,
+            pre_roll,
+            state: Mutex::new(FeedState {
+                ring: VecDeque::new(),
+                audio_sample_entry: None,
+                session: 0,
+                connected: false,
+            }),
+            sender,
+        }
+    }
+
+    /// Live samples from now on, tagged with their session.
+    pub fn subscribe(&self) -> broadcast::Receiver<(u64, MediaSample)> {
+        self.sender.subscribe()
+    }
+
+    fn start_session(&self, audio_sample_entry: Option<AudioSampleEntry>) {
+        let mut state = self.state.lock().unwrap();
+        state.ring.clear();
+        state.audio_sample_entry = audio_sample_entry;
+        state.session += 1;
+        state.connected = true;
+    }
+
+    /// Forgets the samples of the session that just ended, they'd be stale by the next one.
+    fn end_session(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.ring.clear();
+        state.connected = false;
+    }
+
+    /// Whether the camera is streaming, so recordings and snapshots can be taken from the feed.
+    pub fn is_connected(&self) -> bool {
+        self.state.lock().unwrap().connected
+    }
+
+    fn push(&self, sample: MediaSample) {
+        let mut state = self.state.lock().unwrap();
+
+        // Sent while holding the lock, so a recording taking the pre-roll and
+        // subscribing at the same time sees every sample exactly once.
+        let _ = self.sender.send((state.session, sample.clone()));
+
+        state.ring.push_back(sample);
+        state.trim(self.pre_roll);
+    }
+
+    /// Keeps the camera connected while it's armed, reconnecting with backoff, until aborted.
+    /// Disarming the camera disconnects it, which pauses its motion detector too.
+    pub async fn run(self: Arc<Self>, camera: Camera, store: Arc<Store>) {
+        let mut backoff = INITIAL_BACKOFF;
+
+        loop {
+            if !store.arming.is_armed(&camera) {
+                log::info!("Pausing live feed for disarmed camera {}", self.camera_name);
+                store.arming.wait_until(&camera, true).await;
+                log::info!("Resuming live feed for camera {}", self.camera_name);
+                backoff = INITIAL_BACKOFF;
+            }
+
+            let options = match Mp4RecorderOptions::try_from(camera.clone()) {
+                Ok(options) => options,
+                Err(config_error) => {
+                    log::error!(
+                        "Live feed for camera {} stopped: {}",
+                        self.camera_name,
+                        config_error
+                    );
+                    return;
+                }
+            };
+            let started_at = Instant::now();
+
+            let result = mp4::stream_samples(
+                &options,
+                |audio_sample_entry| self.start_session(audio_sample_entry),
+                |sample| self.push(sample),
+                store.arming.wait_until(&camera, false),
+            )
+            .await;
+
+            self.end_session();
+
+            // Stopped because the camera was disarmed, which is handled above.
+            let Err(feed_error) = result else {
+                continue;
+            };
+
+            log::error!(
+                "Live feed for camera {} stopped. Reconnecting in {:?}. Reason:",
+                self.camera_name,
+                backoff
+            );
+            log::error!("{:?}", feed_error);
+
+            if started_at.elapsed() > MAX_BACKOFF {
+                backoff = INITIAL_BACKOFF;
+            }
+
+            sleep(backoff).await;
+            backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+        }
+    }
+
+    /// Writes the pre-roll, then `duration` of live samples, into `output`.
+    pub async fn record(
+        &self,
+        output: &Path,
+        duration: Duration,
+        layout: Layout,
+    ) -> Result<WriterStats, Error> {
+        let (pre_roll, audio_sample_entry, session, connected, mut receiver) = {
+            let state = self.state.lock().unwrap();
+            (
+                state.pre_roll_samples(),
+                state.audio_sample_entry.clone(),
+                state.session,
+                state.connected,
+                self.sender.subscribe(),
+            )
+        };
+
+        if !connected {
+            bail!("Live feed for camera {} isn't connected", self.camera_name);
+        }
+
+        let tmp_filename = mp4::partial_filename(output);
+        let file = mp4::create_output(&tmp_filename).await?;
+        let mut mp4_writer =
+            Mp4Writer::with_audio_sample_entry(audio_sample_entry.clone(), true, layout, file)
+                .await?;
+
+        let result = copy_live(
+            &mut mp4_writer,
+            pre_roll,
+            &mut receiver,
+            session,
+            audio_sample_entry.is_some(),
+            duration,
+        )
+        .await;
+        let stats = mp4_writer.stats();
+
+        mp4::finish_partial_file(mp4_writer.finish().await, &tmp_filename, output).await;
+
+        result.map(|()| stats)
+    }
+
+    /// Writes the latest keyframe into `output` as a single-frame `.mp4`, waiting
+    /// up to `timeout` for one when none was received yet.
+    pub async fn snapshot(&self, output: &Path, timeout: Duration) -> Result<(), Error> {
+        let (buffered, mut receiver) = {
+            let state = self.state.lock().unwrap();
+            let keyframe = state
+                .ring
+                .iter()
+                .rev()
+                .find(|sample| sample.is_random_access_point())
+                .cloned();
+            (keyframe, self.sender.subscribe())
+        };
+
+        let keyframe = match buffered {
+            Some(keyframe) => keyframe,
+            None => tokio::time::timeout(timeout, next_keyframe(&mut receiver))
+                .await
+                .with_context(|| format!("No keyframe received within {:?}", timeout))??,
+        };
+
+        let tmp_filename = mp4::partial_filename(output);
+        let file = mp4::create_output(&tmp_filename).await?;
+        let mut mp4_writer =
+            Mp4Writer::with_audio_sample_entry(None, true, Layout::Progressive, file).await?;
+
+        let result = keyframe.write_to(&mut mp4_writer).await;
+
+        mp4::finish_partial_file(mp4_writer.finish().await, &tmp_filename, output).await;
+
+        result
+    }
+}
+
+/// Waits for the next live keyframe.
+async fn next_keyframe(
+    receiver: &mut broadcast::Receiver<(u64, MediaSample)>,
+) -> Result<MediaSample, Error> {
+    loop {
+        match receiver.recv().await {
+            Ok((_, sample)) if sample.is_random_access_point() => return Ok(sample),
+            Ok(_) | Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => bail!("Live feed closed"),
+        }
+    }
+}
+
+/// Writes `pre_roll` and then live samples of `session` for `duration`.
+async fn copy_live(
+    mp4_writer: &mut Mp4Writer<File>,
+    pre_roll: Vec<MediaSample>,
+    receiver: &mut broadcast::Receiver<(u64, MediaSample)>,
+    session: u64,
+    has_audio: bool,
+    duration: Duration,
+) -> Result<(), Error> {
+    // Video must start with a keyframe, and audio can't start before video.
+    let mut started = !pre_roll.is_empty();
+
+    for sample in &pre_roll {
+        if has_audio || !matches!(sample, MediaSample::Audio { .. }) {
+            sample.write_to(mp4_writer).await?;
+        }
+    }
+
+    let stop = sleep(duration);
+    tokio::pin!(stop);
+
+    loop {
+        tokio::select! {
+            received = receiver.recv() => {
+                let (sample_session, sample) = match received {
+                    Ok(received) => received,
+                    Err(RecvError::Lagged(skipped)) => {
+                        log::warn!("Recording fell behind the live feed, {} samples lost", skipped);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => bail!("Live feed closed"),
+                };
+
+                if sample_session != session {
+                    log::warn!("Live feed reconnected, stopping the recording early");
+                    break;
+                }
+
+                started = started || sample.is_random_access_point();
+
+                if !started || (!has_audio && matches!(sample, MediaSample::Audio { .. })) {
+                    continue;
+                }
+
+                sample.write_to(mp4_writer).await?;
+            },
+            _ = &mut stop => {
+                log::info!("Stopping after {} seconds", duration.as_secs());
+                break;
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// Starts a live feed for `camera` into `tasks` when it has a `preRoll` or `motion`.
+pub fn spawn_feed(
+    camera: &Camera,
+    store: &Arc<Store>,
+    tasks: &mut JoinSet<()>,
+) -> Option<Arc<LiveFeed>> {
+    if camera.pre_roll.is_none() && camera.motion.is_none() {
+        return None;
+    }
+
+    let pre_roll = camera.pre_roll.unwrap_or(0);
+    let feed = Arc::new(LiveFeed::new(
+        camera.name.clone(),
+        Duration::from_secs(pre_roll),
+    ));
+    log::info!(
+        "Keeping camera {} connected for {} sec of pre-roll",
+        camera.name,
+        pre_roll
+    );
+    tasks.spawn(feed.clone().run(camera.clone(), store.clone()));
+
+    Some(feed)
+}
@@ -1,25 +1,127 @@
 extern crate futures;
 extern crate log;
 
+mod alerts;
+mod arming;
+mod auth;
+mod camera_tasks;
+mod clip;
+mod config;
+mod destination;
+mod dispatcher;
+mod error;
+mod h265;
+mod history;
+mod live_feed;
+mod motion;
 mod mp4;
+mod mp4_reader;
 mod mp4_writer;
+mod nvr;
+mod recording_coordinator;
+mod scheduler;
+mod send_arm_command;
+mod send_clip_command;
+mod send_history_command;
+mod send_prefs_command;
+mod send_reload_command;
+mod send_role_command;
+mod send_schedule_command;
+mod send_snapshot_command;
+mod send_subscribe_command;
 mod send_video_command;
 mod server;
+mod store;
+mod subscriptions;
 
+use std::sync::Arc;
+use std::{env, process};
+use tokio::task::JoinSet;
+
+use crate::camera_tasks::CameraTasks;
+use crate::dispatcher::Dispatcher;
+use crate::recording_coordinator::RecordingCoordinator;
 use crate::server::start_telegram_server;
+use crate::store::Store;
+
+/// Validates the camera config for `--check-config`, returning the exit code.
+fn check_config() -> i32 {
+    match config::check() {
+        Ok(camera_config) => {
+            println!(
+                "Camera config is valid, {} camera(s) configured.",
+                camera_config.cameras.len()
+            );
+            0
+        }
+        Err(config_error) => {
+            eprintln!("{}", config_error);
+            1
+        }
+    }
+}
 
 #[tokio::main]
 async fn main() {
     dotenv::dotenv().ok();
     env_logger::init();
 
+    if env::args().skip(1).any(|arg| arg == "--check-config") {
+        process::exit(check_config());
+    }
+
     log::info!("Initializing process..");
 
-    tokio::select! {
-        _ = start_telegram_server() => {},
+    let dispatcher = Arc::new(Dispatcher::from_env());
+
+    // Commands keep failing until a valid config is loaded, the watcher retries on changes.
+    match config::reload() {
+        Ok(_) => log::info!("Loaded the camera config."),
+        Err(config_error) => log::error!("Failed to load camera config: {}", config_error),
+    }
+
+    let store = match Store::open() {
+        Ok(store) => Arc::new(store),
+        Err(store_error) => {
+            // Starting without it would overwrite the state files on the next change.
+            log::error!("Failed to load the bot state: {}", store_error);
+            return;
+        }
     };
 
-    tokio::signal::ctrl_c().await.unwrap();
+    // Continuous recorders, live feeds and motion detectors follow the config as it's reloaded.
+    let camera_tasks = Arc::new(CameraTasks::new(store.clone()));
+    match camera_tasks.apply_current() {
+        Ok(changes) => log::info!("Applied the camera config, {}", changes),
+        Err(config_error) => log::error!(
+            "Continuous recording, live feeds and motion detection start once a valid camera config is loaded: {}",
+            config_error
+        ),
+    }
+
+    let mut config_watcher = JoinSet::new();
+    config_watcher.spawn(config::watch(camera_tasks.clone()));
 
-    log::info!("Received Ctrl-C, shutting down.");
+    let coordinator = Arc::new(RecordingCoordinator::new(camera_tasks.live_feeds()));
+
+    let server = start_telegram_server(dispatcher.clone(), coordinator, store, camera_tasks);
+
+    tokio::select! {
+        result = server => {
+            if let Err(err) = result {
+                log::error!("Telegram server stopped: {}", err);
+            }
+        },
+        _ = tokio::signal::ctrl_c() => {
+            log::info!("Received Ctrl-C, shutting down.");
+        },
+    };
+
+    // In-flight recordings are allowed to finish, unless Ctrl-C is pressed again.
+    tokio::select! {
+        _ = dispatcher.drain() => {},
+        _ = tokio::signal::ctrl_c() => {
+            log::warn!("Received Ctrl-C again, exiting without waiting for in-flight commands.");
+        },
+    };
 }
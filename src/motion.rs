@@ -0,0 +1,525 @@
+//! Motion detection without decoding.
+//!
+//! A static scene compresses into small predicted (non-key) frames, while
+//! movement makes them grow. The detector compares the size of each predicted
+//! frame against a rolling baseline of the camera, which is cheap enough for an
+//! old phone without a GPU.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::task::JoinSet;
+
+use crate::h265;
+use crate::live_feed::LiveFeed;
+use crate::mp4::MediaSample;
+use crate::send_video_command::Camera;
+
+/// Predicted frames used to build the baseline before any motion is reported.
+const WARMUP_FRAMES: u32 = 50;
+
+/// Weight of each new frame in the rolling baseline.
+const BASELINE_ALPHA: f64 = 0.02;
+
+/// How many motion events may be buffered for slow subscribers.
+const EVENTS_CAPACITY: usize = 64;
+
+/// Extra score for each intra slice in a predicted frame, with `analyzeSlices`.
+const INTRA_SLICE_BOOST: f64 = 0.5;
+
+fn default_sensitivity() -> f64 {
+    0.5
+}
+
+fn default_cooldown() -> u64 {
+    60
+}
+
+fn default_min_frames() -> u32 {
+    3
+}
+
+/// Motion detection settings of a camera.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct MotionConfig {
+    /// From 0 (only big changes) to 1 (any change). A frame counts as motion
+    /// when it is `1.25 + 3 * (1 - sensitivity)` times bigger than the baseline.
+    #[serde(default = "default_sensitivity")]
+    pub sensitivity: f64,
+
+    /// Seconds to wait after a motion event before reporting another one.
+    #[serde(default = "default_cooldown")]
+    pub cooldown: u64,
+
+    /// Consecutive frames over the threshold needed to report motion.
+    #[serde(default = "default_min_frames")]
+    pub min_frames: u32,
+
+    /// Also parses the slice headers of each frame, counting intra slices in
+    /// predicted frames (a typical sign of a sudden change) towards the score.
+    /// Only applies to H.264 cameras.
+    #[serde(default)]
+    pub analyze_slices: bool,
+}
+
+/// Motion detected on a camera. `score` is how many times bigger than usual the frames were.
+#[derive(Debug, Clone)]
+pub struct MotionEvent {
+    pub camera: String,
+    pub score: f64,
+    pub at: DateTime<Utc>,
+}
+
+/// Motion events of every camera. Subscribe with `.subscribe()`.
+pub type MotionEvents = broadcast::Sender<MotionEvent>;
+
+/// Reads an unsigned Exp-Golomb code, see ITU-T H.264 section 9.1.
+fn read_ue(bits: &[u8], bit_pos: &mut usize) -> Option<u32> {
+    let mut read_bit = || {
+        let byte = bits.get(*bit_pos / 8)?;
+        let bit = (byte >> (7 - *bit_pos % 8)) & 1;
+        *bit_pos += 1;
+        Some(u32::from(bit))
+    };
+
+    let mut leading_zeros = 0;
+    while read_bit()? == 0 {
+        leading_zeros += 1;
+        if leading_zeros > 31 {
+            return None;
+        }
+    }
+
+    let mut value = 0;
+    for _ in 0..leading_zeros {
+        value = (value << 1) | read_bit()?;
+    }
+
+    Some((1 << leading_zeros) - 1 + value)
+}
+
+/// Sizes of a frame's slices: the bytes of coded slice NAL units, and how many
+/// of them are intra (I or SI) slices. Frames are NAL units prefixed with their
+/// 4-byte length, of H.264 or, when `is_hevc`, H.265.
+#[derive(Debug, Default, PartialEq)]
+struct SliceStats {
+    slice_bytes: usize,
+    intra_slices: u32,
+}
+
+fn slice_stats(mut data: &[u8], is_hevc: bool, parse_headers: bool) -> SliceStats {
+    let mut stats = SliceStats::default();
+
+    while data.len() >= 4 {
+        let len = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        // The length may not even fit in a usize on 32-bit targets.
+        let Some(end) = len.checked_add(4) else {
+            break;
+        };
+        let Some(nal) = data.get(4..end) else {
+            break;
+        };
+        data = &data[end..];
+
+        let Some(&header) = nal.first() else {
+            continue;
+        };
+
+        // Telling H.265 intra slices apart would need the PPS, so only their size counts.
+        if is_hevc {
+            if h265::nal_unit_type(nal).is_some_and(h265::is_vcl) {
+                stats.slice_bytes += nal.len();
+            }
+            continue;
+        }
+
+        // 1: non-IDR slice, 5: IDR slice. Parameter sets and SEI don't count.
+        if !matches!(header & 0x1f, 1 | 5) {
+            continue;
+        }
+
+        stats.slice_bytes += nal.len();
+
+        if parse_headers {
+            // Strip emulation prevention bytes from the start of the slice header.
+            let mut header_bytes = Vec::with_capacity(16);
+            let mut zeros = 0;
+            for &byte in nal[1..].iter().take(16) {
+                if zeros >= 2 && byte == 3 {
+                    zeros = 0;
+                    continue;
+                }
+                zeros = if byte == 0 { zeros + 1 } else { 0 };
+                header_bytes.push(byte);
+            }
+
+            let mut bit_pos = 0;
+            let _first_mb_in_slice = read_ue(&header_bytes, &mut bit_pos);
+            if let Some(slice_type) = read_ue(&header_bytes, &mut bit_pos) {
+                // 2 and 7: I, 4 and 9: SI.
+                if matches!(slice_type % 5, 2 | 4) {
+                    stats.intra_slices += 1;
+                }
+            }
+        }
+    }
+
+    stats
+}
+
+/// Scores predicted frames of a camera against its rolling baseline.
+pub struct MotionDetector {
+    config: MotionConfig,
+    threshold: f64,
+    baseline: Option<f64>,
+    frames_seen: u32,
+    consecutive: u32,
+    last_event: Option<Instant>,
+}
+
+impl MotionDetector {
+    pub fn new(config: MotionConfig) -> Self {
+        let sensitivity = config.sensitivity.clamp(0.0, 1.0);
+
+        MotionDetector {
+            threshold: 1.25 + 3.0 * (1.0 - sensitivity),
+            config,
+            baseline: None,
+            frames_seen: 0,
+            consecutive: 0,
+            last_event: None,
+        }
+    }
+
+    /// Forgets the baseline, e.g. after reconnecting to the camera.
+    pub fn reset(&mut self) {
+        self.baseline = None;
+        self.frames_seen = 0;
+        self.consecutive = 0;
+    }
+
+    /// Feeds a video frame, returning its score when it completes a motion event.
+    pub fn feed(
+        &mut self,
+        data: &[u8],
+        is_hevc: bool,
+        is_random_access_point: bool,
+        now: Instant,
+    ) -> Option<f64> {
+        // Keyframes are always big, they say nothing about motion.
+        if is_random_access_point {
+            return None;
+        }
+
+        let stats = slice_stats(data, is_hevc, self.config.analyze_slices);
+        if stats.slice_bytes == 0 {
+            return None;
+        }
+        let size = stats.slice_bytes as f64;
+
+        let Some(baseline) = self.baseline else {
+            self.baseline = Some(size);
+            self.frames_seen = 1;
+            return None;
+        };
+
+        let score = size / baseline + INTRA_SLICE_BOOST * f64::from(stats.intra_slices);
+        let is_motion = score >= self.threshold;
+
+        // Motion frames barely move the baseline, so a long event doesn't become the norm.
+        let alpha = if is_motion {
+            BASELINE_ALPHA / 10.0
+        } else {
+            BASELINE_ALPHA
+        };
+        self.baseline = Some(baseline + alpha * (size - baseline));
+        self.frames_seen = self.frames_seen.saturating_add(1);
+
+        if self.frames_seen < WARMUP_FRAMES {
+            return None;
+        }
+
+        self.consecutive = if is_motion { self.consecutive + 1 } else { 0 };
+
+        let cooled_down = self.last_event.is_none_or(|last_event| {
+            now.duration_since(last_event) >= Duration::from_secs(self.config.cooldown)
+        });
+
+        if self.consecutive >= self.config.min_frames.max(1) && cooled_down {
+            self.last_event = Some(now);
+            self.consecutive = 0;
+            return Some(score);
+        }
+
+        None
+    }
+}
+
+/// Runs the detector over the live feed of a camera, until aborted.
+/// It gets no samples, and so sends no events, while the feed is paused for a disarmed camera.
+async fn detect(
+    camera_name: String,
+    config: MotionConfig,
+    feed: Arc<LiveFeed>,
+    events: MotionEvents,
+) {
+    let mut detector = MotionDetector::new(config);
+    let mut receiver = feed.subscribe();
+    let mut current_session = None;
+
+    loop {
+        let (session, sample) = match receiver.recv().await {
+            Ok(received) => received,
+            Err(RecvError::Lagged(skipped)) => {
+                log::debug!(
+                    "Motion detector of camera {} skipped {} samples",
+                    camera_name,
+                    skipped
+                );
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+
+        if current_session.replace(session) != Some(session) {
+            detector.reset();
+        }
+
+        let MediaSample::Video {
+            sample_entry,
+            data,
+            is_random_access_point,
+            ..
+        } = sample
+        else {
+            continue;
+        };
+
+        let is_hevc = sample_entry.is_hevc();
+        if let Some(score) = detector.feed(&data, is_hevc, is_random_access_point, Instant::now()) {
+            let event = MotionEvent {
+                camera: camera_name.clone(),
+                score,
+                at: Utc::now(),
+            };
+
+            log::info!(
+                "Motion detected on camera {} at {} (score {:.2})",
+                event.camera,
+                event.at,
+                event.score
+            );
+
+            // Nobody listening is fine.
+            let _ = events.send(event);
+        }
+    }
+}
+
+/// The channel motion events are sent to, whichever detectors are running.
+pub fn event_channel() -> MotionEvents {
+    broadcast::channel(EVENTS_CAPACITY).0
+}
+
+/// Starts a detector for `camera` on its live `feed` into `tasks`, when it has a `motion` section.
+pub fn spawn_detector(
+    camera: &Camera,
+    feed: &Arc<LiveFeed>,
+    events: &MotionEvents,
+    tasks: &mut JoinSet<()>,
+) {
+    let Some(config) = &camera.motion else {
+        return;
+    };
+
+    log::info!("Detecting motion on camera {}", camera.name);
+    tasks.spawn(detect(
+        camera.name.clone(),
+        config.clone(),
+        feed.clone(),
+        events.clone(),
+    ));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// NAL units prefixed with their 4-byte length, as in a frame.
+    fn frame(nals: &[&[u8]]) -> Vec<u8> {
+        let mut data = Vec::new();
+        for nal in nals {
+            data.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+            data.extend_from_slice(nal);
+        }
+        data
+    }
+
+    /// An H.264 predicted frame with a single `len`-byte P slice.
+    fn p_frame(len: usize) -> Vec<u8> {
+        // first_mb_in_slice 0, slice_type 0 (P).
+        let mut nal = vec![0x41, 0b1100_0000];
+        nal.resize(len, 0xff);
+        frame(&[&nal])
+    }
+
+    fn detector() -> MotionDetector {
+        MotionDetector::new(MotionConfig {
+            sensitivity: 0.5,
+            cooldown: 60,
+            min_frames: 3,
+            analyze_slices: false,
+        })
+    }
+
+    /// Feeds the detector `WARMUP_FRAMES` quiet frames of 100 bytes.
+    fn warm_up(detector: &mut MotionDetector, now: Instant) {
+        for _ in 0..WARMUP_FRAMES {
+            assert_eq!(detector.feed(&p_frame(100), false, false, now), None);
+        }
+    }
+
+    #[test]
+    fn read_ue_reads_exp_golomb_codes() {
+        // 1 | 010 | 011 | 00100 | 0001000, then padding.
+        let bits = [0b1010_0110, 0b0100_0001, 0b0000_0000];
+        let mut bit_pos = 0;
+        let values: Vec<_> = (0..5).map(|_| read_ue(&bits, &mut bit_pos)).collect();
+        assert_eq!(values, [Some(0), Some(1), Some(2), Some(3), Some(7)]);
+        assert_eq!(bit_pos, 19);
+    }
+
+    #[test]
+    fn read_ue_stops_at_the_end_of_the_data() {
+        let mut bit_pos = 0;
+        assert_eq!(read_ue(&[0b0000_0001], &mut bit_pos), None);
+
+        // More than 31 leading zeros doesn't fit in 32 bits.
+        let mut bit_pos = 0;
+        assert_eq!(read_ue(&[0, 0, 0, 0, 0xff], &mut bit_pos), None);
+    }
+
+    #[test]
+    fn slice_stats_counts_h264_slices_and_intra_slices() {
+        let sps = [0x67, 0x42, 0x00, 0x1f];
+        let p_slice = [0x41, 0b1100_0000, 0xaa];
+        // slice_type 2 (I), with an emulation prevention byte after the header.
+        let i_slice = [0x41, 0b1011_0000, 0x00, 0x00, 0x03, 0x01];
+        // slice_type 7 (I, all slices of the picture), in an IDR slice.
+        let idr_slice = [0x65, 0b1000_1000];
+        let data = frame(&[&sps, &p_slice, &i_slice, &idr_slice]);
+
+        assert_eq!(
+            slice_stats(&data, false, true),
+            SliceStats {
+                slice_bytes: 3 + 6 + 2,
+                intra_slices: 2,
+            }
+        );
+        assert_eq!(
+            slice_stats(&data, false, false),
+            SliceStats {
+                slice_bytes: 3 + 6 + 2,
+                intra_slices: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn slice_stats_counts_h265_vcl_units_and_stops_at_truncated_ones() {
+        let vps = [0x40, 0x01, 0x0c];
+        let trail_r = [0x02, 0x01, 0xaa, 0xbb];
+        let mut data = frame(&[&vps, &trail_r]);
+        // A NAL unit longer than what's left of the frame.
+        data.extend_from_slice(&[0, 0, 0, 10, 0x02, 0x01]);
+
+        assert_eq!(
+            slice_stats(&data, true, true),
+            SliceStats {
+                slice_bytes: 4,
+                intra_slices: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn keyframes_and_frames_without_slices_are_ignored() {
+        let mut detector = detector();
+        let now = Instant::now();
+
+        assert_eq!(detector.feed(&p_frame(1000), false, true, now), None);
+        assert_eq!(
+            detector.feed(&frame(&[&[0x67, 0x42]]), false, false, now),
+            None
+        );
+        assert_eq!(detector.baseline, None);
+        assert_eq!(detector.frames_seen, 0);
+    }
+
+    #[test]
+    fn motion_is_only_reported_after_warmup() {
+        let mut detector = detector();
+        let now = Instant::now();
+
+        assert_eq!(detector.feed(&p_frame(100), false, false, now), None);
+        let first_event =
+            (1..200).find(|_| detector.feed(&p_frame(1000), false, false, now).is_some());
+
+        // Warmup ends on frame 49, the first of the 3 frames over the threshold needed.
+        assert_eq!(first_event, Some(WARMUP_FRAMES + 1));
+    }
+
+    #[test]
+    fn motion_needs_min_frames_in_a_row() {
+        let mut detector = detector();
+        let now = Instant::now();
+        warm_up(&mut detector, now);
+
+        for _ in 0..5 {
+            assert_eq!(detector.feed(&p_frame(1000), false, false, now), None);
+            assert_eq!(detector.feed(&p_frame(1000), false, false, now), None);
+            assert_eq!(detector.feed(&p_frame(100), false, false, now), None);
+        }
+
+        assert_eq!(detector.feed(&p_frame(1000), false, false, now), None);
+        assert_eq!(detector.feed(&p_frame(1000), false, false, now), None);
+        let score = detector.feed(&p_frame(1000), false, false, now).unwrap();
+        assert!(score > detector.threshold, "score {}", score);
+    }
+
+    #[test]
+    fn cooldown_suppresses_events_until_it_ends() {
+        let mut detector = detector();
+        let start = Instant::now();
+        warm_up(&mut detector, start);
+
+        let events = |detector: &mut MotionDetector, frames: usize, now: Instant| {
+            (0..frames)
+                .filter(|_| detector.feed(&p_frame(1000), false, false, now).is_some())
+                .count()
+        };
+
+        assert_eq!(events(&mut detector, 3, start), 1);
+        assert_eq!(
+            events(&mut detector, 10, start + Duration::from_secs(30)),
+            0
+        );
+        // Motion went on during the cooldown, so it's reported as soon as it ends.
+        assert_eq!(events(&mut detector, 1, start + Duration::from_secs(60)), 1);
+    }
+
+    #[test]
+    fn reset_starts_a_new_warmup() {
+        let mut detector = detector();
+        let now = Instant::now();
+        warm_up(&mut detector, now);
+        detector.reset();
+
+        for _ in 0..WARMUP_FRAMES - 1 {
+            assert_eq!(detector.feed(&p_frame(1000), false, false, now), None);
+        }
+    }
+}
@@ -1,24 +1,29 @@
 use anyhow::{anyhow, bail, Context, Error};
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
 use futures::StreamExt;
 use log::{debug, error, info, warn};
 use retina::{
     client::{
-        Credentials, Demuxed, Described, InitialTimestampPolicy, PlayOptions, Session,
-        SessionGroup, SessionOptions, SetupOptions, TeardownPolicy, Transport,
+        Credentials, Demuxed, Described, InitialTimestampPolicy, PacketItem, PlayOptions, Playing,
+        Session, SessionGroup, SessionOptions, SetupOptions, TeardownPolicy, Transport,
     },
-    codec::{AudioParameters, CodecItem, ParametersRef},
+    codec::{AudioFrame, AudioParameters, CodecItem, ParametersRef},
     rtcp::PacketRef,
+    Timestamp,
 };
 
 use futures::future::Either;
-use std::future::Pending;
-use std::path::PathBuf;
+use std::future::{Future, Pending};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::sync::Arc;
 use std::{num::NonZeroU32, time::Duration};
-use tokio::time::Sleep;
-use tokio::{fs::File, time::sleep};
+use tokio::fs::{File, OpenOptions};
+use tokio::time::{sleep, Sleep};
 
-use crate::mp4_writer::Mp4Writer;
+use crate::h265;
+use crate::mp4_writer::{AudioSampleEntry, Layout, Mp4Writer, VideoSampleEntry, WriterStats};
 
 #[derive(Debug, Clone)]
 pub struct Source {
@@ -62,12 +67,195 @@ pub struct Mp4RecorderOptions {
 
     /// Path to `.mp4` file to write.
     pub(crate) output: PathBuf,
+
+    /// How long to wait for the camera to describe its session, unlimited when `None`.
+    pub(crate) connect_timeout: Option<Duration>,
+
+    /// How long a snapshot waits for a keyframe.
+    pub(crate) snapshot_timeout: Duration,
+
+    /// How recordings and segments lay out their `.mp4` files.
+    pub(crate) layout: Layout,
+
+    /// Parameter sets of H.265 cameras that only send them in the SDP.
+    pub(crate) h265_parameter_sets: Option<h265::ParameterSets>,
+}
+
+/// A video stream set up for recording.
+#[derive(Debug, Clone, Copy)]
+struct VideoStream {
+    index: usize,
+    is_hevc: bool,
+}
+
+/// A video frame of either codec, with the sample entry it is written with.
+struct VideoFrame {
+    sample_entry: VideoSampleEntry,
+    data: Bytes,
+    timestamp: Timestamp,
+    loss: u16,
+    is_random_access_point: bool,
+}
+
+impl VideoFrame {
+    async fn write_to(&self, mp4_writer: &mut Mp4Writer<File>) -> Result<(), Error> {
+        mp4_writer
+            .video(
+                &self.sample_entry,
+                &self.data,
+                self.timestamp.elapsed(),
+                self.loss,
+                self.is_random_access_point,
+            )
+            .await
+            .with_context(|| format!("Error processing video frame at {}", self.timestamp))
+    }
+}
+
+enum Frame {
+    Video(VideoFrame),
+    Audio(AudioFrame),
+}
+
+/// The frames of a playing session.
+enum Frames {
+    /// Depacketized by retina, for H.264 video and audio.
+    Demuxed {
+        session: Demuxed,
+        sample_entry: Option<VideoSampleEntry>,
+    },
+
+    /// H.265 video, which retina can't depacketize, read from the RTP packets.
+    H265 {
+        session: Pin<Box<Session<Playing>>>,
+        stream_index: usize,
+        depacketizer: Box<h265::Depacketizer>,
+    },
+}
+
+impl Frames {
+    async fn play(
+        session: Session<Described>,
+        play_options: PlayOptions,
+        video: Option<VideoStream>,
+        h265_parameter_sets: Option<&h265::ParameterSets>,
+    ) -> Result<Self, Error> {
+        let session = session.play(play_options).await?;
+
+        Ok(match video {
+            Some(VideoStream {
+                index,
+                is_hevc: true,
+            }) => Frames::H265 {
+                session: Box::pin(session),
+                stream_index: index,
+                depacketizer: Box::new(h265::Depacketizer::new(h265_parameter_sets)),
+            },
+            _ => Frames::Demuxed {
+                session: session.demuxed()?,
+                sample_entry: None,
+            },
+        })
+    }
+
+    /// The next video or audio frame, skipping anything else the session sends.
+    async fn next(&mut self) -> Result<Frame, Error> {
+        match self {
+            Frames::Demuxed {
+                session,
+                sample_entry,
+            } => loop {
+                match session.next().await.ok_or_else(|| anyhow!("EOF"))?? {
+                    CodecItem::VideoFrame(frame) => {
+                        if frame.has_new_parameters() || sample_entry.is_none() {
+                            *sample_entry = match session.streams()[frame.stream_id()].parameters()
+                            {
+                                Some(ParametersRef::Video(params)) => {
+                                    log::info!("new video params: {:?}", params);
+                                    Some(VideoSampleEntry::from_params(params)?)
+                                }
+                                _ => None,
+                            };
+                        }
+
+                        // Frames received before the parameters can't be written.
+                        let Some(sample_entry) = sample_entry.clone() else {
+                            debug!("Discarding video frame received before parameters");
+                            continue;
+                        };
+
+                        return Ok(Frame::Video(VideoFrame {
+                            sample_entry,
+                            data: Bytes::copy_from_slice(frame.data()),
+                            timestamp: frame.timestamp(),
+                            loss: frame.loss(),
+                            is_random_access_point: frame.is_random_access_point(),
+                        }));
+                    }
+                    CodecItem::AudioFrame(frame) => return Ok(Frame::Audio(frame)),
+                    CodecItem::Rtcp(rtcp) => {
+                        if let (Some(timestamp), Some(Ok(Some(sender_report)))) = (
+                            rtcp.rtp_timestamp(),
+                            rtcp.pkts().next().map(PacketRef::as_sender_report),
+                        ) {
+                            debug!(
+                                "RTP timestamp={}: Sender Report timestamp={}",
+                                timestamp,
+                                sender_report.ntp_timestamp()
+                            );
+                        }
+                    }
+                    CodecItem::MessageFrame(msg) => {
+                        debug!("Received Message Frame: {:?}", msg);
+                    }
+                    codec_item => {
+                        debug!("Received Unhandled CodecItem: {:?}", codec_item);
+                    }
+                }
+            },
+            Frames::H265 {
+                session,
+                stream_index,
+                depacketizer,
+            } => loop {
+                if let Some(access_unit) = depacketizer.pull() {
+                    return Ok(Frame::Video(VideoFrame {
+                        sample_entry: access_unit.sample_entry,
+                        data: access_unit.data,
+                        timestamp: access_unit.timestamp,
+                        loss: access_unit.loss,
+                        is_random_access_point: access_unit.is_random_access_point,
+                    }));
+                }
+
+                match session.next().await.ok_or_else(|| anyhow!("EOF"))?? {
+                    PacketItem::Rtp(packet) if packet.stream_id() == *stream_index => {
+                        depacketizer.push(
+                            packet.payload(),
+                            packet.timestamp(),
+                            packet.mark(),
+                            packet.loss(),
+                        )?;
+                    }
+                    // RTCP and the packets of other streams.
+                    _ => {}
+                }
+            },
+        }
+    }
+}
+
+/// Play options of recordings, which fail on implausible timestamp jumps.
+fn recording_play_options(options: &Mp4RecorderOptions) -> PlayOptions {
+    PlayOptions::default()
+        .initial_timestamp(options.initial_timestamp)
+        .enforce_timestamps_with_max_jump_secs(NonZeroU32::new(10).unwrap())
 }
 
-/// Copies packets from `session` to `mp4` without handling any cleanup on error.
+/// Copies frames to `mp4` without handling any cleanup on error.
 async fn copy<'a>(
     options: &'a Mp4RecorderOptions,
-    session: &'a mut Demuxed,
+    frames: &'a mut Frames,
     mp4_writer: &'a mut Mp4Writer<File>,
 ) -> Result<(), Error> {
     let sleep: Either<Sleep, Pending<()>> =
@@ -77,32 +265,14 @@ async fn copy<'a>(
 
     loop {
         tokio::select! {
-            packet = session.next() => {
-                match packet.ok_or_else(|| anyhow!("EOF"))?? {
-                    CodecItem::VideoFrame(frame) => {
-                        let stream = &session.streams()[frame.stream_id()];
-                        let start_ctx = *frame.start_ctx();
-                        mp4_writer.video(stream, frame).await.with_context(
-                            || format!("Error processing video frame starting with {start_ctx}"))?;
-                    },
-                    CodecItem::AudioFrame(frame) => {
+            frame = frames.next() => {
+                match frame? {
+                    Frame::Video(frame) => frame.write_to(mp4_writer).await?,
+                    Frame::Audio(frame) => {
                         let ctx = *frame.ctx();
                         mp4_writer.audio(frame).await.with_context(
                             || format!("Error processing audio frame, {ctx}"))?;
                     },
-                    CodecItem::Rtcp(rtcp) => {
-                        if let (Some(timestamp), Some(Ok(Some(sender_report)))) = (rtcp.rtp_timestamp(), rtcp.pkts().next().map(PacketRef::as_sender_report)) {
-                            debug!("RTP timestamp={}: Sender Report timestamp={}", timestamp, sender_report.ntp_timestamp());
-                        }
-                    },
-                    CodecItem::MessageFrame(msg) => {
-                        debug!("Received Message Frame: {:?}", msg);
-                        continue;
-                    },
-                    codec_item => {
-                        debug!("Received Unhandled CodecItem: {:?}", codec_item);
-                        continue;
-                    }
                 };
             },
             _ = &mut sleep => {
@@ -118,59 +288,52 @@ async fn copy<'a>(
 async fn write_mp4(
     options: &Mp4RecorderOptions,
     session: Session<Described>,
+    video: Option<VideoStream>,
     audio_params: Option<Box<AudioParameters>>,
-) -> Result<(), Error> {
-    let mut session = session
-        .play(
-            PlayOptions::default()
-                .initial_timestamp(options.initial_timestamp)
-                .enforce_timestamps_with_max_jump_secs(NonZeroU32::new(10).unwrap()),
-        )
-        .await?
-        .demuxed()?;
+) -> Result<WriterStats, Error> {
+    let mut frames = Frames::play(
+        session,
+        recording_play_options(options),
+        video,
+        options.h265_parameter_sets.as_ref(),
+    )
+    .await?;
 
     // Append into a filename suffixed with ".partial",
     // then try to either rename it into place if
     // it's complete or delete it otherwise.
-    const PARTIAL_SUFFIX: &str = ".partial";
+    let tmp_filename = partial_filename(&options.output);
 
-    // OsString::push doesn't put in a '/', unlike PathBuf
-    let mut tmp_filename = options.output.as_os_str().to_owned();
-    tmp_filename.push(PARTIAL_SUFFIX);
-    let tmp_filename: PathBuf = tmp_filename.into();
-
-    let output = File::create(&tmp_filename).await?;
+    let output = create_output(&tmp_filename).await?;
 
-    let mut mp4 = Mp4Writer::new(audio_params, options.allow_loss, output).await?;
-    let result = copy(options, &mut session, &mut mp4).await;
-
-    if let Err(mp4_error) = mp4.finish().await {
-        error!(".mp4 finish failed: {}", mp4_error);
+    // Fragments start on keyframes, so a recording without video is always progressive.
+    let layout = match video {
+        Some(_) => options.layout,
+        None => Layout::Progressive,
+    };
+    let mut mp4 = Mp4Writer::new(audio_params, options.allow_loss, layout, output).await?;
+    let result = copy(options, &mut frames, &mut mp4).await;
+    let stats = mp4.stats();
 
-        if let Err(rm_file_error) = tokio::fs::remove_file(&tmp_filename).await {
-            error!("and removing .mp4 failed too: {}", rm_file_error);
-        }
-    } else if let Err(mv_file_error) = tokio::fs::rename(&tmp_filename, &options.output).await {
-        error!("unable to completed .mp4 into place: {}", mv_file_error);
-    }
+    finish_partial_file(mp4.finish().await, &tmp_filename, &options.output).await;
 
     result?;
 
-    Ok(())
+    Ok(stats)
 }
 
 async fn setup_video_stream(
     session: &mut Session<Described>,
     options: &Mp4RecorderOptions,
-) -> Result<Option<usize>, Error> {
+) -> Result<Option<VideoStream>, Error> {
     let video_stream_index = if !options.no_video {
         let stream_index = session.streams().iter().position(|stream| {
             if stream.media() != "video" {
                 return false;
             }
 
-            if stream.encoding_name() == "h264" {
-                info!("Using h264 video stream");
+            if matches!(stream.encoding_name(), "h264" | "h265") {
+                info!("Using {} video stream", stream.encoding_name());
                 return true;
             }
 
@@ -192,22 +355,36 @@ async fn setup_video_stream(
         None
     };
 
-    if let Some(stream_index) = video_stream_index {
-        session
-            .setup(
-                stream_index,
-                SetupOptions::default().transport(options.transport.clone()),
-            )
-            .await?;
-    }
+    let Some(stream_index) = video_stream_index else {
+        return Ok(None);
+    };
+
+    session
+        .setup(
+            stream_index,
+            SetupOptions::default().transport(options.transport.clone()),
+        )
+        .await?;
 
-    Ok(video_stream_index)
+    Ok(Some(VideoStream {
+        index: stream_index,
+        is_hevc: session.streams()[stream_index].encoding_name() == "h265",
+    }))
 }
 
 async fn setup_audio_stream(
     session: &mut Session<Described>,
     options: &Mp4RecorderOptions,
+    video: Option<VideoStream>,
 ) -> Result<Option<(usize, Box<AudioParameters>)>, Error> {
+    // H.265 sessions are read as RTP packets, which leaves audio undepacketized.
+    if video.is_some_and(|video| video.is_hevc) {
+        info!(
+            "Ignoring audio streams (if any) because they can't be recorded along with h265 video"
+        );
+        return Ok(None);
+    }
+
     let audio_stream_tuple = if !options.no_audio {
         let audio_params = session
             .streams()
@@ -250,7 +427,10 @@ async fn setup_audio_stream(
     Ok(audio_stream_tuple)
 }
 
-pub async fn start_recording(options: Mp4RecorderOptions) -> Result<(), Error> {
+/// Describes the RTSP session of `options.source`, without setting up any stream yet.
+async fn describe_session(
+    options: &Mp4RecorderOptions,
+) -> Result<(Session<Described>, Arc<SessionGroup>), Error> {
     if matches!(options.transport, Transport::Udp(_)) && !options.allow_loss {
         warn!("Using UDP without strongly recommended `allow_loss`!");
     }
@@ -261,26 +441,75 @@ pub async fn start_recording(options: Mp4RecorderOptions) -> Result<(), Error> {
     });
 
     let session_group = Arc::new(SessionGroup::default());
-    let mut session = Session::describe(
+    let describe = Session::describe(
         options.source.url.clone(),
         SessionOptions::default()
             .creds(credentials)
             .session_group(session_group.clone())
             .user_agent("IPCameraBot_RustImpl".to_owned())
             .teardown(options.teardown),
-    )
-    .await?;
+    );
+    let session = match options.connect_timeout {
+        Some(connect_timeout) => tokio::time::timeout(connect_timeout, describe)
+            .await
+            .with_context(|| format!("Camera didn't answer within {:?}", connect_timeout))??,
+        None => describe.await?,
+    };
 
-    let video_stream_index = setup_video_stream(&mut session, &options).await?;
-    let audio_stream_index = setup_audio_stream(&mut session, &options).await?;
+    Ok((session, session_group))
+}
 
-    if video_stream_index.is_none() && audio_stream_index.is_none() {
+/// The filename `output` is written to until it is complete.
+pub fn partial_filename(output: &Path) -> PathBuf {
+    // OsString::push doesn't put in a '/', unlike PathBuf
+    let mut tmp_filename = output.as_os_str().to_owned();
+    tmp_filename.push(".partial");
+    tmp_filename.into()
+}
+
+/// Creates `path` for an [`Mp4Writer`], which reads back what it wrote to place
+/// the `moov` before the `mdat`.
+pub async fn create_output(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .await
+}
+
+/// Renames the `.partial` file into place if `finish_result` succeeded, deletes it otherwise.
+pub async fn finish_partial_file(
+    finish_result: Result<(), Error>,
+    tmp_filename: &Path,
+    output: &Path,
+) {
+    if let Err(mp4_error) = finish_result {
+        error!(".mp4 finish failed: {}", mp4_error);
+
+        if let Err(rm_file_error) = tokio::fs::remove_file(tmp_filename).await {
+            error!("and removing .mp4 failed too: {}", rm_file_error);
+        }
+    } else if let Err(mv_file_error) = tokio::fs::rename(tmp_filename, output).await {
+        error!("unable to completed .mp4 into place: {}", mv_file_error);
+    }
+}
+
+pub async fn start_recording(options: Mp4RecorderOptions) -> Result<WriterStats, Error> {
+    let (mut session, session_group) = describe_session(&options).await?;
+
+    let video_stream = setup_video_stream(&mut session, &options).await?;
+    let audio_stream_index = setup_audio_stream(&mut session, &options, video_stream).await?;
+
+    if video_stream.is_none() && audio_stream_index.is_none() {
         bail!("Exiting because no video or audio stream was selected; see info log messages above");
     }
 
     let write_result = write_mp4(
         &options,
         session,
+        video_stream,
         audio_stream_index.map(|(_index, audio_param)| audio_param),
     )
     .await;
@@ -293,3 +522,404 @@ pub async fn start_recording(options: Mp4RecorderOptions) -> Result<(), Error> {
 
     write_result
 }
+
+/// Writes the first random access video frame of `session` as a single-frame `.mp4`.
+async fn write_keyframe_mp4(
+    options: &Mp4RecorderOptions,
+    session: Session<Described>,
+    video: VideoStream,
+) -> Result<(), Error> {
+    let play_options = PlayOptions::default().initial_timestamp(options.initial_timestamp);
+    let mut frames = Frames::play(
+        session,
+        play_options,
+        Some(video),
+        options.h265_parameter_sets.as_ref(),
+    )
+    .await?;
+
+    let tmp_filename = partial_filename(&options.output);
+    let output = create_output(&tmp_filename).await?;
+    let mut mp4 = Mp4Writer::new(None, options.allow_loss, Layout::Progressive, output).await?;
+
+    let result = tokio::time::timeout(options.snapshot_timeout, async {
+        loop {
+            match frames.next().await? {
+                Frame::Video(frame) if frame.is_random_access_point => {
+                    frame.write_to(&mut mp4).await?;
+                    return Ok::<_, Error>(());
+                }
+                _ => debug!("Skipping a frame while waiting for a keyframe"),
+            }
+        }
+    })
+    .await
+    .with_context(|| format!("No keyframe received within {:?}", options.snapshot_timeout))
+    .and_then(|result| result);
+
+    match result {
+        Ok(()) => finish_partial_file(mp4.finish().await, &tmp_filename, &options.output).await,
+        Err(_) => {
+            if let Err(rm_file_error) = tokio::fs::remove_file(&tmp_filename).await {
+                error!("removing the unfinished snapshot failed: {}", rm_file_error);
+            }
+        }
+    }
+
+    result
+}
+
+/// Grabs the first random access video frame of the camera into `options.output`,
+/// as a single-frame `.mp4`. Audio is never included.
+pub async fn start_snapshot(options: Mp4RecorderOptions) -> Result<(), Error> {
+    let (mut session, session_group) = describe_session(&options).await?;
+
+    let Some(video_stream) = setup_video_stream(&mut session, &options).await? else {
+        bail!("Exiting because no video stream was selected; see info log messages above");
+    };
+
+    let write_result = write_keyframe_mp4(&options, session, video_stream).await;
+
+    if let Err(teardown_error) = session_group.await_teardown().await {
+        error!("TEARDOWN failed: {}", teardown_error);
+    }
+
+    write_result
+}
+
+/// An `.mp4` segment being written by [`start_segmented_recording`].
+struct Segment {
+    writer: Mp4Writer<File>,
+    tmp_filename: PathBuf,
+    output: PathBuf,
+
+    /// Stream time of the first frame, in seconds.
+    start_secs: f64,
+}
+
+impl Segment {
+    async fn create(
+        output: PathBuf,
+        audio_params: Option<Box<AudioParameters>>,
+        allow_loss: bool,
+        layout: Layout,
+        start_secs: f64,
+    ) -> Result<Self, Error> {
+        let tmp_filename = partial_filename(&output);
+        let file = create_output(&tmp_filename).await?;
+        let writer = Mp4Writer::new(audio_params, allow_loss, layout, file).await?;
+
+        debug!("Starting segment {}", output.display());
+
+        Ok(Segment {
+            writer,
+            tmp_filename,
+            output,
+            start_secs,
+        })
+    }
+
+    async fn finish(self) {
+        finish_partial_file(self.writer.finish().await, &self.tmp_filename, &self.output).await;
+        info!("Finished segment {}", self.output.display());
+    }
+}
+
+/// Copies packets from `session` into consecutive segments until `stop` completes,
+/// without finishing the last one.
+///
+/// A new segment is started on the first keyframe after `segment_duration`,
+/// so every segment starts with a keyframe and can be played on its own.
+async fn copy_segments(
+    options: &Mp4RecorderOptions,
+    frames: &mut Frames,
+    audio_params: &Option<Box<AudioParameters>>,
+    segment_duration: Duration,
+    segment_path: &(dyn Fn(DateTime<Utc>) -> PathBuf + Send + Sync),
+    current: &mut Option<Segment>,
+    mut stop: Pin<&mut impl Future<Output = ()>>,
+) -> Result<(), Error> {
+    loop {
+        let frame = tokio::select! {
+            frame = frames.next() => frame?,
+            _ = stop.as_mut() => {
+                info!("Stopping segmented recording");
+                return Ok(());
+            },
+        };
+
+        match frame {
+            Frame::Video(frame) => {
+                let elapsed_secs = frame.timestamp.elapsed_secs();
+
+                let segment_is_due = current.as_ref().is_none_or(|segment| {
+                    elapsed_secs - segment.start_secs >= segment_duration.as_secs_f64()
+                });
+
+                if segment_is_due && frame.is_random_access_point {
+                    if let Some(segment) = current.take() {
+                        segment.finish().await;
+                    }
+
+                    *current = Some(
+                        Segment::create(
+                            segment_path(Utc::now()),
+                            audio_params.clone(),
+                            options.allow_loss,
+                            options.layout,
+                            elapsed_secs,
+                        )
+                        .await?,
+                    );
+                }
+
+                // Frames before the first keyframe can't be decoded, so they're dropped.
+                if let Some(segment) = current.as_mut() {
+                    frame.write_to(&mut segment.writer).await?;
+                }
+            }
+            Frame::Audio(frame) => {
+                if let Some(segment) = current.as_mut() {
+                    let ctx = *frame.ctx();
+                    segment
+                        .writer
+                        .audio(frame)
+                        .await
+                        .with_context(|| format!("Error processing audio frame, {ctx}"))?;
+                }
+            }
+        }
+    }
+}
+
+/// Writes the segments, finishing the one in progress when the session fails or `stop` completes.
+async fn write_segments(
+    options: &Mp4RecorderOptions,
+    session: Session<Described>,
+    video: VideoStream,
+    audio_params: Option<Box<AudioParameters>>,
+    segment_duration: Duration,
+    segment_path: &(dyn Fn(DateTime<Utc>) -> PathBuf + Send + Sync),
+    stop: impl Future<Output = ()>,
+) -> Result<(), Error> {
+    let mut frames = Frames::play(
+        session,
+        recording_play_options(options),
+        Some(video),
+        options.h265_parameter_sets.as_ref(),
+    )
+    .await?;
+
+    tokio::pin!(stop);
+
+    let mut current = None;
+    let result = copy_segments(
+        options,
+        &mut frames,
+        &audio_params,
+        segment_duration,
+        segment_path,
+        &mut current,
+        stop,
+    )
+    .await;
+
+    if let Some(segment) = current.take() {
+        segment.finish().await;
+    }
+
+    result
+}
+
+/// Records the camera continuously into segments of about `segment_duration` each,
+/// named by `segment_path` from the wall-clock time they start at.
+///
+/// Returns `Ok` once `stop` completes, or the error the session failed with;
+/// either way the segment in progress is finished first.
+pub async fn start_segmented_recording(
+    options: Mp4RecorderOptions,
+    segment_duration: Duration,
+    segment_path: impl Fn(DateTime<Utc>) -> PathBuf + Send + Sync,
+    stop: impl Future<Output = ()>,
+) -> Result<(), Error> {
+    let (mut session, session_group) = describe_session(&options).await?;
+
+    let Some(video_stream) = setup_video_stream(&mut session, &options).await? else {
+        bail!("Segmented recording requires a video stream; see info log messages above");
+    };
+
+    let audio_params = setup_audio_stream(&mut session, &options, Some(video_stream))
+        .await?
+        .map(|(_index, audio_params)| audio_params);
+
+    let result = write_segments(
+        &options,
+        session,
+        video_stream,
+        audio_params,
+        segment_duration,
+        &segment_path,
+        stop,
+    )
+    .await;
+
+    if let Err(teardown_error) = session_group.await_teardown().await {
+        error!("TEARDOWN failed: {}", teardown_error);
+    }
+
+    result
+}
+
+/// A frame received from a camera, detached from its RTSP session so it can be
+/// buffered and written later with [`Mp4Writer::video_sample`] or [`Mp4Writer::audio_sample`].
+#[derive(Debug, Clone)]
+pub enum MediaSample {
+    Video {
+        sample_entry: VideoSampleEntry,
+        data: Bytes,
+
+        /// In the 90 kHz video timescale, since the start of the session.
+        pts: i64,
+
+        /// Seconds since the start of the stream.
+        time_secs: f64,
+        is_random_access_point: bool,
+    },
+    Audio {
+        data: Bytes,
+
+        /// In the clock rate of the audio sample entry, since the start of the session.
+        pts: i64,
+
+        /// Seconds since the start of the stream.
+        time_secs: f64,
+    },
+}
+
+impl MediaSample {
+    pub fn time_secs(&self) -> f64 {
+        match self {
+            MediaSample::Video { time_secs, .. } | MediaSample::Audio { time_secs, .. } => {
+                *time_secs
+            }
+        }
+    }
+
+    pub fn is_random_access_point(&self) -> bool {
+        matches!(
+            self,
+            MediaSample::Video {
+                is_random_access_point: true,
+                ..
+            }
+        )
+    }
+
+    pub async fn write_to(&self, mp4_writer: &mut Mp4Writer<File>) -> Result<(), Error> {
+        match self {
+            MediaSample::Video {
+                sample_entry,
+                data,
+                pts,
+                is_random_access_point,
+                ..
+            } => {
+                mp4_writer
+                    .video_sample(sample_entry, data, *pts, *is_random_access_point)
+                    .await
+            }
+            MediaSample::Audio { data, pts, .. } => mp4_writer.audio_sample(data, *pts).await,
+        }
+    }
+}
+
+/// Converts the frames of a playing session into [`MediaSample`]s.
+async fn stream_frames(
+    options: &Mp4RecorderOptions,
+    session: Session<Described>,
+    video: VideoStream,
+    audio_sample_entry: Option<AudioSampleEntry>,
+    on_start: impl FnOnce(Option<AudioSampleEntry>),
+    mut on_sample: impl FnMut(MediaSample),
+    stop: impl Future<Output = ()>,
+) -> Result<(), Error> {
+    let mut frames = Frames::play(
+        session,
+        recording_play_options(options),
+        Some(video),
+        options.h265_parameter_sets.as_ref(),
+    )
+    .await?;
+
+    on_start(audio_sample_entry);
+
+    tokio::pin!(stop);
+
+    loop {
+        let frame = tokio::select! {
+            frame = frames.next() => frame?,
+            _ = &mut stop => {
+                info!("Stopping streaming samples");
+                return Ok(());
+            },
+        };
+
+        match frame {
+            Frame::Video(frame) => {
+                on_sample(MediaSample::Video {
+                    sample_entry: frame.sample_entry,
+                    data: frame.data,
+                    pts: frame.timestamp.elapsed(),
+                    time_secs: frame.timestamp.elapsed_secs(),
+                    is_random_access_point: frame.is_random_access_point,
+                });
+            }
+            Frame::Audio(frame) => {
+                on_sample(MediaSample::Audio {
+                    data: Bytes::copy_from_slice(frame.data()),
+                    pts: frame.timestamp().elapsed(),
+                    time_secs: frame.timestamp().elapsed_secs(),
+                });
+            }
+        }
+    }
+}
+
+/// Streams frames from the camera into `on_sample` until the session fails,
+/// or returns `Ok` once `stop` completes.
+///
+/// `on_start` is called once the session is playing, with the audio sample entry
+/// of the samples to come, if any.
+pub async fn stream_samples(
+    options: &Mp4RecorderOptions,
+    on_start: impl FnOnce(Option<AudioSampleEntry>),
+    on_sample: impl FnMut(MediaSample),
+    stop: impl Future<Output = ()>,
+) -> Result<(), Error> {
+    let (mut session, session_group) = describe_session(options).await?;
+
+    let Some(video_stream) = setup_video_stream(&mut session, options).await? else {
+        bail!("Streaming samples requires a video stream; see info log messages above");
+    };
+
+    let audio_sample_entry = setup_audio_stream(&mut session, options, Some(video_stream))
+        .await?
+        .and_then(|(_index, audio_params)| AudioSampleEntry::from_params(&audio_params));
+
+    let result = stream_frames(
+        options,
+        session,
+        video_stream,
+        audio_sample_entry,
+        on_start,
+        on_sample,
+        stop,
+    )
+    .await;
+
+    if let Err(teardown_error) = session_group.await_teardown().await {
+        error!("TEARDOWN failed: {}", teardown_error);
+    }
+
+    result
+}
@@ -0,0 +1,619 @@
+//! Reads back the `.mp4` files written by [`crate::mp4_writer::Mp4Writer`].
+//!
+//! This only understands the subset of the BMFF spec the writer produces: one
+//! video and at most one audio track, sample tables in `stts`/`stsc`/`stsz`/`stco`
+//! (or `co64`) and `stss`, or in `moof` fragments after a sample-less `moov`, edit
+//! lists that only delay a track, and no composition offsets. It's enough to cut stored segments into clips without
+//! decoding anything.
+
+use anyhow::{anyhow, bail, Context, Error};
+use bytes::{Buf, Bytes};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::SeekFrom;
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::mp4_writer::{AudioSampleEntry, VideoSampleEntry};
+
+/// A sample located within the file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SampleRef {
+    pub offset: u64,
+    pub size: u32,
+
+    /// Decode time since the start of the movie, in the track timescale.
+    pub dts: i64,
+    pub is_sync: bool,
+
+    /// 1-based index into the track sample entries.
+    pub sample_description_index: u32,
+}
+
+#[derive(Debug)]
+pub struct VideoTrack {
+    pub sample_entries: Vec<VideoSampleEntry>,
+    pub timescale: u32,
+    pub samples: Vec<SampleRef>,
+}
+
+#[derive(Debug)]
+pub struct AudioTrack {
+    pub sample_entry: AudioSampleEntry,
+    pub samples: Vec<SampleRef>,
+}
+
+/// The tracks of an `.mp4` file, with the file kept open to read samples from.
+pub struct Mp4Reader {
+    file: File,
+    pub video: Option<VideoTrack>,
+    pub audio: Option<AudioTrack>,
+}
+
+/// A box header: its type, and where its payload lives.
+struct BoxHeader {
+    fourcc: [u8; 4],
+    payload_start: u64,
+    payload_end: u64,
+}
+
+/// Reads the box header at `pos`, or `None` at the end of the file.
+async fn read_box_header(
+    file: &mut File,
+    pos: u64,
+    file_len: u64,
+) -> Result<Option<BoxHeader>, Error> {
+    if pos + 8 > file_len {
+        return Ok(None);
+    }
+
+    file.seek(SeekFrom::Start(pos)).await?;
+    let size = u64::from(file.read_u32().await?);
+    let mut fourcc = [0; 4];
+    file.read_exact(&mut fourcc).await?;
+
+    let (header_len, size) = match size {
+        0 => (8, file_len - pos),
+        1 => (16, file.read_u64().await?),
+        size => (8, size),
+    };
+
+    if size < header_len || pos + size > file_len {
+        bail!(
+            "box {:?} at {} has invalid size {}",
+            fourcc_str(&fourcc),
+            pos,
+            size
+        );
+    }
+
+    Ok(Some(BoxHeader {
+        fourcc,
+        payload_start: pos + header_len,
+        payload_end: pos + size,
+    }))
+}
+
+/// Reads the payload of a box into memory.
+async fn read_payload(file: &mut File, header: &BoxHeader) -> Result<Bytes, Error> {
+    let mut payload = vec![0; usize::try_from(header.payload_end - header.payload_start)?];
+    file.seek(SeekFrom::Start(header.payload_start)).await?;
+    file.read_exact(&mut payload).await?;
+    Ok(Bytes::from(payload))
+}
+
+fn fourcc_str(fourcc: &[u8; 4]) -> String {
+    String::from_utf8_lossy(fourcc).into_owned()
+}
+
+/// Splits the children of a container box payload, in memory.
+fn child_boxes(mut payload: Bytes) -> Result<Vec<([u8; 4], Bytes)>, Error> {
+    let mut children = Vec::new();
+
+    while payload.remaining() >= 8 {
+        let size = payload.get_u32() as usize;
+        let mut fourcc = [0; 4];
+        payload.copy_to_slice(&mut fourcc);
+
+        let (header_len, size) = match size {
+            1 => (16, usize::try_from(payload.get_u64())?),
+            size => (8, size),
+        };
+        let payload_len = size
+            .checked_sub(header_len)
+            .filter(|len| *len <= payload.remaining())
+            .ok_or_else(|| anyhow!("box {:?} has invalid size {}", fourcc_str(&fourcc), size))?;
+
+        children.push((fourcc, payload.split_to(payload_len)));
+    }
+
+    Ok(children)
+}
+
+fn find_child(children: &[([u8; 4], Bytes)], fourcc: &[u8; 4]) -> Result<Bytes, Error> {
+    children
+        .iter()
+        .find(|(child_fourcc, _)| child_fourcc == fourcc)
+        .map(|(_, payload)| payload.clone())
+        .ok_or_else(|| anyhow!("missing {:?} box", fourcc_str(fourcc)))
+}
+
+/// Skips the version and flags of a full box, checking there's at least `len` more bytes.
+fn full_box_payload(mut payload: Bytes, len: usize) -> Result<Bytes, Error> {
+    if payload.remaining() < 4 + len {
+        bail!("truncated box");
+    }
+    payload.advance(4);
+    Ok(payload)
+}
+
+/// Reads an `entry_count` followed by that many entries of `entry_len` bytes.
+fn table(payload: Bytes, entry_len: usize) -> Result<(u32, Bytes), Error> {
+    let mut payload = full_box_payload(payload, 4)?;
+    let entry_count = payload.get_u32();
+
+    let len = (entry_count as usize)
+        .checked_mul(entry_len)
+        .ok_or_else(|| anyhow!("table of {} entries is too big", entry_count))?;
+    if payload.remaining() < len {
+        bail!("truncated table of {} entries", entry_count);
+    }
+
+    Ok((entry_count, payload))
+}
+
+struct SampleTables {
+    sample_entries: Vec<Bytes>,
+    samples: Vec<SampleRef>,
+}
+
+fn parse_stbl(stbl: Bytes) -> Result<SampleTables, Error> {
+    let children = child_boxes(stbl)?;
+
+    let (entry_count, stsd) = table(find_child(&children, b"stsd")?, 0)?;
+    let sample_entries = child_boxes(stsd)?
+        .into_iter()
+        .take(entry_count as usize)
+        .map(|(fourcc, payload)| {
+            let mut entry = Vec::with_capacity(8 + payload.len());
+            entry.extend_from_slice(&u32::try_from(8 + payload.len())?.to_be_bytes());
+            entry.extend_from_slice(&fourcc);
+            entry.extend_from_slice(&payload);
+            Ok(Bytes::from(entry))
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+
+    // Sample sizes.
+    let mut stsz = full_box_payload(find_child(&children, b"stsz")?, 8)?;
+    let constant_size = stsz.get_u32();
+    let sample_count = stsz.get_u32();
+    let sizes: Vec<u32> = if constant_size != 0 {
+        vec![constant_size; sample_count as usize]
+    } else {
+        let len = (sample_count as usize)
+            .checked_mul(4)
+            .ok_or_else(|| anyhow!("stsz of {} samples is too big", sample_count))?;
+        if stsz.remaining() < len {
+            bail!("truncated stsz");
+        }
+        (0..sample_count).map(|_| stsz.get_u32()).collect()
+    };
+
+    // Decode times.
+    let (stts_count, mut stts) = table(find_child(&children, b"stts")?, 8)?;
+    let mut dts = Vec::with_capacity(sizes.len());
+    let mut time = 0i64;
+    for _ in 0..stts_count {
+        let count = stts.get_u32();
+        let delta = i64::from(stts.get_u32());
+        for _ in 0..count {
+            dts.push(time);
+            time += delta;
+        }
+    }
+
+    // Chunk offsets, either 32 or 64-bit.
+    let chunk_offsets: Vec<u64> = if let Ok(stco) = find_child(&children, b"stco") {
+        let (count, mut stco) = table(stco, 4)?;
+        (0..count).map(|_| u64::from(stco.get_u32())).collect()
+    } else {
+        let (count, mut co64) = table(find_child(&children, b"co64")?, 8)?;
+        (0..count).map(|_| co64.get_u64()).collect()
+    };
+
+    // Samples to chunks: (first_chunk, samples_per_chunk, sample_description_index).
+    let (stsc_count, mut stsc) = table(find_child(&children, b"stsc")?, 12)?;
+    let stsc: Vec<(u32, u32, u32)> = (0..stsc_count)
+        .map(|_| (stsc.get_u32(), stsc.get_u32(), stsc.get_u32()))
+        .collect();
+
+    // Sync samples; every sample is a sync sample when there's no `stss`.
+    let sync_sample_nums: Option<Vec<u32>> = match find_child(&children, b"stss") {
+        Ok(stss) => {
+            let (count, mut stss) = table(stss, 4)?;
+            Some((0..count).map(|_| stss.get_u32()).collect())
+        }
+        Err(_) => None,
+    };
+
+    let mut samples = Vec::with_capacity(sizes.len());
+    for (i, &(first_chunk, samples_per_chunk, sample_description_index)) in stsc.iter().enumerate()
+    {
+        let last_chunk = match stsc.get(i + 1) {
+            Some(next) => next
+                .0
+                .checked_sub(1)
+                .ok_or_else(|| anyhow!("stsc refers to chunk 0"))?,
+            None => u32::try_from(chunk_offsets.len())?,
+        };
+
+        for chunk in first_chunk..=last_chunk {
+            let mut offset = *(chunk as usize)
+                .checked_sub(1)
+                .and_then(|index| chunk_offsets.get(index))
+                .ok_or_else(|| anyhow!("stsc refers to missing chunk {}", chunk))?;
+
+            for _ in 0..samples_per_chunk {
+                let sample_index = samples.len();
+                let (Some(&size), Some(&sample_dts)) =
+                    (sizes.get(sample_index), dts.get(sample_index))
+                else {
+                    bail!("sample tables disagree on the sample count");
+                };
+                let sample_num = u32::try_from(sample_index + 1)?;
+
+                samples.push(SampleRef {
+                    offset,
+                    size,
+                    dts: sample_dts,
+                    is_sync: sync_sample_nums
+                        .as_ref()
+                        .is_none_or(|nums| nums.binary_search(&sample_num).is_ok()),
+                    sample_description_index,
+                });
+                offset += u64::from(size);
+            }
+        }
+    }
+
+    Ok(SampleTables {
+        sample_entries,
+        samples,
+    })
+}
+
+/// The samples a `traf` adds to its track, see ISO/IEC 14496-12 section 8.8.
+///
+/// `track_ends` holds the decode time each track's previous fragment ended at,
+/// for fragments without a `tfdt`.
+fn parse_traf(
+    traf: Bytes,
+    moof_start: u64,
+    track_ends: &mut HashMap<u32, i64>,
+) -> Result<(u32, Vec<SampleRef>), Error> {
+    let children = child_boxes(traf)?;
+
+    let mut tfhd = find_child(&children, b"tfhd")?;
+    if tfhd.remaining() < 8 {
+        bail!("truncated tfhd");
+    }
+    let tfhd_flags = tfhd.get_u32() & 0xff_ffff;
+    let track_id = tfhd.get_u32();
+    let mut tfhd_field = |flag: u32, len: usize| -> Result<Option<u64>, Error> {
+        if tfhd_flags & flag == 0 {
+            return Ok(None);
+        }
+        if tfhd.remaining() < len {
+            bail!("truncated tfhd");
+        }
+        Ok(Some(if len == 8 {
+            tfhd.get_u64()
+        } else {
+            u64::from(tfhd.get_u32())
+        }))
+    };
+    let base_data_offset = tfhd_field(0x000001, 8)?.unwrap_or(moof_start);
+    let sample_description_index = tfhd_field(0x000002, 4)?.map_or(Ok(1), u32::try_from)?;
+    let default_duration = tfhd_field(0x000008, 4)?;
+    let default_size = tfhd_field(0x000010, 4)?;
+    let default_flags = tfhd_field(0x000020, 4)?.map_or(Ok(0), u32::try_from)?;
+
+    let mut dts = match find_child(&children, b"tfdt") {
+        Ok(tfdt) => {
+            let version = tfdt.first().copied();
+            let mut tfdt = full_box_payload(tfdt, if version == Some(1) { 8 } else { 4 })?;
+            if version == Some(1) {
+                i64::try_from(tfdt.get_u64())?
+            } else {
+                i64::from(tfdt.get_u32())
+            }
+        }
+        Err(_) => track_ends.get(&track_id).copied().unwrap_or(0),
+    };
+
+    let mut samples = Vec::new();
+    let mut next_offset = base_data_offset;
+    for (_, trun) in children.iter().filter(|(fourcc, _)| fourcc == b"trun") {
+        let mut trun = trun.clone();
+        if trun.remaining() < 8 {
+            bail!("truncated trun");
+        }
+        let flags = trun.get_u32() & 0xff_ffff;
+        let sample_count = trun.get_u32();
+        let header_len = 4 * [0x000001, 0x000004]
+            .iter()
+            .filter(|flag| flags & **flag != 0)
+            .count();
+        let entry_len = 4 * [0x000100, 0x000200, 0x000400, 0x000800]
+            .iter()
+            .filter(|flag| flags & **flag != 0)
+            .count();
+        let len = (sample_count as usize)
+            .checked_mul(entry_len)
+            .and_then(|entries_len| entries_len.checked_add(header_len))
+            .ok_or_else(|| anyhow!("trun of {} samples is too big", sample_count))?;
+        if trun.remaining() < len {
+            bail!("truncated trun of {} samples", sample_count);
+        }
+
+        // Each run follows the previous one unless it says where its data is.
+        let mut offset = next_offset;
+        if flags & 0x000001 != 0 {
+            offset = base_data_offset
+                .checked_add_signed(i64::from(trun.get_i32()))
+                .ok_or_else(|| anyhow!("trun data offset is out of range"))?;
+        }
+        let first_sample_flags = if flags & 0x000004 != 0 {
+            Some(trun.get_u32())
+        } else {
+            None
+        };
+
+        for i in 0..sample_count {
+            let duration = match flags & 0x000100 {
+                0 => default_duration.ok_or_else(|| anyhow!("fragment sample without duration"))?,
+                _ => u64::from(trun.get_u32()),
+            };
+            let size = match flags & 0x000200 {
+                0 => u32::try_from(
+                    default_size.ok_or_else(|| anyhow!("fragment sample without size"))?,
+                )?,
+                _ => trun.get_u32(),
+            };
+            let sample_flags = match (flags & 0x000400, first_sample_flags) {
+                (0, Some(first_sample_flags)) if i == 0 => first_sample_flags,
+                (0, _) => default_flags,
+                _ => trun.get_u32(),
+            };
+            if flags & 0x000800 != 0 {
+                trun.advance(4); // sample_composition_time_offset
+            }
+
+            samples.push(SampleRef {
+                offset,
+                size,
+                dts,
+                is_sync: sample_flags & 0x0001_0000 == 0, // sample_is_non_sync_sample
+                sample_description_index,
+            });
+            offset += u64::from(size);
+            dts += i64::try_from(duration)?;
+        }
+
+        next_offset = offset;
+    }
+
+    track_ends.insert(track_id, dts);
+    Ok((track_id, samples))
+}
+
+/// Reads the timescale of an `mvhd` or `mdhd` box.
+fn header_timescale(mut header: Bytes) -> Result<u32, Error> {
+    // version 1: version/flags, creation_time (8), modification_time (8), then timescale.
+    if header.remaining() < 24 {
+        bail!("truncated media header");
+    }
+    let version = header[0];
+    header.advance(if version == 1 { 20 } else { 12 });
+    Ok(header.get_u32())
+}
+
+/// How long after the start of the movie a track starts, in the movie timescale:
+/// the duration of an empty edit at the start of its edit list, if any.
+fn edit_delay(edts: Bytes) -> Result<u64, Error> {
+    let elst = find_child(&child_boxes(edts)?, b"elst")?;
+    let version = elst.first().copied();
+    let (entry_count, mut entries) = table(elst, if version == Some(1) { 20 } else { 12 })?;
+    if entry_count == 0 {
+        return Ok(0);
+    }
+
+    let (segment_duration, media_time) = if version == Some(1) {
+        (entries.get_u64(), entries.get_i64())
+    } else {
+        (u64::from(entries.get_u32()), i64::from(entries.get_i32()))
+    };
+
+    Ok(if media_time == -1 {
+        segment_duration
+    } else {
+        0
+    })
+}
+
+/// Width and height of a visual sample entry, see ISO/IEC 14496-12 section 12.1.3.
+fn visual_dimensions(entry: &Bytes) -> Result<(u16, u16), Error> {
+    // box header (8) + reserved (6) + data_reference_index (2) + pre_defined/reserved (16)
+    const OFFSET: usize = 32;
+
+    if entry.len() < OFFSET + 4 {
+        bail!("truncated visual sample entry");
+    }
+
+    let mut dims = entry.slice(OFFSET..OFFSET + 4);
+    Ok((dims.get_u16(), dims.get_u16()))
+}
+
+impl Mp4Reader {
+    pub async fn open(path: &Path) -> Result<Self, Error> {
+        let mut file = File::open(path)
+            .await
+            .with_context(|| format!("failed to open '{}'", path.display()))?;
+        let file_len = file.metadata().await?.len();
+
+        let mut pos = 0;
+        let moov = loop {
+            let header = read_box_header(&mut file, pos, file_len)
+                .await?
+                .ok_or_else(|| anyhow!("'{}' has no moov box", path.display()))?;
+
+            pos = header.payload_end;
+
+            if &header.fourcc == b"moov" {
+                break read_payload(&mut file, &header).await?;
+            }
+        };
+
+        let mut video = None;
+        let mut audio = None;
+        let mut video_track_id = None;
+        let mut audio_track_id = None;
+
+        let moov_children = child_boxes(moov)?;
+        let movie_timescale = header_timescale(find_child(&moov_children, b"mvhd")?)?;
+
+        for (fourcc, trak) in moov_children {
+            if &fourcc != b"trak" {
+                continue;
+            }
+
+            let trak_children = child_boxes(trak)?;
+
+            // version 1 tkhd: version/flags, creation_time (8), modification_time (8), then track_id.
+            let mut tkhd = find_child(&trak_children, b"tkhd")?;
+            if tkhd.remaining() < 24 {
+                bail!("truncated tkhd");
+            }
+            let version = tkhd[0];
+            tkhd.advance(if version == 1 { 20 } else { 12 });
+            let track_id = tkhd.get_u32();
+
+            let mdia = find_child(&trak_children, b"mdia")?;
+            let mdia_children = child_boxes(mdia)?;
+
+            let timescale = header_timescale(find_child(&mdia_children, b"mdhd")?)?;
+
+            let mut hdlr = find_child(&mdia_children, b"hdlr")?;
+            if hdlr.remaining() < 12 {
+                bail!("truncated hdlr");
+            }
+            hdlr.advance(8);
+            let mut handler = [0; 4];
+            hdlr.copy_to_slice(&mut handler);
+
+            let minf = find_child(&mdia_children, b"minf")?;
+            let stbl = find_child(&child_boxes(minf)?, b"stbl")?;
+            let mut tables = parse_stbl(stbl)?;
+
+            // Decode times are since the start of the movie, like in fragments.
+            if let Ok(edts) = find_child(&trak_children, b"edts") {
+                let delay = i64::try_from(edit_delay(edts)?)?;
+                let delay = i64::try_from(
+                    i128::from(delay) * i128::from(timescale) / i128::from(movie_timescale),
+                )?;
+                for sample in &mut tables.samples {
+                    sample.dts += delay;
+                }
+            }
+
+            match &handler {
+                b"vide" => {
+                    let sample_entries = tables
+                        .sample_entries
+                        .into_iter()
+                        .map(|data| {
+                            let (width, height) = visual_dimensions(&data)?;
+                            Ok(VideoSampleEntry {
+                                data,
+                                width,
+                                height,
+                            })
+                        })
+                        .collect::<Result<Vec<_>, Error>>()?;
+
+                    video_track_id = Some(track_id);
+                    video = Some(VideoTrack {
+                        sample_entries,
+                        timescale,
+                        samples: tables.samples,
+                    });
+                }
+                b"soun" => {
+                    let data = tables
+                        .sample_entries
+                        .into_iter()
+                        .next()
+                        .ok_or_else(|| anyhow!("audio track without sample entry"))?;
+
+                    audio_track_id = Some(track_id);
+                    audio = Some(AudioTrack {
+                        sample_entry: AudioSampleEntry {
+                            data,
+                            clock_rate: timescale,
+                        },
+                        samples: tables.samples,
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        // Fragmented files have their samples in the moof boxes after the moov.
+        // A fragment cut short, e.g. by a crash while recording, ends the file.
+        let mut track_ends = HashMap::new();
+        while let Ok(Some(header)) = read_box_header(&mut file, pos, file_len).await {
+            if &header.fourcc == b"moof" {
+                let moof = read_payload(&mut file, &header).await?;
+                let mut fragment = Vec::new();
+                for (fourcc, traf) in child_boxes(moof)? {
+                    if &fourcc == b"traf" {
+                        fragment.push(parse_traf(traf, pos, &mut track_ends)?);
+                    }
+                }
+
+                let is_complete = fragment.iter().all(|(_, samples)| {
+                    samples
+                        .iter()
+                        .all(|sample| sample.offset + u64::from(sample.size) <= file_len)
+                });
+                if !is_complete {
+                    break;
+                }
+
+                for (track_id, samples) in fragment {
+                    if Some(track_id) == video_track_id {
+                        if let Some(video) = &mut video {
+                            video.samples.extend(samples);
+                        }
+                    } else if Some(track_id) == audio_track_id {
+                        if let Some(audio) = &mut audio {
+                            audio.samples.extend(samples);
+                        }
+                    }
+                }
+            }
+
+            pos = header.payload_end;
+        }
+
+        Ok(Mp4Reader { file, video, audio })
+    }
+
+    pub async fn read_sample(&mut self, sample: &SampleRef) -> Result<Vec<u8>, Error> {
+        let mut data = vec![0; sample.size as usize];
+        self.file.seek(SeekFrom::Start(sample.offset)).await?;
+        self.file.read_exact(&mut data).await?;
+        Ok(data)
+    }
+}
@@ -4,27 +4,33 @@
 //! Proof-of-concept `.mp4` writer.
 //!
 //! This writes media data (`mdat`) to a stream, buffering parameters for a
-//! `moov` atom at the end. This avoids the need to buffer the media data
-//! (`mdat`) first or reserved a fixed size for the `moov`, but it will slow
-//! playback, particularly when serving `.mp4` files remotely.
+//! `moov` atom. This avoids the need to buffer the media data (`mdat`) first
+//! or reserved a fixed size for the `moov`. When finished, the `mdat` is moved
+//! forward a buffer at a time to place the `moov` before it ("fast start"), so
+//! players can start before downloading the whole file. Past 4 GiB, chunk
+//! offsets go in a `co64` rather than an `stco`, and the `mdat` gets a 64-bit
+//! size.
+//!
+//! With [`Layout::Fragmented`] it instead writes a `moov` without samples up
+//! front, then a `moof`/`mdat` fragment from each video keyframe on, which
+//! bounds the memory used and leaves a playable file if it never finishes.
 //!
 //! For a more high-quality implementation, see [Moonfire NVR](https://github.com/scottlamb/moonfire-nvr).
-//! It's better tested, places the `moov` atom at the start, can do HTTP range
-//! serving for arbitrary time ranges, and supports standard and fragmented
-//! `.mp4` files.
+//! It's better tested, lays out the file without moving the `mdat`, and can do
+//! HTTP range serving for arbitrary time ranges.
 //!
 //! See the BMFF spec, ISO/IEC 14496-12:2015:
 //! https://github.com/scottlamb/moonfire-nvr/wiki/Standards-and-specifications
 //! https://standards.iso.org/ittf/PubliclyAvailableStandards/c068960_ISO_IEC_14496-12_2015.zip
 
 use anyhow::{anyhow, bail, Error};
-use bytes::{Buf, BufMut, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use log::debug;
-use retina::codec::{AudioParameters, ParametersRef, VideoParameters};
+use retina::codec::{AudioParameters, VideoParameters};
 
 use std::convert::TryFrom;
 use std::io::SeekFrom;
-use tokio::io::{AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
 
 /// Writes a box length for everything appended in the supplied scope.
 macro_rules! write_box {
@@ -43,19 +49,159 @@ macro_rules! write_box {
     }};
 }
 
+/// A video sample entry (an `stsd` child box such as `avc1`), with the
+/// dimensions needed for the track header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VideoSampleEntry {
+    pub data: Bytes,
+    pub width: u16,
+    pub height: u16,
+}
+
+impl VideoSampleEntry {
+    /// An `avc1` sample entry for the H.264 stream of a session.
+    pub fn from_params(parameters: &VideoParameters) -> Result<Self, Error> {
+        let width = u16::try_from(parameters.pixel_dimensions().0)?;
+        let height = u16::try_from(parameters.pixel_dimensions().1)?;
+
+        Self::new(b"avc1", b"avcC", width, height, parameters.extra_data())
+    }
+
+    /// An `hvc1` sample entry for an H.265 stream, with the contents of its `hvcC` box.
+    pub fn hevc(width: u16, height: u16, hvcc: &[u8]) -> Result<Self, Error> {
+        Self::new(b"hvc1", b"hvcC", width, height, hvcc)
+    }
+
+    fn new(
+        fourcc: &[u8; 4],
+        config_fourcc: &[u8; 4],
+        width: u16,
+        height: u16,
+        config: &[u8],
+    ) -> Result<Self, Error> {
+        let mut buf = BytesMut::new();
+
+        // TODO: this should move to client::VideoParameters::sample_entry() or some such.
+        write_box!(&mut buf, fourcc, {
+            buf.put_u32(0);
+            buf.put_u32(1); // data_reference_index = 1
+            buf.extend_from_slice(&[0; 16]);
+            buf.put_u16(width);
+            buf.put_u16(height);
+            buf.extend_from_slice(&[
+                0x00, 0x48, 0x00, 0x00, // horizresolution
+                0x00, 0x48, 0x00, 0x00, // vertresolution
+                0x00, 0x00, 0x00, 0x00, // reserved
+                0x00, 0x01, // frame count
+                0x00, 0x00, 0x00, 0x00, // compressorname
+                0x00, 0x00, 0x00, 0x00, //
+                0x00, 0x00, 0x00, 0x00, //
+                0x00, 0x00, 0x00, 0x00, //
+                0x00, 0x00, 0x00, 0x00, //
+                0x00, 0x00, 0x00, 0x00, //
+                0x00, 0x00, 0x00, 0x00, //
+                0x00, 0x00, 0x00, 0x00, //
+                0x00, 0x18, 0xff, 0xff, // depth + pre_defined
+            ]);
+            write_box!(&mut buf, config_fourcc, {
+                buf.extend_from_slice(config);
+            });
+        });
+
+        Ok(VideoSampleEntry {
+            data: buf.freeze(),
+            width,
+            height,
+        })
+    }
+
+    /// Whether the samples are H.265 rather than H.264.
+    pub fn is_hevc(&self) -> bool {
+        matches!(self.data.get(4..8), Some(b"hvc1") | Some(b"hev1"))
+    }
+}
+
+/// An audio sample entry (an `stsd` child box such as `mp4a`), with its clock rate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AudioSampleEntry {
+    pub data: Bytes,
+    pub clock_rate: u32,
+}
+
+impl AudioSampleEntry {
+    /// Returns `None` for audio that can't be placed into a `.mp4` without transcoding.
+    pub fn from_params(parameters: &AudioParameters) -> Option<Self> {
+        Some(AudioSampleEntry {
+            data: Bytes::copy_from_slice(parameters.sample_entry()?),
+            clock_rate: parameters.clock_rate(),
+        })
+    }
+}
+
+/// What a writer wrote so far, for the recording history.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct WriterStats {
+    pub video_frames: u32,
+    pub audio_frames: u32,
+
+    /// Whether the video is H.265, in which case cameras are recorded without audio.
+    pub hevc: bool,
+}
+
+/// Timescale of the movie, and of the video track.
+const TIMESCALE: u32 = 90000;
+
+/// How much of the `mdat` is held in memory at a time while moving it after the `moov`.
+const MOVE_BUFFER_LEN: usize = 1 << 20;
+
+/// Space reserved before the media data for the `mdat` header, which is 16 bytes
+/// rather than 8 with a 64-bit size.
+const MDAT_HEADER_SPACE: u64 = 16;
+
+/// The `mdat` header for `data_len` bytes of media data, with a 64-bit `largesize`
+/// if the size doesn't fit in 32 bits. See BMFF section 4.2.
+fn mdat_header(data_len: u64) -> Vec<u8> {
+    let mut header = Vec::with_capacity(16);
+    match u32::try_from(data_len + 8) {
+        Ok(size) => {
+            header.extend_from_slice(&size.to_be_bytes());
+            header.extend_from_slice(b"mdat");
+        }
+        Err(_) => {
+            header.extend_from_slice(&1u32.to_be_bytes()); // size
+            header.extend_from_slice(b"mdat");
+            header.extend_from_slice(&(data_len + 16).to_be_bytes()); // largesize
+        }
+    }
+    header
+}
+
+/// How a writer lays out the `.mp4` file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Layout {
+    /// A single `mdat`, preceded by the sample tables in a `moov` once `finish` moves it.
+    #[default]
+    Progressive,
+
+    /// A `moov` without samples up front, then a `moof`/`mdat` fragment per video
+    /// keyframe. Only the current fragment is kept in memory, and the file stays
+    /// playable up to the last fragment written if the writer never finishes.
+    Fragmented,
+}
+
 /// Writes `.mp4` data to a sink.
 /// See module-level documentation for details.
-pub struct Mp4Writer<W: AsyncWrite + AsyncSeek + Send + Unpin> {
+pub struct Mp4Writer<W: AsyncRead + AsyncWrite + AsyncSeek + Send + Unpin> {
     /// media data box start
-    mdat_start: u32,
+    mdat_start: u64,
 
     /// media data box position
-    mdat_pos: u32,
-    video_params: Vec<VideoParameters>,
+    mdat_pos: u64,
+    video_sample_entries: Vec<VideoSampleEntry>,
 
-    /// The most recently used 1-based index within `video_params`.
+    /// The most recently used 1-based index within `video_sample_entries`.
     cur_video_params_sample_description_index: Option<u32>,
-    audio_params: Option<Box<AudioParameters>>,
+    audio_sample_entry: Option<AudioSampleEntry>,
     allow_loss: bool,
 
     /// The (1-indexed) video sample (frame) number of each sync sample (random access point).
@@ -63,13 +209,56 @@ pub struct Mp4Writer<W: AsyncWrite + AsyncSeek + Send + Unpin> {
 
     video_trak: TrakTracker,
     audio_trak: TrakTracker,
+
+    /// The buffered fragment with [`Layout::Fragmented`], in which case the track
+    /// trackers above stay empty.
+    fragments: Option<Fragments>,
     inner: W,
 }
 
+/// Converts a time in `from_timescale` units to `to_timescale` units.
+fn rescale(value: i64, from_timescale: u32, to_timescale: u32) -> i64 {
+    (i128::from(value) * i128::from(to_timescale) / i128::from(from_timescale)) as i64
+}
+
+/// Where a track is on the movie timeline, in the movie timescale.
+#[derive(Debug, Clone, Copy, Default)]
+struct Edit {
+    /// How long after the start of the movie the track starts.
+    delay: u64,
+    duration: u64,
+}
+
+impl Edit {
+    fn end(&self) -> u64 {
+        self.delay + self.duration
+    }
+
+    /// Writes an edit list playing the whole track from `delay` on, see BMFF section 8.6.6.
+    fn write_edts(&self, buf: &mut BytesMut) -> Result<(), Error> {
+        write_box!(buf, b"edts", {
+            write_box!(buf, b"elst", {
+                buf.put_u32(1 << 24); // version
+                buf.put_u32(if self.delay > 0 { 2 } else { 1 }); // entry_count
+                if self.delay > 0 {
+                    // An empty edit, for the time before the track starts.
+                    buf.put_u64(self.delay); // segment_duration
+                    buf.put_i64(-1); // media_time
+                    buf.put_u32(0x00010000); // media_rate
+                }
+                buf.put_u64(self.duration); // segment_duration
+                buf.put_i64(0); // media_time
+                buf.put_u32(0x00010000); // media_rate
+            });
+        });
+        Ok(())
+    }
+}
+
 /// A chunk: a group of samples that have consecutive byte positions and same sample description.
 struct Chunk {
     first_sample_number: u32, // 1-based index
-    byte_pos: u32,            // starting byte of first sample
+    byte_pos: u64,            // starting byte of first sample
     sample_description_index: u32,
 }
 
@@ -77,7 +266,7 @@ struct Chunk {
 #[derive(Default)]
 struct TrakTracker {
     samples: u32,
-    next_pos: Option<u32>,
+    next_pos: Option<u64>,
     chunks: Vec<Chunk>,
     sizes: Vec<u32>,
 
@@ -85,6 +274,9 @@ struct TrakTracker {
     /// This lags one sample behind calls to `add_sample` because each sample's duration
     /// is calculated using the PTS of the following sample.
     durations: Vec<(u32, u32)>,
+
+    /// The PTS of the first sample, where the track starts on the movie timeline.
+    first_pts: Option<i64>,
     last_pts: Option<i64>,
     tot_duration: u64,
 }
@@ -93,9 +285,9 @@ impl TrakTracker {
     fn add_sample(
         &mut self,
         sample_description_index: u32,
-        byte_pos: u32,
+        byte_pos: u64,
         size: u32,
-        timestamp: retina::Timestamp,
+        pts: i64,
         loss: u16,
         allow_loss: bool,
     ) -> Result<(), Error> {
@@ -117,9 +309,10 @@ impl TrakTracker {
             });
         }
         self.sizes.push(size);
-        self.next_pos = Some(byte_pos + size);
-        if let Some(last_pts) = self.last_pts.replace(timestamp.timestamp()) {
-            let duration = timestamp.timestamp().checked_sub(last_pts).unwrap();
+        self.next_pos = Some(byte_pos + u64::from(size));
+        self.first_pts.get_or_insert(pts);
+        if let Some(last_pts) = self.last_pts.replace(pts) {
+            let duration = pts.checked_sub(last_pts).unwrap();
             self.tot_duration += u64::try_from(duration).unwrap();
             let duration = u32::try_from(duration)?;
             match self.durations.last_mut() {
@@ -141,11 +334,19 @@ impl TrakTracker {
         (self.durations.len() * 8) + // stts
         (self.chunks.len() * 12) +   // stsc
         (self.sizes.len() * 4) +     // stsz
-        (self.chunks.len() * 4) // stco
+        (self.chunks.len() * 8) // stco or co64
     }
 
-    fn write_common_stbl_parts(&self, buf: &mut BytesMut) -> Result<(), Error> {
-        // TODO: add an edit list so the video and audio tracks are in sync.
+    /// Writes the sample tables, with the chunks `chunk_offset_shift` bytes past where
+    /// they were written.
+    ///
+    /// Chunk offsets are 32-bit (`stco`) unless one of them is past 4 GiB, in which
+    /// case they're all 64-bit (`co64`).
+    fn write_common_stbl_parts(
+        &self,
+        buf: &mut BytesMut,
+        chunk_offset_shift: u64,
+    ) -> Result<(), Error> {
         write_box!(buf, b"stts", {
             buf.put_u32(0);
             buf.put_u32(u32::try_from(self.durations.len())?);
@@ -180,21 +381,261 @@ impl TrakTracker {
                 buf.put_u32(*s);
             }
         });
-        write_box!(buf, b"stco", {
-            buf.put_u32(0); // version
-            buf.put_u32(u32::try_from(self.chunks.len())?); // entry_count
-            for c in &self.chunks {
-                buf.put_u32(c.byte_pos);
+        let chunk_offsets = self
+            .chunks
+            .iter()
+            .map(|c| {
+                c.byte_pos
+                    .checked_add(chunk_offset_shift)
+                    .ok_or_else(|| anyhow!("chunk offset overflow"))
+            })
+            .collect::<Result<Vec<u64>, Error>>()?;
+        // Chunks are in file order, so the last one has the largest offset.
+        match chunk_offsets.last().map(|&o| u32::try_from(o)) {
+            Some(Err(_)) => write_box!(buf, b"co64", {
+                buf.put_u32(0); // version
+                buf.put_u32(u32::try_from(chunk_offsets.len())?); // entry_count
+                for o in &chunk_offsets {
+                    buf.put_u64(*o);
+                }
+            }),
+            _ => write_box!(buf, b"stco", {
+                buf.put_u32(0); // version
+                buf.put_u32(u32::try_from(chunk_offsets.len())?); // entry_count
+                for o in &chunk_offsets {
+                    buf.put_u32(u32::try_from(*o)?);
+                }
+            }),
+        }
+        Ok(())
+    }
+}
+
+/// A sample of the fragment being buffered.
+struct FragmentSample {
+    size: u32,
+    pts: i64,
+    is_sync: bool,
+}
+
+/// The samples of one track within the fragment being buffered.
+#[derive(Default)]
+struct TrackFragment {
+    samples: Vec<FragmentSample>,
+    data: BytesMut,
+    sample_description_index: u32,
+
+    /// The PTS fragment decode times start from, the start of the movie once the
+    /// `moov` is written.
+    start_pts: Option<i64>,
+
+    /// Samples of the track so far, including the ones in fragments already written.
+    total_samples: u32,
+}
+
+impl TrackFragment {
+    fn add_sample(
+        &mut self,
+        sample_description_index: u32,
+        data: &[u8],
+        pts: i64,
+        loss: u16,
+        allow_loss: bool,
+        is_sync: bool,
+    ) -> Result<(), Error> {
+        if self.total_samples > 0 && loss > 0 && !allow_loss {
+            bail!("Lost {} RTP packets mid-stream", loss);
+        }
+        self.total_samples += 1;
+        self.start_pts.get_or_insert(pts);
+        self.sample_description_index = sample_description_index;
+        self.samples.push(FragmentSample {
+            size: u32::try_from(data.len())?,
+            pts,
+            is_sync,
+        });
+        self.data.extend_from_slice(data);
+        Ok(())
+    }
+
+    /// The duration of each sample, the last one ending at `next_pts` when known
+    /// or lasting as long as the one before it otherwise.
+    fn durations(&self, next_pts: Option<i64>) -> Result<Vec<u32>, Error> {
+        let mut durations = self
+            .samples
+            .windows(2)
+            .map(|pair| u32::try_from(pair[1].pts - pair[0].pts))
+            .collect::<Result<Vec<_>, _>>()?;
+        if let Some(last) = self.samples.last() {
+            let last_duration = match next_pts {
+                Some(next_pts) => u32::try_from(next_pts - last.pts)?,
+                None => durations.last().copied().unwrap_or(0),
+            };
+            durations.push(last_duration);
+        }
+        Ok(durations)
+    }
+
+    /// Appends a `traf` for the buffered samples, returning the position of the
+    /// `trun` data offset, which is only known once the whole `moof` is written.
+    fn write_traf(
+        &self,
+        buf: &mut BytesMut,
+        track_id: u32,
+        next_pts: Option<i64>,
+        with_sample_flags: bool,
+    ) -> Result<usize, Error> {
+        let durations = self.durations(next_pts)?;
+        // Samples arriving late from before the start of the movie are played at its start.
+        let base_media_decode_time =
+            u64::try_from((self.samples[0].pts - self.start_pts.unwrap_or(0)).max(0))?;
+        let data_offset_pos;
+        write_box!(buf, b"traf", {
+            write_box!(buf, b"tfhd", {
+                // default-base-is-moof, sample-description-index-present
+                buf.put_u32(0x020000 | 0x000002); // version, flags
+                buf.put_u32(track_id);
+                buf.put_u32(self.sample_description_index);
+            });
+            write_box!(buf, b"tfdt", {
+                buf.put_u32(1 << 24); // version
+                buf.put_u64(base_media_decode_time);
+            });
+            write_box!(buf, b"trun", {
+                // data-offset, sample-duration, sample-size and maybe sample-flags present
+                let flags = if with_sample_flags {
+                    0x000701
+                } else {
+                    0x000301
+                };
+                buf.put_u32(flags); // version, flags
+                buf.put_u32(u32::try_from(self.samples.len())?);
+                data_offset_pos = buf.len();
+                buf.put_i32(0); // data_offset, see Fragments::write_to
+                for (sample, duration) in self.samples.iter().zip(&durations) {
+                    buf.put_u32(*duration);
+                    buf.put_u32(sample.size);
+                    if with_sample_flags {
+                        buf.put_u32(if sample.is_sync {
+                            0x02000000 // sample_depends_on = 2 (I-frame)
+                        } else {
+                            0x01010000 // sample_depends_on = 1, sample_is_non_sync_sample
+                        });
+                    }
+                }
+            });
+        });
+        Ok(data_offset_pos)
+    }
+
+    fn clear(&mut self) {
+        self.samples.clear();
+        self.data.clear();
+    }
+}
+
+/// The state of a writer using [`Layout::Fragmented`].
+#[derive(Default)]
+struct Fragments {
+    video: TrackFragment,
+    audio: TrackFragment,
+
+    /// The `mfhd` sequence number of the last fragment written.
+    sequence_number: u32,
+
+    /// How many video sample entries the `moov` describes, once it's written.
+    /// Fragments can't refer to sample entries added afterwards.
+    moov_video_sample_entries: Option<usize>,
+}
+
+impl Fragments {
+    /// Starts the decode times of both tracks at whichever starts first, so they
+    /// line up without an edit list.
+    ///
+    /// `next_video_pts` is the PTS of the video sample that starts the next fragment.
+    fn align_tracks(&mut self, next_video_pts: Option<i64>, audio_clock_rate: u32) {
+        let video_start = self
+            .video
+            .samples
+            .first()
+            .map(|sample| sample.pts)
+            .or(next_video_pts);
+        let audio_start = self
+            .audio
+            .samples
+            .first()
+            .map(|sample| rescale(sample.pts, audio_clock_rate, TIMESCALE));
+        if let Some(start) = video_start.into_iter().chain(audio_start).min() {
+            self.video.start_pts = Some(start);
+            self.audio.start_pts = Some(rescale(start, TIMESCALE, audio_clock_rate));
+        }
+    }
+
+    /// Appends a `moof` and `mdat` with the buffered samples, if there are any,
+    /// and starts the next fragment.
+    ///
+    /// `next_video_pts` is the PTS of the video sample that starts the next fragment.
+    fn write_to(&mut self, buf: &mut BytesMut, next_video_pts: Option<i64>) -> Result<(), Error> {
+        if self.video.samples.is_empty() && self.audio.samples.is_empty() {
+            return Ok(());
+        }
+        self.sequence_number += 1;
+
+        // (position of the trun data offset, offset of the samples within the mdat payload)
+        let mut data_offsets = Vec::new();
+        let mut mdat_len = 0;
+        let moof_start = buf.len();
+        write_box!(buf, b"moof", {
+            write_box!(buf, b"mfhd", {
+                buf.put_u32(0); // version
+                buf.put_u32(self.sequence_number);
+            });
+            for (track_id, track, next_pts) in
+                [(1, &self.video, next_video_pts), (2, &self.audio, None)]
+            {
+                if track.samples.is_empty() {
+                    continue;
+                }
+                let data_offset_pos = track.write_traf(buf, track_id, next_pts, track_id == 1)?;
+                data_offsets.push((data_offset_pos, mdat_len));
+                mdat_len += track.data.len();
             }
         });
+
+        // With default-base-is-moof, data offsets are relative to the start of the moof.
+        let moof_len = buf.len() - moof_start;
+        for (data_offset_pos, offset) in data_offsets {
+            let data_offset = i32::try_from(moof_len + 8 + offset)?;
+            buf[data_offset_pos..data_offset_pos + 4].copy_from_slice(&data_offset.to_be_bytes());
+        }
+        buf.put_u32(u32::try_from(8 + mdat_len)?);
+        buf.extend_from_slice(b"mdat");
+        buf.extend_from_slice(&self.video.data);
+        buf.extend_from_slice(&self.audio.data);
+
+        self.video.clear();
+        self.audio.clear();
         Ok(())
     }
 }
 
-impl<W: AsyncWrite + AsyncSeek + Send + Unpin> Mp4Writer<W> {
+impl<W: AsyncRead + AsyncWrite + AsyncSeek + Send + Unpin> Mp4Writer<W> {
     pub async fn new(
         audio_params: Option<Box<AudioParameters>>,
         allow_loss: bool,
+        layout: Layout,
+        inner: W,
+    ) -> Result<Self, Error> {
+        let audio_sample_entry =
+            audio_params.and_then(|params| AudioSampleEntry::from_params(&params));
+        Self::with_audio_sample_entry(audio_sample_entry, allow_loss, layout, inner).await
+    }
+
+    /// Like [`Mp4Writer::new`], for samples that don't come from a live RTSP session.
+    pub async fn with_audio_sample_entry(
+        audio_sample_entry: Option<AudioSampleEntry>,
+        allow_loss: bool,
+        layout: Layout,
         mut inner: W,
     ) -> Result<Self, Error> {
         let mut buf = BytesMut::new();
@@ -204,25 +645,63 @@ impl<W: AsyncWrite + AsyncSeek + Send + Unpin> Mp4Writer<W> {
                 0, 0, 0, 0, // minor_version
                 b'i', b's', b'o', b'm', // compatible_brands[0]
             ]);
+            if layout == Layout::Fragmented {
+                buf.extend_from_slice(b"iso6"); // compatible_brands[1]
+            }
         });
-        buf.extend_from_slice(&b"\0\0\0\0mdat"[..]);
-        let mdat_start = u32::try_from(buf.len())?;
+        let fragments = match layout {
+            Layout::Progressive => {
+                // Room for a 64-bit mdat header, in case the mdat grows past 4 GiB.
+                buf.extend_from_slice(&b"\0\0\0\x08free"[..]);
+                buf.extend_from_slice(&mdat_header(0));
+                None
+            }
+            Layout::Fragmented => Some(Fragments::default()),
+        };
+        let mdat_start = u64::try_from(buf.len())?;
         inner.write_all(&buf).await?;
         Ok(Mp4Writer {
             inner,
-            video_params: Vec::new(),
+            video_sample_entries: Vec::new(),
             cur_video_params_sample_description_index: None,
-            audio_params,
+            audio_sample_entry,
             allow_loss,
             video_trak: TrakTracker::default(),
             audio_trak: TrakTracker::default(),
             video_sync_sample_nums: Vec::new(),
+            fragments,
             mdat_start,
             mdat_pos: mdat_start,
         })
     }
 
+    pub fn stats(&self) -> WriterStats {
+        let hevc = self
+            .video_sample_entries
+            .iter()
+            .any(VideoSampleEntry::is_hevc);
+
+        match &self.fragments {
+            Some(fragments) => WriterStats {
+                video_frames: fragments.video.total_samples,
+                audio_frames: fragments.audio.total_samples,
+                hevc,
+            },
+            None => WriterStats {
+                video_frames: self.video_trak.samples,
+                audio_frames: self.audio_trak.samples,
+                hevc,
+            },
+        }
+    }
+
     pub async fn finish(mut self) -> Result<(), Error> {
+        if self.fragments.is_some() {
+            self.write_fragment(None).await?;
+            self.inner.flush().await?;
+            return Ok(());
+        }
+
         self.video_trak.finish();
         self.audio_trak.finish();
         let mut buf = BytesMut::with_capacity(
@@ -230,13 +709,145 @@ impl<W: AsyncWrite + AsyncSeek + Send + Unpin> Mp4Writer<W> {
                 + self.audio_trak.size_estimate()
                 + 4 * self.video_sync_sample_nums.len(),
         );
-        write_box!(&mut buf, b"moov", {
-            write_box!(&mut buf, b"mvhd", {
+
+        // The moov goes where the mdat header space starts, and the mdat header
+        // takes the end of that space, the whole of it with a 64-bit size.
+        let moov_start = self.mdat_start - MDAT_HEADER_SPACE;
+        let mdat_header = mdat_header(self.mdat_pos - self.mdat_start);
+        let mdat_box_start = self.mdat_start - u64::try_from(mdat_header.len())?;
+        let unused_space = mdat_box_start - moov_start;
+
+        // Placing the moov before the mdat moves every chunk forward by about its
+        // length. Chunk offsets promoted to 64 bits lengthen it in turn, so it's
+        // laid out again until its length settles.
+        let mut moov_len: u64 = 0;
+        loop {
+            buf.clear();
+            self.write_moov(&mut buf, moov_len.saturating_sub(unused_space))?;
+            let len = u64::try_from(buf.len())?;
+            if len == moov_len {
+                break;
+            }
+            moov_len = len;
+        }
+
+        self.inner.seek(SeekFrom::Start(mdat_box_start)).await?;
+        self.inner.write_all(&mdat_header).await?;
+        self.move_forward(mdat_box_start, self.mdat_pos, moov_len - unused_space)
+            .await?;
+        self.inner.seek(SeekFrom::Start(moov_start)).await?;
+        self.inner.write_all(&buf).await?;
+        self.inner.flush().await?;
+        Ok(())
+    }
+
+    /// Moves the bytes from `start` to `end` forward by `distance`, from the end
+    /// backwards so none is overwritten before it's moved.
+    async fn move_forward(&mut self, start: u64, end: u64, distance: u64) -> Result<(), Error> {
+        let mut buf = vec![0; MOVE_BUFFER_LEN];
+        let mut pos = end;
+        while pos > start {
+            let len = usize::try_from(pos - start)
+                .unwrap_or(usize::MAX)
+                .min(MOVE_BUFFER_LEN);
+            pos -= len as u64;
+            self.inner.seek(SeekFrom::Start(pos)).await?;
+            self.inner.read_exact(&mut buf[..len]).await?;
+            self.inner.seek(SeekFrom::Start(pos + distance)).await?;
+            self.inner.write_all(&buf[..len]).await?;
+        }
+        Ok(())
+    }
+
+    /// Writes the `moov` if it isn't written yet, then the buffered samples as a fragment.
+    ///
+    /// `next_video_pts` is the PTS of the video sample that starts the next fragment.
+    async fn write_fragment(&mut self, next_video_pts: Option<i64>) -> Result<(), Error> {
+        let Some(moov_video_sample_entries) = self
+            .fragments
+            .as_ref()
+            .map(|fragments| fragments.moov_video_sample_entries)
+        else {
+            return Ok(());
+        };
+        let mut buf = BytesMut::new();
+        if moov_video_sample_entries.is_none() {
+            self.write_moov(&mut buf, 0)?;
+        }
+        let video_sample_entries = self.video_sample_entries.len();
+        let audio_clock_rate = self.audio_clock_rate();
+        if let Some(fragments) = &mut self.fragments {
+            if fragments.moov_video_sample_entries.is_none() {
+                fragments.moov_video_sample_entries = Some(video_sample_entries);
+                fragments.align_tracks(next_video_pts, audio_clock_rate);
+            }
+            fragments.write_to(&mut buf, next_video_pts)?;
+        }
+        self.inner.write_all(&buf).await?;
+        Ok(())
+    }
+
+    fn audio_clock_rate(&self) -> u32 {
+        self.audio_sample_entry
+            .as_ref()
+            .map_or(TIMESCALE, |entry| entry.clock_rate)
+    }
+
+    /// Places the video and audio tracks on the movie timeline, which starts with
+    /// whichever track starts first.
+    fn edits(&self) -> Result<(Edit, Edit), Error> {
+        let audio_clock_rate = self.audio_clock_rate();
+        let video_start = self.video_trak.first_pts;
+        let audio_start = self
+            .audio_trak
+            .first_pts
+            .map(|pts| rescale(pts, audio_clock_rate, TIMESCALE));
+        let movie_start = video_start
+            .into_iter()
+            .chain(audio_start)
+            .min()
+            .unwrap_or(0);
+        let edit = |start: Option<i64>, duration: i64| -> Result<Edit, Error> {
+            Ok(Edit {
+                delay: u64::try_from(start.map_or(0, |start| start - movie_start))?,
+                duration: u64::try_from(duration)?,
+            })
+        };
+        Ok((
+            edit(video_start, i64::try_from(self.video_trak.tot_duration)?)?,
+            edit(
+                audio_start,
+                rescale(
+                    i64::try_from(self.audio_trak.tot_duration)?,
+                    audio_clock_rate,
+                    TIMESCALE,
+                ),
+            )?,
+        ))
+    }
+
+    /// Appends the `moov`, with the chunks `chunk_offset_shift` bytes past where they
+    /// were written. With [`Layout::Fragmented`] it describes the tracks and their
+    /// sample entries, without samples.
+    fn write_moov(&self, buf: &mut BytesMut, chunk_offset_shift: u64) -> Result<(), Error> {
+        let fragmented = self.fragments.is_some();
+        let has_video = if fragmented {
+            !self.video_sample_entries.is_empty()
+        } else {
+            self.video_trak.samples > 0
+        };
+        let audio_sample_entry = match &self.audio_sample_entry {
+            Some(entry) if fragmented || self.audio_trak.samples > 0 => Some(entry),
+            _ => None,
+        };
+        let (video_edit, audio_edit) = self.edits()?;
+        write_box!(buf, b"moov", {
+            write_box!(buf, b"mvhd", {
                 buf.put_u32(1 << 24); // version
                 buf.put_u64(0); // creation_time
                 buf.put_u64(0); // modification_time
-                buf.put_u32(90000); // timescale
-                buf.put_u64(self.video_trak.tot_duration);
+                buf.put_u32(TIMESCALE);
+                buf.put_u64(video_edit.end().max(audio_edit.end())); // duration
                 buf.put_u32(0x00010000); // rate
                 buf.put_u16(0x0100); // volume
                 buf.put_u16(0); // reserved
@@ -249,24 +860,37 @@ impl<W: AsyncWrite + AsyncSeek + Send + Unpin> Mp4Writer<W> {
                 }
                 buf.put_u32(2); // next_track_id
             });
-            if self.video_trak.samples > 0 {
-                self.write_video_trak(&mut buf)?;
+            if has_video {
+                self.write_video_trak(buf, chunk_offset_shift, video_edit)?;
             }
-            if self.audio_trak.samples > 0 {
-                self.write_audio_trak(&mut buf, self.audio_params.as_ref().unwrap())?;
+            if let Some(sample_entry) = audio_sample_entry {
+                self.write_audio_trak(buf, sample_entry, chunk_offset_shift, audio_edit)?;
+            }
+            if fragmented {
+                write_box!(buf, b"mvex", {
+                    let track_ids = [(has_video, 1), (audio_sample_entry.is_some(), 2)];
+                    for (_, track_id) in track_ids.iter().filter(|(present, _)| *present) {
+                        write_box!(buf, b"trex", {
+                            buf.put_u32(0); // version
+                            buf.put_u32(*track_id);
+                            buf.put_u32(1); // default_sample_description_index
+                            buf.put_u32(0); // default_sample_duration
+                            buf.put_u32(0); // default_sample_size
+                            buf.put_u32(0); // default_sample_flags
+                        });
+                    }
+                });
             }
         });
-        self.inner.write_all(&buf).await?;
-        self.inner
-            .seek(SeekFrom::Start(u64::from(self.mdat_start - 8)))
-            .await?;
-        self.inner
-            .write_all(&(self.mdat_pos + 8 - self.mdat_start).to_be_bytes()[..])
-            .await?;
         Ok(())
     }
 
-    fn write_video_trak(&self, buf: &mut BytesMut) -> Result<(), Error> {
+    fn write_video_trak(
+        &self,
+        buf: &mut BytesMut,
+        chunk_offset_shift: u64,
+        edit: Edit,
+    ) -> Result<(), Error> {
         write_box!(buf, b"trak", {
             write_box!(buf, b"tkhd", {
                 buf.put_u32((1 << 24) | 7); // version, flags
@@ -274,7 +898,7 @@ impl<W: AsyncWrite + AsyncSeek + Send + Unpin> Mp4Writer<W> {
                 buf.put_u64(0); // modification_time
                 buf.put_u32(1); // track_id
                 buf.put_u32(0); // reserved
-                buf.put_u64(self.video_trak.tot_duration);
+                buf.put_u64(edit.end()); // duration
                 buf.put_u64(0); // reserved
                 buf.put_u16(0); // layer
                 buf.put_u16(0); // alternate_group
@@ -283,24 +907,31 @@ impl<W: AsyncWrite + AsyncSeek + Send + Unpin> Mp4Writer<W> {
                 for value in &[0x00010000, 0, 0, 0, 0x00010000, 0, 0, 0, 0x40000000] {
                     buf.put_u32(*value); // matrix
                 }
-                let dims = self.video_params.iter().fold((0, 0), |prev_dims, params| {
-                    let dimensions = params.pixel_dimensions();
-                    (
-                        std::cmp::max(prev_dims.0, dimensions.0),
-                        std::cmp::max(prev_dims.1, dimensions.1),
-                    )
-                });
-                let width = u32::from(u16::try_from(dims.0)?) << 16;
-                let height = u32::from(u16::try_from(dims.1)?) << 16;
+                let dims = self
+                    .video_sample_entries
+                    .iter()
+                    .fold((0, 0), |prev_dims, entry| {
+                        (
+                            std::cmp::max(prev_dims.0, entry.width),
+                            std::cmp::max(prev_dims.1, entry.height),
+                        )
+                    });
+                let width = u32::from(dims.0) << 16;
+                let height = u32::from(dims.1) << 16;
                 buf.put_u32(width);
                 buf.put_u32(height);
             });
+
+            // Fragments line the tracks up with their decode times instead.
+            if self.fragments.is_none() {
+                edit.write_edts(buf)?;
+            }
             write_box!(buf, b"mdia", {
                 write_box!(buf, b"mdhd", {
                     buf.put_u32(1 << 24); // version
                     buf.put_u64(0); // creation_time
                     buf.put_u64(0); // modification_time
-                    buf.put_u32(90000); // timebase
+                    buf.put_u32(TIMESCALE); // timebase
                     buf.put_u64(self.video_trak.tot_duration);
                     buf.put_u32(0x55c40000); // language=und + pre-defined
                 });
@@ -332,19 +963,24 @@ impl<W: AsyncWrite + AsyncSeek + Send + Unpin> Mp4Writer<W> {
                     write_box!(buf, b"stbl", {
                         write_box!(buf, b"stsd", {
                             buf.put_u32(0); // version
-                            buf.put_u32(u32::try_from(self.video_params.len())?); // entry_count
-                            for params in &self.video_params {
-                                self.write_video_sample_entry(buf, params)?;
-                            }
-                        });
-                        self.video_trak.write_common_stbl_parts(buf)?;
-                        write_box!(buf, b"stss", {
-                            buf.put_u32(0); // version
-                            buf.put_u32(u32::try_from(self.video_sync_sample_nums.len())?);
-                            for sample_num in &self.video_sync_sample_nums {
-                                buf.put_u32(*sample_num);
+                            buf.put_u32(u32::try_from(self.video_sample_entries.len())?); // entry_count
+                            for entry in &self.video_sample_entries {
+                                buf.extend_from_slice(&entry.data);
                             }
                         });
+                        self.video_trak
+                            .write_common_stbl_parts(buf, chunk_offset_shift)?;
+
+                        // Fragments flag their sync samples in the trun instead.
+                        if self.fragments.is_none() {
+                            write_box!(buf, b"stss", {
+                                buf.put_u32(0); // version
+                                buf.put_u32(u32::try_from(self.video_sync_sample_nums.len())?);
+                                for sample_num in &self.video_sync_sample_nums {
+                                    buf.put_u32(*sample_num);
+                                }
+                            });
+                        }
                     });
                 });
             });
@@ -355,7 +991,9 @@ impl<W: AsyncWrite + AsyncSeek + Send + Unpin> Mp4Writer<W> {
     fn write_audio_trak(
         &self,
         buf: &mut BytesMut,
-        parameters: &AudioParameters,
+        sample_entry: &AudioSampleEntry,
+        chunk_offset_shift: u64,
+        edit: Edit,
     ) -> Result<(), Error> {
         write_box!(buf, b"trak", {
             write_box!(buf, b"tkhd", {
@@ -364,7 +1002,7 @@ impl<W: AsyncWrite + AsyncSeek + Send + Unpin> Mp4Writer<W> {
                 buf.put_u64(0); // modification_time
                 buf.put_u32(2); // track_id
                 buf.put_u32(0); // reserved
-                buf.put_u64(self.audio_trak.tot_duration);
+                buf.put_u64(edit.end()); // duration
                 buf.put_u64(0); // reserved
                 buf.put_u16(0); // layer
                 buf.put_u16(0); // alternate_group
@@ -376,12 +1014,15 @@ impl<W: AsyncWrite + AsyncSeek + Send + Unpin> Mp4Writer<W> {
                 buf.put_u32(0); // width
                 buf.put_u32(0); // height
             });
+            if self.fragments.is_none() {
+                edit.write_edts(buf)?;
+            }
             write_box!(buf, b"mdia", {
                 write_box!(buf, b"mdhd", {
                     buf.put_u32(1 << 24); // version
                     buf.put_u64(0); // creation_time
                     buf.put_u64(0); // modification_time
-                    buf.put_u32(parameters.clock_rate());
+                    buf.put_u32(sample_entry.clock_rate);
                     buf.put_u64(self.audio_trak.tot_duration);
                     buf.put_u32(0x55c40000); // language=und + pre-defined
                 });
@@ -417,33 +1058,33 @@ impl<W: AsyncWrite + AsyncSeek + Send + Unpin> Mp4Writer<W> {
                         write_box!(buf, b"stsd", {
                             buf.put_u32(0); // version
                             buf.put_u32(1); // entry_count
-                            buf.extend_from_slice(
-                                parameters
-                                    .sample_entry()
-                                    .expect("all added streams have sample entries"),
-                            );
+                            buf.extend_from_slice(&sample_entry.data);
                         });
-                        self.audio_trak.write_common_stbl_parts(buf)?;
+                        self.audio_trak
+                            .write_common_stbl_parts(buf, chunk_offset_shift)?;
 
-                        // AAC requires two samples (really, each is a set of 960 or 1024 samples)
-                        // to decode accurately. See
-                        // https://developer.apple.com/library/archive/documentation/QuickTime/QTFF/QTFFAppenG/QTFFAppenG.html .
-                        write_box!(buf, b"sgpd", {
-                            // BMFF section 8.9.3: SampleGroupDescriptionBox
-                            buf.put_u32(0); // version
-                            buf.extend_from_slice(b"roll"); // grouping type
-                            buf.put_u32(1); // entry_count
-                                            // BMFF section 10.1: AudioRollRecoveryEntry
-                            buf.put_i16(-1); // roll_distance
-                        });
-                        write_box!(buf, b"sbgp", {
-                            // BMFF section 8.9.2: SampleToGroupBox
-                            buf.put_u32(0); // version
-                            buf.extend_from_slice(b"roll"); // grouping type
-                            buf.put_u32(1); // entry_count
-                            buf.put_u32(self.audio_trak.samples);
-                            buf.put_u32(1); // group_description_index
-                        });
+                        // Fragments have no samples to group here.
+                        if self.fragments.is_none() {
+                            // AAC requires two samples (really, each is a set of 960 or 1024 samples)
+                            // to decode accurately. See
+                            // https://developer.apple.com/library/archive/documentation/QuickTime/QTFF/QTFFAppenG/QTFFAppenG.html .
+                            write_box!(buf, b"sgpd", {
+                                // BMFF section 8.9.3: SampleGroupDescriptionBox
+                                buf.put_u32(0); // version
+                                buf.extend_from_slice(b"roll"); // grouping type
+                                buf.put_u32(1); // entry_count
+                                                // BMFF section 10.1: AudioRollRecoveryEntry
+                                buf.put_i16(-1); // roll_distance
+                            });
+                            write_box!(buf, b"sbgp", {
+                                // BMFF section 8.9.2: SampleToGroupBox
+                                buf.put_u32(0); // version
+                                buf.extend_from_slice(b"roll"); // grouping type
+                                buf.put_u32(1); // entry_count
+                                buf.put_u32(self.audio_trak.samples);
+                                buf.put_u32(1); // group_description_index
+                            });
+                        }
                     });
                 });
             });
@@ -451,94 +1092,136 @@ impl<W: AsyncWrite + AsyncSeek + Send + Unpin> Mp4Writer<W> {
         Ok(())
     }
 
-    fn write_video_sample_entry(
-        &self,
-        buf: &mut BytesMut,
-        parameters: &VideoParameters,
+    /// Writes a video frame received from the camera.
+    ///
+    /// `pts` is in the 90 kHz video timescale, on the same timeline as the audio's
+    /// so the tracks line up, e.g. since the start of the session.
+    pub async fn video(
+        &mut self,
+        sample_entry: &VideoSampleEntry,
+        data: &[u8],
+        pts: i64,
+        loss: u16,
+        is_random_access_point: bool,
     ) -> Result<(), Error> {
-        // TODO: this should move to client::VideoParameters::sample_entry() or some such.
-        write_box!(buf, b"avc1", {
-            buf.put_u32(0);
-            buf.put_u32(1); // data_reference_index = 1
-            buf.extend_from_slice(&[0; 16]);
-            buf.put_u16(u16::try_from(parameters.pixel_dimensions().0)?);
-            buf.put_u16(u16::try_from(parameters.pixel_dimensions().1)?);
-            buf.extend_from_slice(&[
-                0x00, 0x48, 0x00, 0x00, // horizresolution
-                0x00, 0x48, 0x00, 0x00, // vertresolution
-                0x00, 0x00, 0x00, 0x00, // reserved
-                0x00, 0x01, // frame count
-                0x00, 0x00, 0x00, 0x00, // compressorname
-                0x00, 0x00, 0x00, 0x00, //
-                0x00, 0x00, 0x00, 0x00, //
-                0x00, 0x00, 0x00, 0x00, //
-                0x00, 0x00, 0x00, 0x00, //
-                0x00, 0x00, 0x00, 0x00, //
-                0x00, 0x00, 0x00, 0x00, //
-                0x00, 0x00, 0x00, 0x00, //
-                0x00, 0x18, 0xff, 0xff, // depth + pre_defined
-            ]);
-            write_box!(buf, b"avcC", {
-                buf.extend_from_slice(parameters.extra_data());
-            });
-        });
-        Ok(())
+        debug!("{}: {}-byte video frame", pts, data.len());
+        let sample_description_index = match self.cur_video_params_sample_description_index {
+            // Most frames use the same sample entry as the previous one,
+            // no need to scan through self.video_sample_entries.
+            Some(i) if self.video_sample_entries[i as usize - 1] == *sample_entry => i,
+            _ => self.video_sample_description_index(sample_entry)?,
+        };
+        self.cur_video_params_sample_description_index = Some(sample_description_index);
+        self.write_video_sample(
+            sample_description_index,
+            data,
+            pts,
+            loss,
+            is_random_access_point,
+        )
+        .await
     }
 
-    pub async fn video(
+    /// Writes a video sample that doesn't come from a live RTSP session,
+    /// e.g. one read back from another `.mp4` file.
+    ///
+    /// `pts` is in the 90 kHz video timescale, on the same timeline as the audio's.
+    pub async fn video_sample(
         &mut self,
-        stream: &retina::client::Stream,
-        frame: retina::codec::VideoFrame,
+        sample_entry: &VideoSampleEntry,
+        data: &[u8],
+        pts: i64,
+        is_random_access_point: bool,
     ) -> Result<(), Error> {
-        debug!(
-            "{}: {}-byte video frame",
-            &frame.timestamp(),
-            frame.data().remaining(),
-        );
-        let sample_description_index = if let (Some(i), false) = (
-            self.cur_video_params_sample_description_index,
-            frame.has_new_parameters(),
-        ) {
-            // Use the most recent sample description index for most frames, without having to
-            // scan through self.video_sample_index.
-            i
+        let sample_description_index = self.video_sample_description_index(sample_entry)?;
+        self.write_video_sample(
+            sample_description_index,
+            data,
+            pts,
+            0,
+            is_random_access_point,
+        )
+        .await
+    }
+
+    /// Writes an audio sample that doesn't come from a live RTSP session.
+    ///
+    /// `pts` is in the clock rate of the writer's audio sample entry, on the same
+    /// timeline as the video's.
+    pub async fn audio_sample(&mut self, data: &[u8], pts: i64) -> Result<(), Error> {
+        self.write_audio_sample(data, pts, 0).await
+    }
+
+    /// The 1-based index of `sample_entry` within `video_sample_entries`, adding it if needed.
+    fn video_sample_description_index(
+        &mut self,
+        sample_entry: &VideoSampleEntry,
+    ) -> Result<u32, Error> {
+        let pos = self
+            .video_sample_entries
+            .iter()
+            .position(|entry| entry == sample_entry);
+        if let Some(pos) = pos {
+            Ok(u32::try_from(pos + 1)?)
         } else {
-            match stream.parameters() {
-                Some(ParametersRef::Video(params)) => {
-                    log::info!("new video params: {:?}", params);
-                    let pos = self.video_params.iter().position(|p| p == params);
-                    if let Some(pos) = pos {
-                        u32::try_from(pos + 1)?
-                    } else {
-                        self.video_params.push(params.clone());
-                        u32::try_from(self.video_params.len())?
-                    }
-                }
-                None => {
-                    debug!("Discarding video frame received before parameters");
-                    return Ok(());
+            self.video_sample_entries.push(sample_entry.clone());
+            Ok(u32::try_from(self.video_sample_entries.len())?)
+        }
+    }
+
+    async fn write_video_sample(
+        &mut self,
+        sample_description_index: u32,
+        data: &[u8],
+        pts: i64,
+        loss: u16,
+        is_random_access_point: bool,
+    ) -> Result<(), Error> {
+        if let Some(fragments) = &self.fragments {
+            // Each keyframe starts a fragment, as does a change of sample entry,
+            // which a fragment only has one of.
+            let starts_fragment = is_random_access_point
+                || (!fragments.video.samples.is_empty()
+                    && fragments.video.sample_description_index != sample_description_index);
+            if starts_fragment {
+                self.write_fragment(Some(pts)).await?;
+            }
+            if let Some(fragments) = &mut self.fragments {
+                if fragments
+                    .moov_video_sample_entries
+                    .is_some_and(|described| sample_description_index as usize > described)
+                {
+                    bail!("Video parameters changed after the fragmented moov was written");
                 }
-                _ => unreachable!(),
+                fragments.video.add_sample(
+                    sample_description_index,
+                    data,
+                    pts,
+                    loss,
+                    self.allow_loss,
+                    is_random_access_point,
+                )?;
             }
-        };
-        self.cur_video_params_sample_description_index = Some(sample_description_index);
-        let size = u32::try_from(frame.data().remaining())?;
+            return Ok(());
+        }
+
+        let size = u32::try_from(data.len())?;
         self.video_trak.add_sample(
             sample_description_index,
             self.mdat_pos,
             size,
-            frame.timestamp(),
-            frame.loss(),
+            pts,
+            loss,
             self.allow_loss,
         )?;
         self.mdat_pos = self
             .mdat_pos
-            .checked_add(size)
+            .checked_add(u64::from(size))
             .ok_or_else(|| anyhow!("mdat_pos overflow"))?;
-        if frame.is_random_access_point() {
+        if is_random_access_point {
             self.video_sync_sample_nums.push(self.video_trak.samples);
         }
-        self.inner.write_all(frame.data()).await?;
+        self.inner.write_all(data).await?;
         Ok(())
     }
 
@@ -548,20 +1231,626 @@ impl<W: AsyncWrite + AsyncSeek + Send + Unpin> Mp4Writer<W> {
             frame.timestamp(),
             frame.data().remaining()
         );
-        let size = u32::try_from(frame.data().remaining())?;
+        self.write_audio_sample(frame.data(), frame.timestamp().elapsed(), frame.loss())
+            .await
+    }
+
+    async fn write_audio_sample(&mut self, data: &[u8], pts: i64, loss: u16) -> Result<(), Error> {
+        if let Some(fragments) = &mut self.fragments {
+            // Audio goes into the fragment of the video it plays along with.
+            return fragments
+                .audio
+                .add_sample(1, data, pts, loss, self.allow_loss, true);
+        }
+
+        let size = u32::try_from(data.len())?;
         self.audio_trak.add_sample(
             /* sample_description_index */ 1,
             self.mdat_pos,
             size,
-            frame.timestamp(),
-            frame.loss(),
+            pts,
+            loss,
             self.allow_loss,
         )?;
         self.mdat_pos = self
             .mdat_pos
-            .checked_add(size)
+            .checked_add(u64::from(size))
             .ok_or_else(|| anyhow!("mdat_pos overflow"))?;
-        self.inner.write_all(frame.data()).await?;
+        self.inner.write_all(data).await?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mp4_reader::Mp4Reader;
+    use std::io::Cursor;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::ReadBuf;
+
+    type Boxes<'a> = Vec<([u8; 4], &'a [u8])>;
+
+    /// Splits `data` into its boxes, as (fourcc, payload) pairs.
+    fn boxes(mut data: &[u8]) -> Boxes<'_> {
+        let mut boxes = Vec::new();
+        while data.len() >= 8 {
+            let size = u32::from_be_bytes(data[..4].try_into().unwrap()) as usize;
+            let fourcc = data[4..8].try_into().unwrap();
+            boxes.push((fourcc, &data[8..size]));
+            data = &data[size..];
+        }
+        boxes
+    }
+
+    fn fourccs(boxes: &Boxes) -> Vec<String> {
+        boxes
+            .iter()
+            .map(|(fourcc, _)| String::from_utf8_lossy(fourcc).into_owned())
+            .collect()
+    }
+
+    /// The payload of the first `fourcc` box, following `path` down from `data`.
+    fn find<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> &'a [u8] {
+        path.iter().fold(data, |data, fourcc| {
+            boxes(data)
+                .into_iter()
+                .find(|(child_fourcc, _)| child_fourcc == *fourcc)
+                .map(|(_, payload)| payload)
+                .unwrap_or_else(|| panic!("missing {:?}", String::from_utf8_lossy(*fourcc)))
+        })
+    }
+
+    fn u32_at(data: &[u8], pos: usize) -> u32 {
+        u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap())
+    }
+
+    fn u64_at(data: &[u8], pos: usize) -> u64 {
+        u64::from_be_bytes(data[pos..pos + 8].try_into().unwrap())
+    }
+
+    /// The payloads of the `trak` boxes in the `moov`.
+    fn traks(data: &[u8]) -> Vec<&[u8]> {
+        boxes(find(data, &[b"moov"]))
+            .into_iter()
+            .filter(|(fourcc, _)| fourcc == b"trak")
+            .map(|(_, payload)| payload)
+            .collect()
+    }
+
+    fn video_sample_entry(width: u16) -> VideoSampleEntry {
+        VideoSampleEntry::hevc(width, 720, &[1, 2, 3, 4]).unwrap()
+    }
+
+    fn audio_sample_entry() -> AudioSampleEntry {
+        AudioSampleEntry {
+            data: Bytes::from_static(b"\0\0\0\x08mp4a"),
+            clock_rate: 8000,
+        }
+    }
+
+    /// Writes `gops` groups of three frames, each followed by an audio frame.
+    /// Video frame `i` is 100 bytes of `i`, starting at PTS `i * 3000`.
+    async fn write_gops<W: AsyncRead + AsyncWrite + AsyncSeek + Send + Unpin>(
+        writer: &mut Mp4Writer<W>,
+        gops: usize,
+    ) {
+        let sample_entry = video_sample_entry(1280);
+        for i in 0..gops * 3 {
+            writer
+                .video(
+                    &sample_entry,
+                    &[i as u8; 100],
+                    i as i64 * 3000,
+                    0,
+                    i % 3 == 0,
+                )
+                .await
+                .unwrap();
+            writer.audio_sample(&[0; 10], i as i64 * 267).await.unwrap();
+        }
+    }
+
+    /// Writes 6 video frames 3000 ticks apart from `video_start`, and 6 audio frames
+    /// of 400 ticks at 8 kHz (4500 ticks at 90 kHz) from `audio_start`, in time order.
+    async fn write_offset_tracks(layout: Layout, video_start: i64, audio_start: i64) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        let mut writer =
+            Mp4Writer::with_audio_sample_entry(Some(audio_sample_entry()), false, layout, &mut out)
+                .await
+                .unwrap();
+        let sample_entry = video_sample_entry(1280);
+
+        let mut frames: Vec<(i64, bool, i64)> = (0..6)
+            .map(|i| (video_start + i * 3000, true, video_start + i * 3000))
+            .chain((0..6).map(|i| {
+                let pts = audio_start + i * 400;
+                (rescale(pts, 8000, TIMESCALE), false, pts)
+            }))
+            .collect();
+        frames.sort();
+
+        for (i, (_, is_video, pts)) in frames.into_iter().enumerate() {
+            if is_video {
+                let is_keyframe = (pts - video_start) % 9000 == 0;
+                writer
+                    .video(&sample_entry, &[i as u8; 100], pts, 0, is_keyframe)
+                    .await
+                    .unwrap();
+            } else {
+                writer.audio_sample(&[i as u8; 10], pts).await.unwrap();
+            }
+        }
+        writer.finish().await.unwrap();
+        out.into_inner()
+    }
+
+    async fn write_fragmented(gops: usize, finish: bool) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = Mp4Writer::with_audio_sample_entry(
+            Some(audio_sample_entry()),
+            false,
+            Layout::Fragmented,
+            &mut out,
+        )
+        .await
+        .unwrap();
+        write_gops(&mut writer, gops).await;
+        assert_eq!(
+            writer.stats(),
+            WriterStats {
+                video_frames: 3 * gops as u32,
+                audio_frames: 3 * gops as u32,
+                hevc: true,
+            }
+        );
+        if finish {
+            writer.finish().await.unwrap();
+        } else {
+            drop(writer);
+        }
+        out.into_inner()
+    }
+
+    async fn read_back(data: &[u8], name: &str) -> Mp4Reader {
+        let path =
+            std::env::temp_dir().join(format!("ipcamera_bot_{}_{}.mp4", name, std::process::id()));
+        std::fs::write(&path, data).unwrap();
+        let reader = Mp4Reader::open(&path).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        reader
+    }
+
+    /// A sink that reads back zeros wherever nothing was written, keeping only the
+    /// written extents, so a file can go past 4 GiB without holding that much data.
+    #[derive(Default)]
+    struct SparseSink {
+        pos: u64,
+        len: u64,
+
+        /// Written extents as (position, data); later ones win where they overlap.
+        extents: Vec<(u64, Vec<u8>)>,
+    }
+
+    static ZEROS: [u8; MOVE_BUFFER_LEN] = [0; MOVE_BUFFER_LEN];
+
+    impl SparseSink {
+        fn read_at(&self, pos: u64, dst: &mut [u8]) {
+            for chunk in dst.chunks_mut(MOVE_BUFFER_LEN) {
+                chunk.copy_from_slice(&ZEROS[..chunk.len()]);
+            }
+            let end = pos + dst.len() as u64;
+            for (start, data) in &self.extents {
+                let from = pos.max(*start);
+                let to = end.min(start + data.len() as u64);
+                if from < to {
+                    dst[(from - pos) as usize..(to - pos) as usize]
+                        .copy_from_slice(&data[(from - start) as usize..(to - start) as usize]);
+                }
+            }
+        }
+
+        fn write_at(&mut self, pos: u64, data: &[u8]) {
+            let end = pos + data.len() as u64;
+            let is_zero = data
+                .chunks(MOVE_BUFFER_LEN)
+                .all(|chunk| chunk == &ZEROS[..chunk.len()]);
+            let overlaps = self
+                .extents
+                .iter()
+                .any(|(start, extent)| *start < end && pos < start + extent.len() as u64);
+            if !is_zero || overlaps {
+                self.extents.push((pos, data.to_vec()));
+            }
+            self.len = self.len.max(end);
+        }
+    }
+
+    impl AsyncRead for SparseSink {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            let this = self.get_mut();
+            let len = usize::try_from(this.len.saturating_sub(this.pos))
+                .unwrap_or(usize::MAX)
+                .min(buf.remaining());
+            this.read_at(this.pos, buf.initialize_unfilled_to(len));
+            buf.advance(len);
+            this.pos += len as u64;
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for SparseSink {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            data: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            let this = self.get_mut();
+            this.write_at(this.pos, data);
+            this.pos += data.len() as u64;
+            Poll::Ready(Ok(data.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncSeek for SparseSink {
+        fn start_seek(self: Pin<&mut Self>, position: SeekFrom) -> std::io::Result<()> {
+            let this = self.get_mut();
+            this.pos = match position {
+                SeekFrom::Start(pos) => pos,
+                SeekFrom::Current(offset) => this.pos.checked_add_signed(offset).unwrap(),
+                SeekFrom::End(offset) => this.len.checked_add_signed(offset).unwrap(),
+            };
+            Ok(())
+        }
+
+        fn poll_complete(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<std::io::Result<u64>> {
+            Poll::Ready(Ok(self.pos))
+        }
+    }
+
+    impl<W: AsyncRead + AsyncWrite + AsyncSeek + Send + Unpin> Mp4Writer<W> {
+        /// Leaves `len` bytes of the mdat unwritten, as if a sample no track refers
+        /// to was written there.
+        async fn skip_mdat(&mut self, len: u64) {
+            self.mdat_pos += len;
+            self.inner
+                .seek(SeekFrom::Start(self.mdat_pos))
+                .await
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn progressive_layout_moves_moov_before_mdat() {
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = Mp4Writer::with_audio_sample_entry(
+            Some(audio_sample_entry()),
+            false,
+            Layout::Progressive,
+            &mut out,
+        )
+        .await
+        .unwrap();
+        write_gops(&mut writer, 2).await;
+        writer.finish().await.unwrap();
+        let data = out.into_inner();
+
+        let top = boxes(&data);
+        assert_eq!(fourccs(&top), ["ftyp", "moov", "mdat"]);
+        assert_eq!(top[2].1.len(), 660);
+        let stss = find(
+            &data,
+            &[b"moov", b"trak", b"mdia", b"minf", b"stbl", b"stss"],
+        );
+        assert_eq!(u32_at(stss, 4), 2); // entry_count
+
+        // The chunk offsets point into the moved mdat.
+        let mut reader = read_back(&data, "progressive_layout").await;
+        let video_samples = reader.video.as_ref().unwrap().samples.clone();
+        assert_eq!(video_samples.len(), 6);
+        for (i, sample) in video_samples.iter().enumerate() {
+            assert_eq!(
+                reader.read_sample(sample).await.unwrap(),
+                vec![i as u8; 100]
+            );
+        }
+        assert_eq!(reader.audio.unwrap().samples.len(), 6);
+    }
+
+    #[tokio::test]
+    async fn progressive_layout_moves_mdat_larger_than_the_buffer() {
+        let mut out = Cursor::new(Vec::new());
+        let mut writer =
+            Mp4Writer::with_audio_sample_entry(None, false, Layout::Progressive, &mut out)
+                .await
+                .unwrap();
+        let sample_entry = video_sample_entry(1280);
+        let frame_len = MOVE_BUFFER_LEN * 2 / 3;
+        for i in 0..4 {
+            writer
+                .video(
+                    &sample_entry,
+                    &vec![i as u8; frame_len],
+                    i * 3000,
+                    0,
+                    i == 0,
+                )
+                .await
+                .unwrap();
+        }
+        writer.finish().await.unwrap();
+        let data = out.into_inner();
+
+        assert_eq!(fourccs(&boxes(&data)), ["ftyp", "moov", "mdat"]);
+        let mut reader = read_back(&data, "progressive_layout_large").await;
+        let video_samples = reader.video.as_ref().unwrap().samples.clone();
+        for (i, sample) in video_samples.iter().enumerate() {
+            assert_eq!(
+                reader.read_sample(sample).await.unwrap(),
+                vec![i as u8; frame_len]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn edit_list_delays_video_starting_after_audio() {
+        let data = write_offset_tracks(Layout::Progressive, 9000, 0).await;
+
+        let traks = traks(&data);
+        let video_elst = find(traks[0], &[b"edts", b"elst"]);
+        assert_eq!(u32_at(video_elst, 4), 2); // entry_count
+        assert_eq!(u64_at(video_elst, 8), 9000); // segment_duration of the empty edit
+        assert_eq!(u64_at(video_elst, 16), u64::MAX); // media_time = -1
+        assert_eq!(u64_at(video_elst, 28), 15000); // segment_duration of the video
+        assert_eq!(u64_at(video_elst, 36), 0); // media_time
+
+        let audio_elst = find(traks[1], &[b"edts", b"elst"]);
+        assert_eq!(u32_at(audio_elst, 4), 1); // entry_count
+        assert_eq!(u64_at(audio_elst, 16), 0); // media_time
+
+        // mvhd duration: the video ends last, at 9000 + 5 * 3000.
+        assert_eq!(u64_at(find(&data, &[b"moov", b"mvhd"]), 24), 24000);
+
+        let reader = read_back(&data, "edit_list_video_after_audio").await;
+        assert_eq!(reader.video.unwrap().samples[0].dts, 9000);
+        assert_eq!(reader.audio.unwrap().samples[0].dts, 0);
+    }
+
+    #[tokio::test]
+    async fn edit_list_delays_audio_starting_after_video() {
+        let data = write_offset_tracks(Layout::Progressive, 0, 400).await;
+
+        let traks = traks(&data);
+        assert_eq!(u32_at(find(traks[0], &[b"edts", b"elst"]), 4), 1); // entry_count
+        let audio_elst = find(traks[1], &[b"edts", b"elst"]);
+        assert_eq!(u32_at(audio_elst, 4), 2); // entry_count
+        assert_eq!(u64_at(audio_elst, 8), 4500); // segment_duration, in the movie timescale
+
+        let reader = read_back(&data, "edit_list_audio_after_video").await;
+        assert_eq!(reader.video.unwrap().samples[0].dts, 0);
+        assert_eq!(reader.audio.unwrap().samples[0].dts, 400);
+    }
+
+    #[tokio::test]
+    async fn fragmented_layout_lines_tracks_up_with_decode_times() {
+        let data = write_offset_tracks(Layout::Fragmented, 9000, 0).await;
+
+        let traks = traks(&data);
+        assert!(boxes(traks[0]).iter().all(|(fourcc, _)| fourcc != b"edts"));
+
+        let reader = read_back(&data, "fragmented_offset_tracks").await;
+        let video_dts: Vec<i64> = reader
+            .video
+            .unwrap()
+            .samples
+            .iter()
+            .map(|s| s.dts)
+            .collect();
+        let audio_dts: Vec<i64> = reader
+            .audio
+            .unwrap()
+            .samples
+            .iter()
+            .map(|s| s.dts)
+            .collect();
+        assert_eq!(video_dts, [9000, 12000, 15000, 18000, 21000, 24000]);
+        assert_eq!(audio_dts, [0, 400, 800, 1200, 1600, 2000]);
+    }
+
+    #[tokio::test]
+    async fn fragmented_layout_writes_moov_first_and_a_fragment_per_keyframe() {
+        let data = write_fragmented(2, true).await;
+
+        let top = boxes(&data);
+        assert_eq!(
+            fourccs(&top),
+            ["ftyp", "moov", "moof", "mdat", "moof", "mdat"]
+        );
+        assert_eq!(&top[0].1[8..], b"isomiso6"); // compatible_brands
+
+        let moov = top[1].1;
+        assert_eq!(fourccs(&boxes(moov)), ["mvhd", "trak", "trak", "mvex"]);
+        assert_eq!(fourccs(&boxes(find(moov, &[b"mvex"]))), ["trex", "trex"]);
+        let stbl = find(moov, &[b"trak", b"mdia", b"minf", b"stbl"]);
+        assert_eq!(
+            fourccs(&boxes(stbl)),
+            ["stsd", "stts", "stsc", "stsz", "stco"]
+        );
+        assert_eq!(u32_at(find(stbl, &[b"stsz"]), 8), 0); // sample_count
+
+        for (sequence_number, moof) in [(1, top[2].1), (2, top[4].1)] {
+            assert_eq!(
+                fourccs(&boxes(moof)),
+                ["mfhd", "traf", "traf"],
+                "fragment {}",
+                sequence_number
+            );
+            assert_eq!(u32_at(find(moof, &[b"mfhd"]), 4), sequence_number);
+
+            let trafs: Vec<_> = boxes(moof)
+                .into_iter()
+                .filter(|(fourcc, _)| fourcc == b"traf")
+                .collect();
+            let video_trun = find(trafs[0].1, &[b"trun"]);
+            assert_eq!(u32_at(find(trafs[0].1, &[b"tfhd"]), 4), 1); // track_id
+            assert_eq!(u32_at(video_trun, 4), 3); // sample_count
+            assert_eq!(u32_at(video_trun, 12), 3000); // sample_duration
+            assert_eq!(u32_at(video_trun, 16), 100); // sample_size
+            assert_eq!(u32_at(video_trun, 20), 0x02000000); // sample_flags of the keyframe
+            assert_eq!(u32_at(video_trun, 32), 0x01010000); // sample_flags of the next frame
+
+            assert_eq!(u32_at(find(trafs[1].1, &[b"tfhd"]), 4), 2); // track_id
+            assert_eq!(u32_at(find(trafs[1].1, &[b"trun"]), 4), 3); // sample_count
+        }
+
+        // 3 video frames of 100 bytes and 3 audio frames of 10 bytes each.
+        assert_eq!(top[3].1.len(), 330);
+    }
+
+    #[tokio::test]
+    async fn fragmented_file_reads_back() {
+        let data = write_fragmented(2, true).await;
+        let mut reader = read_back(&data, "fragmented_file_reads_back").await;
+
+        let video_samples = reader.video.as_ref().unwrap().samples.clone();
+        assert_eq!(video_samples.len(), 6);
+        for (i, sample) in video_samples.iter().enumerate() {
+            assert_eq!(sample.dts, i as i64 * 3000);
+            assert_eq!(sample.is_sync, i % 3 == 0);
+            assert_eq!(
+                reader.read_sample(sample).await.unwrap(),
+                vec![i as u8; 100]
+            );
+        }
+
+        let audio_samples = &reader.audio.as_ref().unwrap().samples;
+        assert_eq!(audio_samples.len(), 6);
+        assert_eq!(audio_samples[3].dts, 3 * 267);
+    }
+
+    #[tokio::test]
+    async fn unfinished_fragmented_file_keeps_its_complete_fragments() {
+        let data = write_fragmented(2, false).await;
+
+        assert_eq!(fourccs(&boxes(&data)), ["ftyp", "moov", "moof", "mdat"]);
+
+        let reader = read_back(&data, "unfinished_fragmented_file").await;
+        assert_eq!(reader.video.unwrap().samples.len(), 3);
+        assert_eq!(reader.audio.unwrap().samples.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn fragmented_layout_rejects_new_parameters_after_moov() {
+        let mut out = Cursor::new(Vec::new());
+        let mut writer = Mp4Writer::with_audio_sample_entry(
+            Some(audio_sample_entry()),
+            false,
+            Layout::Fragmented,
+            &mut out,
+        )
+        .await
+        .unwrap();
+        write_gops(&mut writer, 1).await;
+
+        let result = writer
+            .video(&video_sample_entry(1920), &[0; 100], 9000, 0, true)
+            .await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn mdat_header_switches_to_largesize_past_4_gib() {
+        assert_eq!(mdat_header(100), b"\0\0\0\x6cmdat");
+
+        let data_len = u64::from(u32::MAX);
+        let header = mdat_header(data_len);
+        assert_eq!(&header[..8], b"\0\0\0\x01mdat");
+        assert_eq!(u64_at(&header, 8), data_len + 16);
+    }
+
+    #[test]
+    fn chunk_offsets_switch_to_co64_past_4_gib() {
+        let mut trak = TrakTracker::default();
+        let near_4_gib = u64::from(u32::MAX) - 100;
+        trak.add_sample(1, 100, 10, 0, 0, false).unwrap();
+        trak.add_sample(1, near_4_gib, 10, 3000, 0, false).unwrap();
+        trak.finish();
+
+        let mut buf = BytesMut::new();
+        trak.write_common_stbl_parts(&mut buf, 0).unwrap();
+        let stco = find(&buf, &[b"stco"]);
+        assert_eq!(u32_at(stco, 4), 2); // entry_count
+        assert_eq!(u32_at(stco, 12), u32::MAX - 100);
+
+        // The moov in front pushes the last chunk past 4 GiB.
+        buf.clear();
+        trak.write_common_stbl_parts(&mut buf, 1000).unwrap();
+        let tables = boxes(&buf);
+        assert_eq!(fourccs(&tables), ["stts", "stsc", "stsz", "co64"]);
+        let co64 = tables[3].1;
+        assert_eq!(u32_at(co64, 4), 2); // entry_count
+        assert_eq!(u64_at(co64, 8), 1100);
+        assert_eq!(u64_at(co64, 16), near_4_gib + 1000);
+    }
+
+    #[tokio::test]
+    async fn progressive_layout_writes_64_bit_offsets_past_4_gib() {
+        let mut out = SparseSink::default();
+        let mut writer =
+            Mp4Writer::with_audio_sample_entry(None, false, Layout::Progressive, &mut out)
+                .await
+                .unwrap();
+        let sample_entry = video_sample_entry(1280);
+        writer
+            .video(&sample_entry, &[1; 100], 0, 0, true)
+            .await
+            .unwrap();
+        writer.skip_mdat(u64::from(u32::MAX)).await;
+        writer
+            .video(&sample_entry, &[2; 100], 3000, 0, false)
+            .await
+            .unwrap();
+        writer.finish().await.unwrap();
+
+        let mut head = vec![0; 4096];
+        out.read_at(0, &mut head);
+        let ftyp_len = u32_at(&head, 0) as usize;
+        let moov_len = u32_at(&head, ftyp_len) as usize;
+        let moov = &head[ftyp_len..ftyp_len + moov_len];
+        let mdat = &head[ftyp_len + moov_len..];
+        assert_eq!(&mdat[..8], b"\0\0\0\x01mdat");
+        assert_eq!(
+            u64_at(mdat, 8),
+            out.len - (ftyp_len + moov_len) as u64 // largesize
+        );
+
+        let co64 = find(
+            moov,
+            &[b"moov", b"trak", b"mdia", b"minf", b"stbl", b"co64"],
+        );
+        assert_eq!(u32_at(co64, 4), 2); // entry_count
+        let offsets = [u64_at(co64, 8), u64_at(co64, 16)];
+        assert!(offsets[1] > u64::from(u32::MAX));
+        for (i, offset) in offsets.into_iter().enumerate() {
+            let mut sample = vec![0; 100];
+            out.read_at(offset, &mut sample);
+            assert_eq!(sample, vec![i as u8 + 1; 100]);
+        }
+    }
+}
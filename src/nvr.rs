@@ -0,0 +1,308 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+use tokio::time::sleep;
+
+use crate::arming::Arming;
+use crate::mp4::{self, Mp4RecorderOptions};
+use crate::mp4_reader::Mp4Reader;
+use crate::send_video_command::Camera;
+use crate::store::Store;
+
+/// Format of the start time in segment filenames, always in UTC.
+const SEGMENT_TIME_FORMAT: &str = "%Y%m%dT%H%M%S%.3fZ";
+
+/// How often old segments are pruned.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Delay before reconnecting after the first session failure.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Upper bound for the exponential backoff between session failures.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+fn default_segment_duration() -> u64 {
+    60
+}
+
+/// Continuous recording (NVR mode) settings of a camera.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct NvrConfig {
+    /// Length of each segment, in seconds. Segments are cut on the
+    /// first keyframe after that, so they may be slightly longer.
+    #[serde(default = "default_segment_duration")]
+    pub segment_duration: u64,
+
+    /// Segments older than this many seconds are deleted.
+    #[serde(default)]
+    pub max_age: Option<u64>,
+
+    /// The oldest segments are deleted while the camera uses more than this many bytes.
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+}
+
+/// A finished segment on disk.
+#[derive(Debug, Clone)]
+pub struct SegmentFile {
+    pub path: PathBuf,
+    pub start: DateTime<Utc>,
+    pub size: u64,
+}
+
+/// Directory all segments are written to, from the `RECORDINGS_DIR` env var.
+pub fn recordings_dir() -> PathBuf {
+    env::var("RECORDINGS_DIR")
+        .unwrap_or("recordings".to_string())
+        .into()
+}
+
+pub fn camera_dir(recordings_dir: &Path, camera_name: &str) -> PathBuf {
+    recordings_dir.join(camera_name)
+}
+
+/// Path of the segment of `camera_name` starting at `start`, e.g. `cam1/cam1_20240101T083000.000Z.mp4`.
+pub fn segment_path(camera_dir: &Path, camera_name: &str, start: DateTime<Utc>) -> PathBuf {
+    camera_dir.join(format!(
+        "{}_{}.mp4",
+        camera_name,
+        start.format(SEGMENT_TIME_FORMAT)
+    ))
+}
+
+/// Parses the start time back from a segment filename.
+fn segment_start(path: &Path, camera_name: &str) -> Option<DateTime<Utc>> {
+    let filename = path.file_name()?.to_str()?;
+    let timestamp = filename
+        .strip_prefix(camera_name)?
+        .strip_prefix('_')?
+        .strip_suffix(".mp4")?;
+
+    NaiveDateTime::parse_from_str(timestamp, SEGMENT_TIME_FORMAT)
+        .ok()
+        .map(|start| start.and_utc())
+}
+
+/// Lists the finished segments of a camera, oldest first.
+pub async fn list_segments(
+    camera_dir: &Path,
+    camera_name: &str,
+) -> std::io::Result<Vec<SegmentFile>> {
+    let mut segments = Vec::new();
+    let mut entries = tokio::fs::read_dir(camera_dir).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+
+        if let Some(start) = segment_start(&path, camera_name) {
+            let size = entry.metadata().await?.len();
+            segments.push(SegmentFile { path, start, size });
+        }
+    }
+
+    segments.sort_by_key(|segment| segment.start);
+
+    Ok(segments)
+}
+
+/// Deletes segments older than `max_age`, then the oldest ones while over `max_bytes`.
+async fn prune_segments(
+    camera_dir: &Path,
+    camera_name: &str,
+    nvr: &NvrConfig,
+) -> std::io::Result<()> {
+    let mut segments = list_segments(camera_dir, camera_name).await?;
+
+    // The newest segment is never pruned, so there's always something to look at.
+    segments.pop();
+
+    let now = Utc::now();
+    let mut total_bytes: u64 = segments.iter().map(|segment| segment.size).sum();
+
+    for segment in segments {
+        let age = now.signed_duration_since(segment.start).num_seconds();
+        let too_old = nvr.max_age.is_some_and(|max_age| age > max_age as i64);
+        let too_big = nvr
+            .max_bytes
+            .is_some_and(|max_bytes| total_bytes > max_bytes);
+
+        if !too_old && !too_big {
+            break;
+        }
+
+        log::debug!("Pruning segment {}", segment.path.display());
+
+        // A segment that can't be removed mustn't keep the older ones from being pruned.
+        if let Err(remove_error) = tokio::fs::remove_file(&segment.path).await {
+            log::error!(
+                "Failed to prune segment {}: {}",
+                segment.path.display(),
+                remove_error
+            );
+            continue;
+        }
+        total_bytes -= segment.size;
+    }
+
+    Ok(())
+}
+
+/// Deals with `.partial` files left behind when the process was stopped mid-segment.
+///
+/// Fragmented ones with complete fragments are playable, so they're kept as
+/// segments; the others are removed.
+async fn recover_stale_partials(camera_dir: &Path) -> std::io::Result<()> {
+    let mut entries = tokio::fs::read_dir(camera_dir).await?;
+
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+
+        if path.extension().is_none_or(|ext| ext != "partial") {
+            continue;
+        }
+
+        let is_playable = Mp4Reader::open(&path)
+            .await
+            .is_ok_and(|reader| reader.video.is_some_and(|video| !video.samples.is_empty()));
+
+        if is_playable {
+            let segment = path.with_extension("");
+            log::info!("Recovering stale partial segment as {}", segment.display());
+            tokio::fs::rename(&path, &segment).await?;
+        } else {
+            log::info!("Removing stale partial segment {}", path.display());
+            tokio::fs::remove_file(&path).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Records the camera whenever it's armed. Disarming it finishes the segment in progress.
+async fn record_forever(camera: &Camera, nvr: &NvrConfig, camera_dir: &Path, arming: &Arming) {
+    let mut backoff = INITIAL_BACKOFF;
+
+    loop {
+        if !arming.is_armed(camera) {
+            log::info!(
+                "Pausing continuous recording for disarmed camera {}",
+                camera.name
+            );
+            arming.wait_until(camera, true).await;
+            log::info!("Resuming continuous recording for camera {}", camera.name);
+            backoff = INITIAL_BACKOFF;
+        }
+
+        let options = match Mp4RecorderOptions::try_from(camera.clone()) {
+            Ok(options) => options,
+            Err(config_error) => {
+                log::error!(
+                    "Continuous recording for camera {} stopped: {}",
+                    camera.name,
+                    config_error
+                );
+                return;
+            }
+        };
+        let started_at = Instant::now();
+
+        let result = mp4::start_segmented_recording(
+            options,
+            Duration::from_secs(nvr.segment_duration),
+            |start| segment_path(camera_dir, &camera.name, start),
+            arming.wait_until(camera, false),
+        )
+        .await;
+
+        // Stopped because the camera was disarmed, which is handled above.
+        let Err(recorder_error) = result else {
+            continue;
+        };
+
+        log::error!(
+            "Continuous recording for camera {} stopped. Reconnecting in {:?}. Reason:",
+            camera.name,
+            backoff
+        );
+        log::error!("{:?}", recorder_error);
+
+        // A session that ran for a while was healthy, so the next failure starts over.
+        if started_at.elapsed() > MAX_BACKOFF {
+            backoff = INITIAL_BACKOFF;
+        }
+
+        sleep(backoff).await;
+        backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+    }
+}
+
+async fn prune_forever(camera: &Camera, nvr: &NvrConfig, camera_dir: &Path) {
+    loop {
+        if let Err(prune_error) = prune_segments(camera_dir, &camera.name, nvr).await {
+            log::error!(
+                "Failed to prune segments of camera {}: {}",
+                camera.name,
+                prune_error
+            );
+        }
+
+        sleep(PRUNE_INTERVAL).await;
+    }
+}
+
+/// Records a camera into rolling segments while it's armed, applying its retention policy,
+/// until aborted.
+pub async fn run_recorder(
+    camera: Camera,
+    nvr: NvrConfig,
+    recordings_dir: PathBuf,
+    store: Arc<Store>,
+) {
+    let camera_dir = camera_dir(&recordings_dir, &camera.name);
+
+    if let Err(create_dir_error) = tokio::fs::create_dir_all(&camera_dir).await {
+        log::error!(
+            "Failed to create '{}', continuous recording for camera {} is disabled: {}",
+            camera_dir.display(),
+            camera.name,
+            create_dir_error
+        );
+        return;
+    }
+
+    if let Err(cleanup_error) = recover_stale_partials(&camera_dir).await {
+        log::error!(
+            "Failed to clean up '{}': {}",
+            camera_dir.display(),
+            cleanup_error
+        );
+    }
+
+    log::info!(
+        "Recording camera {} continuously into '{}'",
+        camera.name,
+        camera_dir.display()
+    );
+
+    tokio::join!(
+        record_forever(&camera, &nvr, &camera_dir, &store.arming),
+        prune_forever(&camera, &nvr, &camera_dir),
+    );
+}
+
+/// Starts a recorder for `camera` into `tasks` when it has an `nvr` section.
+pub fn spawn_recorder(camera: &Camera, store: &Arc<Store>, tasks: &mut JoinSet<()>) {
+    if let Some(nvr) = camera.nvr.clone() {
+        tasks.spawn(run_recorder(
+            camera.clone(),
+            nvr,
+            recordings_dir(),
+            store.clone(),
+        ));
+    }
+}
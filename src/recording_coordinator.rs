@@ -0,0 +1,206 @@
+use futures::future::{BoxFuture, Shared};
+use futures::FutureExt as _;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::OnceCell;
+use tokio_compat_02::FutureExt;
+
+use crate::live_feed::{LiveFeed, LiveFeeds};
+use crate::mp4::{self, Mp4RecorderOptions};
+use crate::mp4_writer::WriterStats;
+
+/// A finished recording shared by everyone who asked for it.
+///
+/// The file is uploaded once; `file_id` then holds the Telegram file id so the
+/// video can be re-sent to other chats without uploading it again.
+/// The file is deleted from disk once the last requester drops it.
+pub struct Recording {
+    pub output: PathBuf,
+    pub stats: WriterStats,
+    pub file_id: OnceCell<String>,
+}
+
+impl Drop for Recording {
+    fn drop(&mut self) {
+        if let Err(remove_file_error) = std::fs::remove_file(&self.output) {
+            if remove_file_error.kind() != std::io::ErrorKind::NotFound {
+                log::error!("Failed to delete file at '{}'", self.output.display());
+                log::error!("{:?}", remove_file_error);
+            }
+        }
+    }
+}
+
+pub type RecordingResult = Result<Arc<Recording>, Arc<anyhow::Error>>;
+
+type SharedRecording = Shared<BoxFuture<'static, RecordingResult>>;
+
+/// A recording that is still running, with the duration it was started for.
+struct InFlight {
+    duration: u64,
+    recording: SharedRecording,
+}
+
+/// How [`RecordingCoordinator::record`] got the recording it returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordingStart {
+    /// The call started a new recording.
+    New,
+
+    /// The call joined a recording already running, which lasts `duration`
+    /// seconds whatever duration was asked for.
+    Joined { duration: u64 },
+}
+
+/// Deduplicates concurrent recordings of the same camera.
+///
+/// The first request for a camera starts the recording, requests that arrive
+/// while it is still running subscribe to the same result instead of opening
+/// another RTSP session, which cheap cameras often refuse.
+///
+/// Cameras with a connected live feed are recorded from it, including its pre-roll.
+/// A feed is disconnected while its camera is disarmed, forced recordings then
+/// open their own session. Snapshots go through it too, see [`RecordingCoordinator::snapshot`].
+pub struct RecordingCoordinator {
+    in_flight: Arc<Mutex<HashMap<String, InFlight>>>,
+    live_feeds: LiveFeeds,
+}
+
+impl RecordingCoordinator {
+    pub fn new(live_feeds: LiveFeeds) -> Self {
+        RecordingCoordinator {
+            in_flight: Arc::default(),
+            live_feeds,
+        }
+    }
+
+    fn connected_feed(&self, camera_name: &str) -> Option<Arc<LiveFeed>> {
+        self.live_feeds
+            .read()
+            .unwrap()
+            .get(camera_name)
+            .filter(|live_feed| live_feed.is_connected())
+            .cloned()
+    }
+
+    /// Returns the recording for `camera_name`, and whether this call started it.
+    ///
+    /// A recording already running is joined even if it was started for another
+    /// duration, rather than opening a second session to the camera.
+    pub fn record(
+        &self,
+        camera_name: &str,
+        options: Mp4RecorderOptions,
+    ) -> (SharedRecording, RecordingStart) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+
+        if let Some(ongoing) = in_flight.get(camera_name) {
+            log::info!(
+                "Joining in-flight {} sec recording for camera {}",
+                ongoing.duration,
+                camera_name
+            );
+            return (
+                ongoing.recording.clone(),
+                RecordingStart::Joined {
+                    duration: ongoing.duration,
+                },
+            );
+        }
+
+        let in_flight_ref = self.in_flight.clone();
+        let key = camera_name.to_string();
+        let live_feed = self.connected_feed(camera_name);
+        let duration = options.duration;
+
+        let recording = async move {
+            let result = match live_feed {
+                Some(live_feed) => {
+                    live_feed
+                        .record(
+                            &options.output,
+                            Duration::from_secs(options.duration),
+                            options.layout,
+                        )
+                        .await
+                }
+                None => mp4::start_recording(options.clone()).compat().await,
+            };
+
+            // Requests arriving from now on get a fresh recording.
+            in_flight_ref.lock().unwrap().remove(&key);
+
+            match result {
+                Ok(stats) => Ok(Arc::new(Recording {
+                    output: options.output,
+                    stats,
+                    file_id: OnceCell::new(),
+                })),
+                Err(recorder_error) => {
+                    remove_recording(&options.output).await;
+                    Err(Arc::new(recorder_error))
+                }
+            }
+        }
+        .boxed()
+        .shared();
+
+        in_flight.insert(
+            camera_name.to_string(),
+            InFlight {
+                duration,
+                recording: recording.clone(),
+            },
+        );
+
+        (recording, RecordingStart::New)
+    }
+
+    /// Writes a single-frame snapshot of `camera_name` into `options.output`.
+    ///
+    /// Cameras with a connected live feed are snapshotted from it. Otherwise a recording
+    /// of the camera still running is waited for first, so the snapshot doesn't
+    /// open a second session to the camera.
+    pub async fn snapshot(
+        &self,
+        camera_name: &str,
+        options: Mp4RecorderOptions,
+    ) -> Result<(), anyhow::Error> {
+        if let Some(live_feed) = self.connected_feed(camera_name) {
+            return live_feed
+                .snapshot(&options.output, options.snapshot_timeout)
+                .await;
+        }
+
+        let ongoing = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .get(camera_name)
+            .map(|ongoing| ongoing.recording.clone());
+
+        if let Some(ongoing) = ongoing {
+            log::info!(
+                "Waiting for the recording of camera {} before the snapshot",
+                camera_name
+            );
+            let _ = ongoing.await;
+        }
+
+        mp4::start_snapshot(options).compat().await
+    }
+}
+
+/// Deletes a recording from disk, if it is there. Failures are only logged.
+pub async fn remove_recording(output: &Path) {
+    if let Ok(false) | Err(_) = tokio::fs::try_exists(output).await {
+        return;
+    }
+
+    if let Err(remove_file_error) = tokio::fs::remove_file(output).await {
+        log::error!("Failed to delete file at '{}'", output.display());
+        log::error!("{:?}", remove_file_error);
+    }
+}
@@ -0,0 +1,513 @@
+//! Recurring recordings and snapshots sent to a chat, e.g. every day at 08:00 and 20:00.
+
+use chrono::{DateTime, Datelike, Duration as ChronoDuration, Local, Timelike};
+use futures::FutureExt as _;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::task::JoinSet;
+use tokio::time::sleep;
+use tokio_compat_02::FutureExt;
+
+use telegram_bot::{Api, ChatId};
+
+use crate::destination::Destination;
+use crate::error::BotError;
+use crate::recording_coordinator::RecordingCoordinator;
+use crate::send_snapshot_command::send_snapshot_for_camera;
+use crate::send_video_command::{
+    get_camera_configs, report_camera_errors, send_video_for_camera, Camera,
+};
+use crate::store::{JsonFile, Store};
+
+/// A cron expression: `minute hour day-of-month month day-of-week`, e.g. `0 8,20 * * *`.
+///
+/// Fields accept `*`, numbers, ranges (`1-5`), lists (`8,20`) and steps (`*/15`).
+/// Days of the week go from 0 (Sunday) to 6, 7 is Sunday too. As in cron, a day
+/// matches when either day field matches, if both are restricted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct CronExpr {
+    source: String,
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+/// Parses a cron field into a bit set of the matching values.
+fn parse_cron_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+    let parse_value = |value: &str| {
+        value
+            .parse::<u32>()
+            .map_err(|_| format!("invalid value '{}' in '{}'", value, field))
+    };
+
+    let mut values = 0;
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<usize>() {
+                Ok(step) if step > 0 => (range, step),
+                _ => return Err(format!("invalid step in '{}'", part)),
+            },
+            None => (part, 1),
+        };
+
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (parse_value(start)?, parse_value(end)?)
+        } else {
+            let start = parse_value(range)?;
+            // `5/15` means every 15 from 5 on.
+            (start, if step > 1 { max } else { start })
+        };
+
+        if start < min || end > max || start > end {
+            return Err(format!("'{}' is out of range {}-{}", part, min, max));
+        }
+
+        for value in (start..=end).step_by(step) {
+            values |= 1 << value;
+        }
+    }
+
+    Ok(values)
+}
+
+impl FromStr for CronExpr {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = source.split_whitespace().collect();
+
+        let [minutes, hours, days_of_month, months, days_of_week] = fields[..] else {
+            return Err(format!(
+                "'{}' should have 5 fields: minute hour day-of-month month day-of-week",
+                source
+            ));
+        };
+
+        let mut days_of_week_values = parse_cron_field(days_of_week, 0, 7)?;
+        if days_of_week_values & (1 << 7) != 0 {
+            days_of_week_values |= 1;
+        }
+
+        Ok(CronExpr {
+            source: fields.join(" "),
+            minutes: parse_cron_field(minutes, 0, 59)?,
+            hours: parse_cron_field(hours, 0, 23)?,
+            days_of_month: parse_cron_field(days_of_month, 1, 31)?,
+            months: parse_cron_field(months, 1, 12)?,
+            days_of_week: days_of_week_values,
+            any_day_of_month: days_of_month == "*",
+            any_day_of_week: days_of_week == "*",
+        })
+    }
+}
+
+impl TryFrom<String> for CronExpr {
+    type Error = String;
+
+    fn try_from(source: String) -> Result<Self, Self::Error> {
+        source.parse()
+    }
+}
+
+impl From<CronExpr> for String {
+    fn from(cron: CronExpr) -> Self {
+        cron.source
+    }
+}
+
+impl fmt::Display for CronExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl CronExpr {
+    pub fn matches(&self, time: DateTime<Local>) -> bool {
+        let is_set = |values: u64, value: u32| values & (1 << value) != 0;
+
+        let day_of_month = is_set(self.days_of_month, time.day());
+        let day_of_week = is_set(self.days_of_week, time.weekday().num_days_from_sunday());
+        let day = match (self.any_day_of_month, self.any_day_of_week) {
+            (true, _) => day_of_week,
+            (_, true) => day_of_month,
+            (false, false) => day_of_month || day_of_week,
+        };
+
+        is_set(self.minutes, time.minute())
+            && is_set(self.hours, time.hour())
+            && is_set(self.months, time.month())
+            && day
+    }
+}
+
+/// What a schedule sends.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ScheduleKind {
+    #[default]
+    Video,
+    Snapshot,
+}
+
+impl fmt::Display for ScheduleKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduleKind::Video => f.write_str("video"),
+            ScheduleKind::Snapshot => f.write_str("snapshot"),
+        }
+    }
+}
+
+/// A recording (or snapshot) of a camera sent to `chat_id` whenever `cron` matches, in local time.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ScheduleConfig {
+    pub cron: CronExpr,
+    pub chat_id: i64,
+
+    #[serde(default)]
+    pub kind: ScheduleKind,
+
+    /// Overrides the camera `duration` for videos, capped at its `maxDuration`.
+    #[serde(default)]
+    pub duration: Option<u64>,
+}
+
+/// A schedule added with the schedule command, on behalf of `user_id`.
+/// It stops running when that user loses access to the camera.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredSchedule {
+    pub id: u64,
+    pub camera: String,
+    pub user_id: i64,
+
+    #[serde(flatten)]
+    pub schedule: ScheduleConfig,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SchedulesFile {
+    next_id: u64,
+    schedules: Vec<StoredSchedule>,
+}
+
+/// Schedules added with the schedule command.
+pub struct Schedules {
+    file: JsonFile<SchedulesFile>,
+}
+
+impl Schedules {
+    pub fn open(path: PathBuf) -> Result<Self, BotError> {
+        Ok(Schedules {
+            file: JsonFile::open(path)?,
+        })
+    }
+
+    pub fn list(&self) -> Vec<StoredSchedule> {
+        self.file.read(|file| file.schedules.clone())
+    }
+
+    /// Adds a schedule, returning its id.
+    pub async fn add(
+        &self,
+        camera: String,
+        user_id: i64,
+        schedule: ScheduleConfig,
+    ) -> Result<u64, BotError> {
+        self.file
+            .update(|file| {
+                file.next_id += 1;
+                file.schedules.push(StoredSchedule {
+                    id: file.next_id,
+                    camera,
+                    user_id,
+                    schedule,
+                });
+
+                file.next_id
+            })
+            .await
+    }
+
+    /// Removes schedule `id` if it targets `chat_id`. Returns false when there's no such schedule.
+    pub async fn remove(&self, id: u64, chat_id: i64) -> Result<bool, BotError> {
+        self.file
+            .update(|file| {
+                let count = file.schedules.len();
+                file.schedules
+                    .retain(|stored| stored.id != id || stored.schedule.chat_id != chat_id);
+
+                file.schedules.len() != count
+            })
+            .await
+    }
+}
+
+/// Sends the video or snapshot of a schedule to its chat, reporting failures there.
+async fn run_schedule(
+    api: Api,
+    camera: Camera,
+    schedule: ScheduleConfig,
+    coordinator: Arc<RecordingCoordinator>,
+    store: Arc<Store>,
+) {
+    let destination = Destination::Chat(ChatId::new(schedule.chat_id));
+    let camera_name = camera.name.clone();
+
+    let result = match schedule.kind {
+        ScheduleKind::Video => {
+            send_video_for_camera(
+                camera,
+                api.clone(),
+                destination.clone(),
+                schedule.duration,
+                coordinator,
+                &store.history,
+            )
+            .await
+        }
+        ScheduleKind::Snapshot => {
+            send_snapshot_for_camera(
+                camera,
+                api.clone(),
+                destination.clone(),
+                coordinator,
+                &store.history,
+            )
+            .await
+        }
+    };
+
+    report_camera_errors(&api, &destination, vec![(camera_name, result)]).await;
+}
+
+/// The schedules due at `minute`: the ones from the camera config, and the
+/// stored ones whose user may still see the camera from the target chat.
+/// Disarmed cameras are skipped.
+fn due_schedules(minute: DateTime<Local>, store: &Store) -> Vec<(Camera, ScheduleConfig)> {
+    let camera_config = match get_camera_configs(store) {
+        Ok(camera_config) => camera_config,
+        Err(config_error) => {
+            log::error!("Failed to load camera config. Skipping schedules.");
+            log::error!("{:?}", config_error);
+            return Vec::new();
+        }
+    };
+
+    let mut due: Vec<(Camera, ScheduleConfig)> = camera_config
+        .cameras
+        .iter()
+        .flat_map(|camera| {
+            camera
+                .schedules
+                .iter()
+                .filter(|schedule| schedule.cron.matches(minute))
+                .map(move |schedule| (camera.clone(), schedule.clone()))
+        })
+        .collect();
+
+    let access = &camera_config.access;
+
+    for stored in store.schedules.list() {
+        if !stored.schedule.cron.matches(minute) {
+            continue;
+        }
+
+        let camera = camera_config
+            .cameras
+            .iter()
+            .find(|camera| camera.name == stored.camera)
+            .filter(|camera| {
+                access.is_allowed(stored.user_id, stored.schedule.chat_id)
+                    && access.can_view_camera(camera, stored.user_id)
+            });
+
+        match camera {
+            Some(camera) => due.push((camera.clone(), stored.schedule)),
+            None => log::warn!(
+                "Skipping schedule #{}, camera {} is gone or user {} lost access to it",
+                stored.id,
+                stored.camera,
+                stored.user_id
+            ),
+        }
+    }
+
+    due.retain(|(camera, _)| {
+        let is_armed = store.arming.is_armed(camera);
+        if !is_armed {
+            log::info!("Skipping scheduled job of disarmed camera {}", camera.name);
+        }
+        is_armed
+    });
+
+    due
+}
+
+/// Wakes up at the start of every minute and runs the schedules due then, until aborted.
+pub async fn run_scheduler(api: Api, coordinator: Arc<RecordingCoordinator>, store: Arc<Store>) {
+    let mut jobs = JoinSet::new();
+
+    loop {
+        let now = Local::now();
+        let minute = now
+            .with_second(0)
+            .and_then(|now| now.with_nanosecond(0))
+            .unwrap_or(now)
+            + ChronoDuration::minutes(1);
+
+        sleep((minute - now).to_std().unwrap_or_default()).await;
+
+        // Reap finished jobs so the set doesn't grow forever.
+        while let Some(Some(result)) = jobs.join_next().now_or_never() {
+            if let Err(join_error) = result {
+                log::error!("Scheduled job failed: {}", join_error);
+            }
+        }
+
+        for (camera, schedule) in due_schedules(minute, &store) {
+            log::info!(
+                "Running scheduled {} of camera {} for chat {}",
+                schedule.kind,
+                camera.name,
+                schedule.chat_id
+            );
+
+            jobs.spawn(
+                run_schedule(
+                    api.clone(),
+                    camera,
+                    schedule,
+                    coordinator.clone(),
+                    store.clone(),
+                )
+                .compat(),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn bits(values: &[u32]) -> u64 {
+        values.iter().fold(0, |bits, value| bits | 1 << value)
+    }
+
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        // June 2024 starts on a Saturday.
+        Local
+            .with_ymd_and_hms(2024, 6, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    fn cron(source: &str) -> CronExpr {
+        source.parse().unwrap()
+    }
+
+    #[test]
+    fn cron_fields_accept_values_lists_and_ranges() {
+        assert_eq!(parse_cron_field("5", 0, 59), Ok(bits(&[5])));
+        assert_eq!(parse_cron_field("8,20", 0, 23), Ok(bits(&[8, 20])));
+        assert_eq!(parse_cron_field("1-5", 0, 7), Ok(bits(&[1, 2, 3, 4, 5])));
+        assert_eq!(parse_cron_field("1-2,10", 1, 31), Ok(bits(&[1, 2, 10])));
+        assert_eq!(
+            parse_cron_field("*", 1, 12),
+            Ok(bits(&(1..=12).collect::<Vec<_>>()))
+        );
+    }
+
+    #[test]
+    fn cron_fields_accept_steps() {
+        assert_eq!(parse_cron_field("*/15", 0, 59), Ok(bits(&[0, 15, 30, 45])));
+        assert_eq!(parse_cron_field("5/15", 0, 59), Ok(bits(&[5, 20, 35, 50])));
+        assert_eq!(parse_cron_field("10-20/5", 0, 59), Ok(bits(&[10, 15, 20])));
+        assert_eq!(
+            parse_cron_field("*/7", 1, 31),
+            Ok(bits(&[1, 8, 15, 22, 29]))
+        );
+    }
+
+    #[test]
+    fn cron_fields_reject_invalid_and_out_of_range_values() {
+        assert!(parse_cron_field("60", 0, 59).is_err());
+        assert!(parse_cron_field("0", 1, 31).is_err());
+        assert!(parse_cron_field("10-13", 1, 12).is_err());
+        assert!(parse_cron_field("5-1", 0, 59).is_err());
+        assert!(parse_cron_field("*/0", 0, 59).is_err());
+        assert!(parse_cron_field("*/x", 0, 59).is_err());
+        assert!(parse_cron_field("a", 0, 59).is_err());
+        assert!(parse_cron_field("", 0, 59).is_err());
+
+        assert!("0 24 * * *".parse::<CronExpr>().is_err());
+        assert!("0 8 * * 8".parse::<CronExpr>().is_err());
+        assert!("0 8 * *".parse::<CronExpr>().is_err());
+        assert!("0 8 * * * *".parse::<CronExpr>().is_err());
+    }
+
+    #[test]
+    fn cron_matches_minutes_hours_and_months() {
+        let twice_a_day = cron("0 8,20 * * *");
+        assert!(twice_a_day.matches(at(3, 8, 0)));
+        assert!(twice_a_day.matches(at(3, 20, 0)));
+        assert!(!twice_a_day.matches(at(3, 8, 1)));
+        assert!(!twice_a_day.matches(at(3, 9, 0)));
+
+        assert!(cron("*/15 * * 6 *").matches(at(3, 9, 45)));
+        assert!(!cron("*/15 * * 7-12 *").matches(at(3, 9, 45)));
+    }
+
+    #[test]
+    fn cron_day_of_week_7_is_sunday() {
+        let sundays = cron("0 8 * * 7");
+        assert!(sundays.matches(at(2, 8, 0)));
+        assert!(!sundays.matches(at(3, 8, 0)));
+        assert_eq!(
+            sundays.days_of_week,
+            cron("0 8 * * 0").days_of_week | 1 << 7
+        );
+
+        let weekends = cron("0 8 * * 6-7");
+        assert!(weekends.matches(at(1, 8, 0)));
+        assert!(weekends.matches(at(2, 8, 0)));
+        assert!(!weekends.matches(at(3, 8, 0)));
+    }
+
+    #[test]
+    fn cron_day_matches_either_day_field_when_both_are_restricted() {
+        // The 1st of the month, or Mondays.
+        let both = cron("0 8 1 * 1");
+        assert!(both.matches(at(1, 8, 0)));
+        assert!(both.matches(at(3, 8, 0)));
+        assert!(!both.matches(at(4, 8, 0)));
+
+        // A wildcard field doesn't widen the other one.
+        let first_of_month = cron("0 8 1 * *");
+        assert!(first_of_month.matches(at(1, 8, 0)));
+        assert!(!first_of_month.matches(at(3, 8, 0)));
+
+        let mondays = cron("0 8 * * 1");
+        assert!(mondays.matches(at(3, 8, 0)));
+        assert!(!mondays.matches(at(1, 8, 0)));
+    }
+
+    #[test]
+    fn cron_keeps_its_source_normalized() {
+        assert_eq!(cron(" 0  8,20 * *   * ").to_string(), "0 8,20 * * *");
+    }
+}
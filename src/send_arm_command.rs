@@ -0,0 +1,99 @@
+use telegram_bot::prelude::*;
+use telegram_bot::{Api, Message};
+
+use crate::error::BotError;
+use crate::send_video_command::{get_camera_configs, select_cameras};
+use crate::store::Store;
+
+/// Arms (or disarms) the named cameras, or every camera when empty,
+/// announcing the change in the chat of `command_msg`. Admins only.
+pub async fn send_arm_command(
+    api: Api,
+    command_msg: Message,
+    camera_names: Vec<String>,
+    armed: bool,
+    store: &Store,
+) -> Result<(), BotError> {
+    let camera_config = get_camera_configs(store)?;
+    let user = &command_msg.from;
+    let user_name = user.username.as_deref().unwrap_or(&user.first_name);
+    let action = if armed { "armed" } else { "disarmed" };
+
+    if !camera_config.access.is_admin(i64::from(user.id)) {
+        api.send(command_msg.text_reply("Only admins can arm or disarm cameras."))
+            .await?;
+        return Ok(());
+    }
+
+    let announcement = if camera_names.is_empty() {
+        store.arming.set_globally_armed(armed).await?;
+        log::info!("User {} {} every camera", user.id, action);
+
+        format!("{} {} every camera.", user_name, action)
+    } else {
+        let cameras = match select_cameras(camera_config, &camera_names, i64::from(user.id)) {
+            Ok(cameras) => cameras,
+            Err(reason) => {
+                api.send(command_msg.text_reply(reason)).await?;
+                return Ok(());
+            }
+        };
+
+        for camera in &cameras {
+            store.arming.set_camera_armed(&camera.name, armed).await?;
+        }
+
+        let names: Vec<&str> = cameras.iter().map(|camera| camera.name.as_str()).collect();
+        log::info!("User {} {} cameras {:?}", user.id, action, names);
+
+        let mut announcement = format!("{} {} camera(s) {}.", user_name, action, names.join(", "));
+        if armed && !store.arming.is_globally_armed() {
+            announcement
+                .push_str(" Every camera is still disarmed until armed without camera names.");
+        }
+
+        announcement
+    };
+
+    api.send(command_msg.text_reply(announcement)).await?;
+
+    Ok(())
+}
+
+/// Replies with the armed state of every camera the user can see.
+pub async fn send_status_command(
+    api: Api,
+    command_msg: Message,
+    store: &Store,
+) -> Result<(), BotError> {
+    let camera_config = get_camera_configs(store)?;
+    let user_id = i64::from(command_msg.from.id);
+    let cameras = select_cameras(camera_config, &[], user_id).unwrap_or_default();
+
+    let mut lines = vec![if store.arming.is_globally_armed() {
+        "Armed.".to_string()
+    } else {
+        "Disarmed, no camera is watched.".to_string()
+    }];
+
+    for camera in &cameras {
+        let mut features = vec![if store.arming.is_camera_armed(camera) {
+            "armed"
+        } else {
+            "disarmed"
+        }];
+
+        if camera.motion.is_some() {
+            features.push("motion detection");
+        }
+        if camera.nvr.is_some() {
+            features.push("continuous recording");
+        }
+
+        lines.push(format!("{}: {}", camera.name, features.join(", ")));
+    }
+
+    api.send(command_msg.text_reply(lines.join("\n"))).await?;
+
+    Ok(())
+}
@@ -0,0 +1,169 @@
+use chrono::{DateTime, Duration, Local, NaiveTime, Utc};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+
+use telegram_bot::{prelude::*, InputFileUpload};
+use telegram_bot::{Api, Message};
+
+use crate::clip;
+use crate::error::BotError;
+use crate::history::{HistoryEntry, Kind, Outcome};
+use crate::nvr;
+use crate::recording_coordinator::remove_recording;
+use crate::send_video_command::{get_camera_configs, select_cameras, skip_disarmed};
+use crate::store::Store;
+
+/// The most recent past occurrence of `at` in local time: today, or yesterday
+/// if `at` is still to come today.
+fn most_recent_local_time(at: NaiveTime) -> Option<DateTime<Utc>> {
+    let now = Local::now();
+    let today = now
+        .date_naive()
+        .and_time(at)
+        .and_local_timezone(Local)
+        .earliest()?;
+
+    let time = if today > now {
+        today - Duration::days(1)
+    } else {
+        today
+    };
+
+    Some(time.with_timezone(&Utc))
+}
+
+pub async fn send_clip_command(
+    api: Api,
+    command_msg: Message,
+    camera_name: String,
+    at: NaiveTime,
+    duration: Option<u64>,
+    force: bool,
+    store: Arc<Store>,
+) -> Result<(), BotError> {
+    let camera_config = get_camera_configs(&store)?;
+    let user_id = i64::from(command_msg.from.id);
+    let is_admin = camera_config.access.is_admin(user_id);
+
+    let cameras = match select_cameras(camera_config, &[camera_name], user_id) {
+        Ok(cameras) => cameras,
+        Err(reason) => {
+            api.send(command_msg.text_reply(reason)).await?;
+            return Ok(());
+        }
+    };
+
+    let cameras = skip_disarmed(
+        &api,
+        &command_msg,
+        cameras,
+        &store.arming,
+        force && is_admin,
+    )
+    .await?;
+
+    let Some(camera) = cameras.into_iter().next() else {
+        return Ok(());
+    };
+
+    if camera.nvr.is_none() {
+        api.send(command_msg.text_reply(format!(
+            "Camera {} has no continuous recording, so there's no past footage to clip.",
+            camera.name
+        )))
+        .await?;
+        return Ok(());
+    }
+
+    let Some(from) = most_recent_local_time(at) else {
+        api.send(command_msg.text_reply(format!("{} doesn't exist in local time.", at)))
+            .await?;
+        return Ok(());
+    };
+
+    let mut entry = HistoryEntry::new(
+        camera.name.clone(),
+        Some(user_id),
+        i64::from(command_msg.chat.id()),
+    );
+    entry.kind = Kind::Clip;
+    entry.duration = camera.effective_duration(duration);
+
+    let started = Instant::now();
+    let result = write_and_send_clip(&api, &command_msg, &camera.name, from, &mut entry).await;
+
+    entry.elapsed_ms = started.elapsed().as_millis() as u64;
+    if let Err(err) = &result {
+        entry.outcome = Outcome::Failed {
+            reason: err.to_string(),
+        };
+    }
+    store.history.record(entry).await;
+
+    result
+}
+
+/// Cuts `entry.duration` seconds of footage from `from` and replies with it.
+async fn write_and_send_clip(
+    api: &Api,
+    command_msg: &Message,
+    camera_name: &str,
+    from: DateTime<Utc>,
+    entry: &mut HistoryEntry,
+) -> Result<(), BotError> {
+    let feedback_msg = api
+        .send(command_msg.text_reply(format!(
+            "Looking for {} sec of footage from camera {} at {}..",
+            entry.duration,
+            camera_name,
+            from.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S")
+        )))
+        .await?;
+
+    let camera_dir = nvr::camera_dir(&nvr::recordings_dir(), camera_name);
+    let output = PathBuf::from(format!("clip_{}.mp4", Local::now()));
+
+    let clip_result = clip::write_clip(
+        &camera_dir,
+        camera_name,
+        from,
+        std::time::Duration::from_secs(entry.duration),
+        &output,
+    )
+    .await;
+
+    if let Err(clip_error) = clip_result {
+        remove_recording(&output).await;
+
+        log::error!("Clip of camera {} failed: {:#}", camera_name, clip_error);
+
+        // Clips are cut from the files on disk, their errors don't contain the camera URL.
+        let reason = clip_error.root_cause().to_string();
+        api.send(feedback_msg.edit_text(format!(
+            "Clip of camera {} has failed: {}",
+            camera_name, reason
+        )))
+        .await?;
+
+        entry.outcome = Outcome::Failed { reason };
+        return Ok(());
+    }
+
+    entry.set_size(&output).await;
+
+    let video = InputFileUpload::with_path(output.to_string_lossy().into_owned());
+    let video_reply = api.send(command_msg.video_reply(video)).await;
+
+    remove_recording(&output).await;
+    video_reply?;
+
+    if let Err(delete_feedback_msg_error) = api.send(feedback_msg.delete()).await {
+        log::error!(
+            "Failed to delete clip feedback message: {:?}",
+            delete_feedback_msg_error
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,127 @@
+use chrono::Local;
+
+use telegram_bot::prelude::*;
+use telegram_bot::{Api, Message, ParseMode};
+
+use crate::error::BotError;
+use crate::history::{HistoryEntry, Kind, Outcome};
+use crate::send_video_command::{get_camera_configs, select_cameras};
+use crate::store::Store;
+
+/// Entries listed when the command doesn't say how many.
+const DEFAULT_COUNT: usize = 10;
+
+const MAX_COUNT: usize = 50;
+
+/// Telegram rejects longer messages (4096), this leaves room for the markup.
+const MAX_MESSAGE_LEN: usize = 3800;
+
+/// Failure reasons are cut to this many characters.
+const MAX_REASON_LEN: usize = 80;
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn format_size(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{}B", bytes),
+        1024..=1_048_575 => format!("{}K", bytes / 1024),
+        _ => format!("{:.1}M", bytes as f64 / 1_048_576.0),
+    }
+}
+
+/// One line per entry, plus the reason on a second line when it failed.
+fn format_entry(entry: &HistoryEntry) -> String {
+    let size = entry.size.map(format_size).unwrap_or("-".to_string());
+    let frames = match (entry.video_frames, entry.audio_frames) {
+        (Some(video_frames), Some(audio_frames)) => format!("{}v/{}a", video_frames, audio_frames),
+        _ => "-".to_string(),
+    };
+    let duration = match entry.kind {
+        Kind::Video => format!("{}s", entry.duration),
+        Kind::Clip => format!("{}s clip", entry.duration),
+        Kind::Snapshot => "snapshot".to_string(),
+    };
+    let requester = match entry.requester {
+        Some(requester) => requester.to_string(),
+        None => "auto".to_string(),
+    };
+
+    let mut line = format!(
+        "{} {} {} {:.1}s {} {} {}",
+        entry.started_at.with_timezone(&Local).format("%m-%d %H:%M"),
+        entry.camera,
+        duration,
+        entry.elapsed_ms as f64 / 1000.0,
+        size,
+        frames,
+        requester
+    );
+
+    match &entry.outcome {
+        Outcome::Sent => line.push_str(" ok"),
+        Outcome::Failed { reason } => {
+            let reason: String = reason.chars().take(MAX_REASON_LEN).collect();
+            line.push_str(" FAILED\n  ");
+            line.push_str(&reason);
+        }
+    }
+
+    escape_html(&line)
+}
+
+/// Replies with the last `count` recordings of `camera`, or of every camera.
+/// Admins see recordings of every chat, other users only those of the current one.
+pub async fn send_history_command(
+    api: Api,
+    command_msg: Message,
+    camera: Option<String>,
+    count: Option<usize>,
+    store: &Store,
+) -> Result<(), BotError> {
+    let camera_config = get_camera_configs(store)?;
+    let user_id = i64::from(command_msg.from.id);
+    let chat_id = i64::from(command_msg.chat.id());
+    let is_admin = camera_config.access.is_admin(user_id);
+
+    let camera_names: Vec<String> = camera.into_iter().collect();
+    let cameras = match select_cameras(camera_config, &camera_names, user_id) {
+        Ok(cameras) => cameras,
+        Err(reason) => {
+            api.send(command_msg.text_reply(reason)).await?;
+            return Ok(());
+        }
+    };
+
+    let count = count.unwrap_or(DEFAULT_COUNT).min(MAX_COUNT);
+    let entries = store.history.recent(count, |entry| {
+        cameras.iter().any(|camera| camera.name == entry.camera)
+            && (is_admin || entry.chat_id == chat_id)
+    });
+
+    if entries.is_empty() {
+        api.send(command_msg.text_reply("No recordings yet."))
+            .await?;
+        return Ok(());
+    }
+
+    let mut table = String::from("time camera duration took size frames by result\n");
+    for entry in &entries {
+        let line = format_entry(entry);
+        if table.len() + line.len() > MAX_MESSAGE_LEN {
+            break;
+        }
+
+        table.push_str(&line);
+        table.push('\n');
+    }
+
+    let mut reply = command_msg.text_reply(format!("<pre>{}</pre>", table));
+    reply.parse_mode(ParseMode::Html);
+    api.send(reply).await?;
+
+    Ok(())
+}
@@ -0,0 +1,90 @@
+use telegram_bot::prelude::*;
+use telegram_bot::{Api, Message};
+
+use crate::error::BotError;
+use crate::send_video_command::{get_camera_configs, select_cameras};
+use crate::store::{ChatPreferences, Store};
+
+#[derive(Debug)]
+pub enum PrefsAction {
+    /// Shows the preferences of the chat.
+    Show,
+
+    /// Cameras recorded when the record command names none.
+    Cameras(Vec<String>),
+
+    /// Duration used when the record command has none.
+    Duration(u64),
+
+    /// Goes back to the camera config defaults.
+    Reset,
+}
+
+fn format_preferences(preferences: &ChatPreferences) -> String {
+    let cameras = if preferences.cameras.is_empty() {
+        "all".to_string()
+    } else {
+        preferences.cameras.join(", ")
+    };
+
+    let duration = match preferences.duration {
+        Some(duration) => format!("{} sec", duration),
+        None => "camera default".to_string(),
+    };
+
+    format!("Cameras: {}\nDuration: {}", cameras, duration)
+}
+
+/// Shows or changes the preferences of the chat of `command_msg`.
+pub async fn send_prefs_command(
+    api: Api,
+    command_msg: Message,
+    action: PrefsAction,
+    store: &Store,
+) -> Result<(), BotError> {
+    let user_id = i64::from(command_msg.from.id);
+    let chat_id = i64::from(command_msg.chat.id());
+
+    match &action {
+        PrefsAction::Show => {
+            let preferences = store.chat_preferences(chat_id);
+            api.send(command_msg.text_reply(format_preferences(&preferences)))
+                .await?;
+            return Ok(());
+        }
+        PrefsAction::Cameras(camera_names) => {
+            let camera_config = get_camera_configs(store)?;
+
+            if let Err(reason) = select_cameras(camera_config, camera_names, user_id) {
+                api.send(command_msg.text_reply(reason)).await?;
+                return Ok(());
+            }
+        }
+        PrefsAction::Duration(_) | PrefsAction::Reset => {}
+    }
+
+    let preferences = store
+        .preferences
+        .update(|preferences| {
+            match action {
+                PrefsAction::Show => {}
+                PrefsAction::Cameras(camera_names) => {
+                    preferences.entry(chat_id).or_default().cameras = camera_names;
+                }
+                PrefsAction::Duration(duration) => {
+                    preferences.entry(chat_id).or_default().duration = Some(duration);
+                }
+                PrefsAction::Reset => {
+                    preferences.remove(&chat_id);
+                }
+            }
+
+            preferences.get(&chat_id).cloned().unwrap_or_default()
+        })
+        .await?;
+
+    api.send(command_msg.text_reply(format_preferences(&preferences)))
+        .await?;
+
+    Ok(())
+}
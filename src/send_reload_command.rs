@@ -0,0 +1,52 @@
+use telegram_bot::prelude::*;
+use telegram_bot::{Api, Message};
+
+use crate::camera_tasks::CameraTasks;
+use crate::config;
+use crate::error::BotError;
+use crate::send_video_command::get_camera_configs;
+use crate::store::Store;
+
+/// Reloads the camera config and replies with what changed, restarting the background
+/// tasks of the cameras that changed. Admins only.
+pub async fn send_reload_command(
+    api: Api,
+    command_msg: Message,
+    store: &Store,
+    camera_tasks: &CameraTasks,
+) -> Result<(), BotError> {
+    let camera_config = get_camera_configs(store)?;
+    let user_id = i64::from(command_msg.from.id);
+
+    if !camera_config.access.is_admin(user_id) {
+        api.send(command_msg.text_reply("Only admins can reload the config."))
+            .await?;
+        return Ok(());
+    }
+
+    let reply = match config::reload() {
+        Ok(diff) => {
+            log::info!("User {} reloaded the camera config, {}", user_id, diff);
+            let changes = camera_tasks.apply_current()?;
+            log::info!("Applied the camera config, {}", changes);
+
+            if changes.is_empty() {
+                format!("Reloaded the camera config, {}.", diff)
+            } else {
+                format!("Reloaded the camera config, {}. Camera {}.", diff, changes)
+            }
+        }
+        Err(err) => {
+            log::error!(
+                "User {} failed to reload the camera config: {}",
+                user_id,
+                err
+            );
+            format!("Kept the previous camera config, {}", err)
+        }
+    };
+
+    api.send(command_msg.text_reply(reply)).await?;
+
+    Ok(())
+}
@@ -0,0 +1,79 @@
+use telegram_bot::prelude::*;
+use telegram_bot::{Api, Message};
+
+use crate::error::BotError;
+use crate::send_video_command::get_camera_configs;
+use crate::store::{Role, Store};
+
+#[derive(Debug)]
+pub enum RoleAction {
+    /// Lists the granted roles.
+    List,
+
+    /// Grants `role` to `user_id`, or revokes its role when `None`.
+    Set { user_id: i64, role: Option<Role> },
+}
+
+/// Manages the roles granted on top of the camera config. Admins only.
+pub async fn send_role_command(
+    api: Api,
+    command_msg: Message,
+    action: RoleAction,
+    store: &Store,
+) -> Result<(), BotError> {
+    let camera_config = get_camera_configs(store)?;
+    let user_id = i64::from(command_msg.from.id);
+
+    if !camera_config.access.is_admin(user_id) {
+        api.send(command_msg.text_reply("Only admins can manage roles."))
+            .await?;
+        return Ok(());
+    }
+
+    let reply = match action {
+        RoleAction::List => {
+            let lines: Vec<String> = store.roles.read(|roles| {
+                roles
+                    .iter()
+                    .map(|(user_id, role)| format!("{}: {}", user_id, role))
+                    .collect()
+            });
+
+            if lines.is_empty() {
+                "No roles granted, only the camera config applies.".to_string()
+            } else {
+                lines.join("\n")
+            }
+        }
+        RoleAction::Set {
+            user_id: target_id,
+            role,
+        } => {
+            store
+                .roles
+                .update(|roles| match role {
+                    Some(role) => roles.insert(target_id, role),
+                    None => roles.remove(&target_id),
+                })
+                .await?;
+            log::info!(
+                "User {} set the role of user {} to {:?}",
+                user_id,
+                target_id,
+                role
+            );
+
+            match role {
+                Some(role) => format!("User {} is now {}.", target_id, role),
+                None => format!(
+                    "User {} has no role anymore, only the camera config applies.",
+                    target_id
+                ),
+            }
+        }
+    };
+
+    api.send(command_msg.text_reply(reply)).await?;
+
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use telegram_bot::prelude::*;
+use telegram_bot::{Api, Message};
+
+use crate::error::BotError;
+use crate::scheduler::{CronExpr, ScheduleConfig, ScheduleKind};
+use crate::send_video_command::{get_camera_configs, select_cameras};
+use crate::store::Store;
+
+#[derive(Debug)]
+pub enum ScheduleAction {
+    /// Schedules `kind` of `camera` in the chat of the command.
+    Add {
+        camera: String,
+        cron: CronExpr,
+        kind: ScheduleKind,
+        duration: Option<u64>,
+    },
+
+    /// Lists the schedules targeting the chat of the command.
+    List,
+
+    /// Removes a schedule added in the chat of the command.
+    Remove { id: u64 },
+}
+
+fn format_schedule(camera: &str, schedule: &ScheduleConfig) -> String {
+    let duration = match (schedule.kind, schedule.duration) {
+        (ScheduleKind::Video, Some(duration)) => format!(" ({} sec)", duration),
+        _ => String::new(),
+    };
+
+    format!(
+        "{} of camera {}{} at '{}'",
+        schedule.kind, camera, duration, schedule.cron
+    )
+}
+
+pub async fn send_schedule_command(
+    api: Api,
+    command_msg: Message,
+    action: ScheduleAction,
+    store: &Store,
+) -> Result<(), BotError> {
+    let camera_config = get_camera_configs(store)?;
+    let user_id = i64::from(command_msg.from.id);
+    let chat_id = i64::from(command_msg.chat.id());
+
+    let reply = match action {
+        ScheduleAction::Add {
+            camera,
+            cron,
+            kind,
+            duration,
+        } => {
+            let camera = match select_cameras(camera_config, &[camera], user_id) {
+                Ok(mut cameras) => cameras.remove(0),
+                Err(reason) => {
+                    api.send(command_msg.text_reply(reason)).await?;
+                    return Ok(());
+                }
+            };
+
+            let schedule = ScheduleConfig {
+                cron,
+                chat_id,
+                kind,
+                duration,
+            };
+            let text = format_schedule(&camera.name, &schedule);
+            let id = store.schedules.add(camera.name, user_id, schedule).await?;
+
+            format!("Added schedule #{}: {}.", id, text)
+        }
+        ScheduleAction::List => {
+            let access = &camera_config.access;
+
+            let configured = camera_config
+                .cameras
+                .iter()
+                .filter(|camera| access.can_view_camera(camera, user_id))
+                .flat_map(|camera| {
+                    camera
+                        .schedules
+                        .iter()
+                        .filter(|schedule| schedule.chat_id == chat_id)
+                        .map(move |schedule| {
+                            format!("config: {}", format_schedule(&camera.name, schedule))
+                        })
+                });
+
+            let stored = store
+                .schedules
+                .list()
+                .into_iter()
+                .filter(|stored| stored.schedule.chat_id == chat_id)
+                .map(|stored| {
+                    format!(
+                        "#{}: {}",
+                        stored.id,
+                        format_schedule(&stored.camera, &stored.schedule)
+                    )
+                });
+
+            let lines: Vec<String> = configured.chain(stored).collect();
+
+            if lines.is_empty() {
+                "No schedules in this chat.".to_string()
+            } else {
+                lines.join("\n")
+            }
+        }
+        ScheduleAction::Remove { id } => {
+            if store.schedules.remove(id, chat_id).await? {
+                format!("Removed schedule #{}.", id)
+            } else {
+                format!("There's no schedule #{} in this chat.", id)
+            }
+        }
+    };
+
+    api.send(command_msg.text_reply(reply)).await?;
+
+    Ok(())
+}
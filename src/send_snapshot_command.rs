@@ -0,0 +1,180 @@
+use chrono::Local;
+use futures::future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio_compat_02::FutureExt;
+
+use telegram_bot::{prelude::*, InputFileUpload};
+use telegram_bot::{Api, Message};
+
+use crate::destination::Destination;
+use crate::error::BotError;
+use crate::history::{History, HistoryEntry, Kind, Outcome};
+use crate::mp4::Mp4RecorderOptions;
+use crate::recording_coordinator::{remove_recording, RecordingCoordinator};
+use crate::send_video_command::{
+    get_camera_configs, report_camera_errors, select_cameras, skip_disarmed, Camera,
+};
+use crate::store::Store;
+
+/// Converts the single-frame `.mp4` into a `.jpg` next to it, using an `ffmpeg` subprocess.
+#[cfg(feature = "ffmpeg-snapshot")]
+async fn convert_to_jpeg(mp4_path: &Path) -> Result<PathBuf, anyhow::Error> {
+    let jpeg_path = mp4_path.with_extension("jpg");
+    let status = tokio::process::Command::new("ffmpeg")
+        .args(["-y", "-loglevel", "error", "-i"])
+        .arg(mp4_path)
+        .args(["-frames:v", "1"])
+        .arg(&jpeg_path)
+        .status()
+        .await?;
+
+    if !status.success() {
+        anyhow::bail!("ffmpeg exited with {}", status);
+    }
+
+    Ok(jpeg_path)
+}
+
+/// Uploads the snapshot as a photo, converting it with `ffmpeg` first.
+#[cfg(feature = "ffmpeg-snapshot")]
+async fn send_snapshot(
+    api: &Api,
+    destination: &Destination,
+    camera: &Camera,
+    output: &Path,
+) -> Result<(), BotError> {
+    let jpeg_path = convert_to_jpeg(output)
+        .await
+        .map_err(|convert_error| BotError::Recording {
+            camera: camera.name.clone(),
+            source: Arc::new(convert_error),
+        })?;
+
+    let photo = InputFileUpload::with_path(jpeg_path.to_string_lossy().into_owned());
+    let result = api.send(destination.photo(photo)).await;
+
+    remove_recording(&jpeg_path).await;
+    result?;
+
+    Ok(())
+}
+
+/// Uploads the snapshot as it is: a single-frame `.mp4`, which Telegram shows as a video.
+#[cfg(not(feature = "ffmpeg-snapshot"))]
+async fn send_snapshot(
+    api: &Api,
+    destination: &Destination,
+    _camera: &Camera,
+    output: &Path,
+) -> Result<(), BotError> {
+    let video = InputFileUpload::with_path(output.to_string_lossy().into_owned());
+    api.send(destination.video(video)).await?;
+
+    Ok(())
+}
+
+/// Takes a snapshot of the camera and sends it, adding the outcome to `history`.
+pub async fn send_snapshot_for_camera(
+    camera: Camera,
+    api: Api,
+    destination: Destination,
+    coordinator: Arc<RecordingCoordinator>,
+    history: &History,
+) -> Result<(), BotError> {
+    let mut entry = HistoryEntry::new(
+        camera.name.clone(),
+        destination.requester(),
+        i64::from(destination.chat_id()),
+    );
+    entry.kind = Kind::Snapshot;
+
+    let started = Instant::now();
+    let result = take_and_send_snapshot(camera, api, destination, coordinator, &mut entry).await;
+
+    entry.elapsed_ms = started.elapsed().as_millis() as u64;
+    if let Err(err) = &result {
+        entry.outcome = Outcome::Failed {
+            reason: err.to_string(),
+        };
+    }
+    history.record(entry).await;
+
+    result
+}
+
+async fn take_and_send_snapshot(
+    camera: Camera,
+    api: Api,
+    destination: Destination,
+    coordinator: Arc<RecordingCoordinator>,
+    entry: &mut HistoryEntry,
+) -> Result<(), BotError> {
+    let mut options = Mp4RecorderOptions::try_from(camera.clone())?;
+    options.output = PathBuf::from(format!("snapshot_{}.mp4", Local::now()));
+
+    if let Err(snapshot_error) = coordinator.snapshot(&camera.name, options.clone()).await {
+        remove_recording(&options.output).await;
+
+        return Err(BotError::Recording {
+            camera: camera.name,
+            source: Arc::new(snapshot_error),
+        });
+    }
+
+    entry.set_size(&options.output).await;
+    let result = send_snapshot(&api, &destination, &camera, &options.output).await;
+
+    remove_recording(&options.output).await;
+
+    result
+}
+
+pub async fn send_snapshot_command(
+    api: Api,
+    command_msg: Message,
+    camera_names: Vec<String>,
+    force: bool,
+    coordinator: Arc<RecordingCoordinator>,
+    store: Arc<Store>,
+) -> Result<(), BotError> {
+    let camera_config = get_camera_configs(&store)?;
+    let user_id = i64::from(command_msg.from.id);
+    let is_admin = camera_config.access.is_admin(user_id);
+
+    let cameras = match select_cameras(camera_config, &camera_names, user_id) {
+        Ok(cameras) => cameras,
+        Err(reason) => {
+            api.send(command_msg.text_reply(reason)).await?;
+            return Ok(());
+        }
+    };
+
+    let cameras = skip_disarmed(
+        &api,
+        &command_msg,
+        cameras,
+        &store.arming,
+        force && is_admin,
+    )
+    .await?;
+
+    let results = future::join_all(cameras.into_iter().map(|camera| {
+        let camera_name = camera.name.clone();
+        let result = send_snapshot_for_camera(
+            camera,
+            api.clone(),
+            Destination::Reply(command_msg.clone()),
+            coordinator.clone(),
+            &store.history,
+        );
+        async move { (camera_name, result.await) }
+    }))
+    .compat()
+    .await;
+
+    report_camera_errors(&api, &Destination::Reply(command_msg), results).await;
+
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use telegram_bot::prelude::*;
+use telegram_bot::{Api, Message};
+
+use crate::error::BotError;
+use crate::send_video_command::{get_camera_configs, select_cameras};
+use crate::store::Store;
+use crate::subscriptions::Subscription;
+
+/// Subscribes (or unsubscribes) the chat of `command_msg` to motion alerts of
+/// the named cameras, or of every camera with motion detection when empty.
+/// Unsubscribing works on any camera, in case its motion detection was turned off since.
+pub async fn send_subscribe_command(
+    api: Api,
+    command_msg: Message,
+    camera_names: Vec<String>,
+    subscribe: bool,
+    store: &Store,
+) -> Result<(), BotError> {
+    let camera_config = get_camera_configs(store)?;
+    let user_id = i64::from(command_msg.from.id);
+    let chat_id = i64::from(command_msg.chat.id());
+
+    let cameras = match select_cameras(camera_config, &camera_names, user_id) {
+        Ok(cameras) => cameras,
+        Err(reason) => {
+            api.send(command_msg.text_reply(reason)).await?;
+            return Ok(());
+        }
+    };
+
+    let (cameras, without_motion): (Vec<_>, Vec<_>) = cameras
+        .into_iter()
+        .partition(|camera| !subscribe || camera.motion.is_some());
+
+    let mut lines = Vec::new();
+
+    if !without_motion.is_empty() && !camera_names.is_empty() {
+        let names: Vec<&str> = without_motion
+            .iter()
+            .map(|camera| camera.name.as_str())
+            .collect();
+        lines.push(format!("No motion detection on: {}.", names.join(", ")));
+    }
+
+    let mut changed = Vec::new();
+    let mut unchanged = Vec::new();
+
+    for camera in &cameras {
+        let was_changed = if subscribe {
+            store
+                .subscriptions
+                .subscribe(&camera.name, Subscription { chat_id, user_id })
+                .await?
+        } else {
+            store
+                .subscriptions
+                .unsubscribe(&camera.name, chat_id)
+                .await?
+        };
+
+        if was_changed {
+            changed.push(camera.name.as_str());
+        } else {
+            unchanged.push(camera.name.as_str());
+        }
+    }
+
+    let (changed_text, unchanged_text) = if subscribe {
+        ("Subscribed to motion alerts of", "Already subscribed to")
+    } else {
+        ("Unsubscribed from motion alerts of", "Wasn't subscribed to")
+    };
+
+    if !changed.is_empty() {
+        lines.push(format!("{} {}.", changed_text, changed.join(", ")));
+    }
+
+    if !unchanged.is_empty() {
+        lines.push(format!("{} {}.", unchanged_text, unchanged.join(", ")));
+    }
+
+    if cameras.is_empty() && camera_names.is_empty() {
+        lines.push("No camera has motion detection.".to_string());
+    }
+
+    api.send(command_msg.text_reply(lines.join("\n"))).await?;
+
+    Ok(())
+}
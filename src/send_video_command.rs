@@ -2,25 +2,165 @@ use chrono::Local;
 use retina::client::{InitialTimestampPolicy, TeardownPolicy, Transport};
 use tokio_compat_02::FutureExt;
 
+use anyhow::anyhow;
 use futures::future;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::{env, fs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use url::Url;
 
-use telegram_bot::{prelude::*, InputFileUpload};
-use telegram_bot::{Api, Message};
+use telegram_bot::{prelude::*, InputFileRef, InputFileUpload};
+use telegram_bot::{Api, Message, MessageKind};
 
-use crate::mp4::{self, Mp4RecorderOptions, Source};
+use crate::alerts::AlertsConfig;
+use crate::arming::Arming;
+use crate::auth::AccessConfig;
+use crate::config::{self, ConfigProblem, ConfigProblems};
+use crate::destination::Destination;
+use crate::error::BotError;
+use crate::h265;
+use crate::history::{History, HistoryEntry, Outcome};
+use crate::motion::MotionConfig;
+use crate::mp4::{Mp4RecorderOptions, Source};
+use crate::mp4_writer::Layout;
+use crate::nvr::NvrConfig;
+use crate::recording_coordinator::{RecordingCoordinator, RecordingStart};
+use crate::scheduler::ScheduleConfig;
+use crate::store::Store;
+use serde::de::IgnoredAny;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// The camera config as written, before `defaults` are applied to the cameras.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct RawCameraConfig {
+    #[serde(default)]
+    defaults: CameraSettings,
+    cameras: Vec<CameraEntry>,
+    #[serde(default)]
+    access: AccessConfig,
+    #[serde(default)]
+    alerts: AlertsConfig,
+}
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
+#[serde(rename_all = "camelCase", try_from = "RawCameraConfig")]
 pub struct CameraConfig {
     pub cameras: Vec<Camera>,
+    pub access: AccessConfig,
+    pub alerts: AlertsConfig,
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+impl TryFrom<RawCameraConfig> for CameraConfig {
+    type Error = ConfigProblems;
+
+    /// Fails on unknown camera fields, which are usually misspelled settings.
+    fn try_from(raw: RawCameraConfig) -> Result<Self, Self::Error> {
+        let unknown_fields: Vec<ConfigProblem> = raw
+            .cameras
+            .iter()
+            .enumerate()
+            .flat_map(|(index, entry)| {
+                entry.unknown_fields.keys().map(move |field| ConfigProblem {
+                    path: format!("cameras[{}].{}", index, field),
+                    message: "unknown field".to_string(),
+                })
+            })
+            .collect();
+
+        if !unknown_fields.is_empty() {
+            return Err(ConfigProblems(unknown_fields));
+        }
+
+        let cameras = raw
+            .cameras
+            .into_iter()
+            .map(|entry| Camera::from_entry(entry, &raw.defaults))
+            .collect();
+
+        Ok(CameraConfig {
+            cameras,
+            access: raw.access,
+            alerts: raw.alerts,
+        })
+    }
+}
+
+/// Recording settings a camera may set itself or inherit from the `defaults` block.
+///
+/// Unknown fields are only denied in `defaults`, serde ignores the attribute when flattened.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+struct CameraSettings {
+    username: Option<String>,
+    password: Option<String>,
+    no_audio: Option<bool>,
+    no_video: Option<bool>,
+    duration: Option<u64>,
+    transport: Option<String>,
+    max_duration: Option<u64>,
+    connect_timeout: Option<u64>,
+    snapshot_timeout: Option<u64>,
+    initial_timestamp: Option<String>,
+    teardown: Option<String>,
+    fragmented: Option<bool>,
+}
+
+impl CameraSettings {
+    /// These settings, taking the unset ones from `defaults`.
+    fn or(self, defaults: &CameraSettings) -> Self {
+        CameraSettings {
+            username: self.username.or_else(|| defaults.username.clone()),
+            password: self.password.or_else(|| defaults.password.clone()),
+            no_audio: self.no_audio.or(defaults.no_audio),
+            no_video: self.no_video.or(defaults.no_video),
+            duration: self.duration.or(defaults.duration),
+            transport: self.transport.or_else(|| defaults.transport.clone()),
+            max_duration: self.max_duration.or(defaults.max_duration),
+            connect_timeout: self.connect_timeout.or(defaults.connect_timeout),
+            snapshot_timeout: self.snapshot_timeout.or(defaults.snapshot_timeout),
+            initial_timestamp: self
+                .initial_timestamp
+                .or_else(|| defaults.initial_timestamp.clone()),
+            teardown: self.teardown.or_else(|| defaults.teardown.clone()),
+            fragmented: self.fragmented.or(defaults.fragmented),
+        }
+    }
+}
+
+/// A camera as written in the config, see [`Camera`] for its fields.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CameraEntry {
+    name: String,
+    url: String,
+    #[serde(flatten)]
+    settings: CameraSettings,
+    #[serde(default)]
+    allowed_user_ids: Vec<i64>,
+    #[serde(default)]
+    nvr: Option<NvrConfig>,
+    #[serde(default)]
+    pre_roll: Option<u64>,
+    #[serde(default)]
+    h265_parameter_sets: Option<String>,
+    #[serde(default)]
+    motion: Option<MotionConfig>,
+    #[serde(default)]
+    schedules: Vec<ScheduleConfig>,
+    #[serde(default = "default_armed")]
+    armed: bool,
+
+    /// Whatever is left once the fields above and the settings are taken,
+    /// since `deny_unknown_fields` doesn't work along with `flatten`.
+    #[serde(flatten)]
+    unknown_fields: BTreeMap<String, IgnoredAny>,
+}
+
+/// A camera with the `defaults` block and the built-in defaults applied.
+#[derive(Debug, Clone, PartialEq, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Camera {
     pub name: String,
@@ -29,20 +169,169 @@ pub struct Camera {
     pub password: String,
     pub no_audio: bool,
     pub no_video: bool,
+
+    /// Recording duration, in seconds, when none is requested.
     pub duration: u64,
+
+    /// `tcp` or `udp`.
     pub transport: String,
+
+    /// Upper bound, in seconds, for durations requested with the record command.
+    pub max_duration: Option<u64>,
+
+    /// Seconds to wait for the camera to answer before giving up, unlimited when unset.
+    pub connect_timeout: Option<u64>,
+
+    /// Seconds to wait for a keyframe when taking a snapshot.
+    pub snapshot_timeout: u64,
+
+    /// How to handle the initial `rtptime` of streams:
+    /// `default`, `require`, `ignore` or `permissive`.
+    pub initial_timestamp: String,
+
+    /// When to send a `TEARDOWN` request: `auto`, `always` or `never`.
+    pub teardown: String,
+
+    /// Writes recordings and segments as fragmented `.mp4` files,
+    /// which stay playable up to the last keyframe if cut short.
+    pub fragmented: bool,
+
+    /// Users allowed to see this camera. Empty means every authorized user.
+    pub allowed_user_ids: Vec<i64>,
+
+    /// Records the camera continuously into segments on disk, when set.
+    pub nvr: Option<NvrConfig>,
+
+    /// Keeps the camera connected and this many seconds buffered in memory,
+    /// so recordings include what happened right before they were requested.
+    pub pre_roll: Option<u64>,
+
+    /// The `sprop-vps`, `sprop-sps` and `sprop-pps` parameters of an H.265 camera
+    /// that only sends its parameter sets in the SDP, which retina doesn't pass on.
+    pub h265_parameter_sets: Option<String>,
+
+    /// Watches the camera for motion, keeping it connected like `pre_roll` does.
+    pub motion: Option<MotionConfig>,
+
+    /// Recordings or snapshots sent to a chat on a cron-like schedule.
+    pub schedules: Vec<ScheduleConfig>,
+
+    /// Whether the camera is armed until changed with the arm and disarm commands.
+    pub armed: bool,
+}
+
+fn default_armed() -> bool {
+    true
+}
+
+/// Recording duration of cameras without `duration`, in seconds.
+const DEFAULT_DURATION: u64 = 5;
+
+/// Upper bound for requested durations of cameras without `maxDuration`.
+const DEFAULT_MAX_DURATION: u64 = 60;
+
+/// Keyframe wait of cameras without `snapshotTimeout`, in seconds.
+const DEFAULT_SNAPSHOT_TIMEOUT: u64 = 10;
+
+/*
+ * During development and tracing I've got some "Invalid RTSP message" errors
+ * and found the following log warn message from the retina package:
+ *
+ * 2023-12-30_09:29:40.74555 [2023-12-30T09:29:40Z WARN  retina::client]
+ * Connecting via TCP to known-broken RTSP server "TP-LINK Streaming Media v2015.05.12".
+ * See <https://github.com/scottlamb/retina/issues/17>.
+ * Consider using UDP instead!
+ *
+ * This is why cameras send a TEARDOWN unless configured otherwise, and why
+ * lost packets are allowed when using UDP. TCP stays the default transport,
+ * since it works with most cameras and through NAT, so TP-Link cameras should
+ * set `transport` to `udp`.
+ *
+ * If you have a different camera, please take that into consideration.
+ */
+const DEFAULT_TEARDOWN: &str = "always";
+
+/// Transport of cameras without `transport`, see above for TP-Link cameras.
+const DEFAULT_TRANSPORT: &str = "tcp";
+
+impl Camera {
+    fn from_entry(entry: CameraEntry, defaults: &CameraSettings) -> Self {
+        let settings = entry.settings.or(defaults);
+
+        Camera {
+            name: entry.name,
+            url: entry.url,
+            username: settings.username.unwrap_or_default(),
+            password: settings.password.unwrap_or_default(),
+            no_audio: settings.no_audio.unwrap_or(false),
+            no_video: settings.no_video.unwrap_or(false),
+            duration: settings.duration.unwrap_or(DEFAULT_DURATION),
+            transport: settings
+                .transport
+                .unwrap_or_else(|| DEFAULT_TRANSPORT.to_string()),
+            max_duration: settings.max_duration,
+            connect_timeout: settings.connect_timeout,
+            snapshot_timeout: settings
+                .snapshot_timeout
+                .unwrap_or(DEFAULT_SNAPSHOT_TIMEOUT),
+            initial_timestamp: settings
+                .initial_timestamp
+                .unwrap_or_else(|| "default".to_string()),
+            teardown: settings
+                .teardown
+                .unwrap_or_else(|| DEFAULT_TEARDOWN.to_string()),
+            fragmented: settings.fragmented.unwrap_or(false),
+            allowed_user_ids: entry.allowed_user_ids,
+            nvr: entry.nvr,
+            pre_roll: entry.pre_roll,
+            h265_parameter_sets: entry.h265_parameter_sets,
+            motion: entry.motion,
+            schedules: entry.schedules,
+            armed: entry.armed,
+        }
+    }
+
+    /// The `requested` duration capped at `max_duration`, or the configured one.
+    pub fn effective_duration(&self, requested: Option<u64>) -> u64 {
+        let max_duration = self.max_duration.unwrap_or(DEFAULT_MAX_DURATION);
+
+        requested
+            .map(|duration| duration.min(max_duration))
+            .unwrap_or(self.duration)
+    }
 }
 
-impl From<Camera> for Mp4RecorderOptions {
-    fn from(camera: Camera) -> Self {
+impl TryFrom<Camera> for Mp4RecorderOptions {
+    type Error = BotError;
+
+    /// Fails on cameras that `CameraConfig::validate` would have rejected.
+    fn try_from(camera: Camera) -> Result<Self, Self::Error> {
         let filename = format!("recording_{}.mp4", Local::now());
         let output = PathBuf::from(Path::new(&filename));
-        let url = Url::parse(&camera.url).unwrap();
-        let transport = Transport::from_str(camera.transport.as_str()).unwrap();
-        let udp_transport = Transport::from_str("udp").unwrap();
-        let is_udp = transport.to_string() == udp_transport.to_string();
+        let invalid = |field: &str, err: String| {
+            BotError::Config(anyhow!(
+                "camera {} has an invalid {}: {}",
+                camera.name,
+                field,
+                err
+            ))
+        };
+        let url = Url::parse(&camera.url).map_err(|err| invalid("url", err.to_string()))?;
+        let transport = Transport::from_str(camera.transport.as_str())
+            .map_err(|err| invalid("transport", err.to_string()))?;
+        let initial_timestamp = InitialTimestampPolicy::from_str(&camera.initial_timestamp)
+            .map_err(|err| invalid("initialTimestamp", err.to_string()))?;
+        let teardown = TeardownPolicy::from_str(&camera.teardown)
+            .map_err(|err| invalid("teardown", err.to_string()))?;
+        let is_udp = matches!(transport, Transport::Udp(_));
+        let h265_parameter_sets = camera
+            .h265_parameter_sets
+            .as_deref()
+            .map(h265::ParameterSets::from_str)
+            .transpose()
+            .map_err(|err| invalid("h265ParameterSets", err.to_string()))?;
 
-        Mp4RecorderOptions {
+        Ok(Mp4RecorderOptions {
             source: Source {
                 url,
                 username: camera.username,
@@ -52,90 +341,199 @@ impl From<Camera> for Mp4RecorderOptions {
             no_video: camera.no_video,
             no_audio: camera.no_audio,
             duration: camera.duration,
-            initial_timestamp: InitialTimestampPolicy::Default,
-            /*
-             * During development and tracing I've got some "Invalid RTSP message" errors
-             * and found the following log warn message from the retina package:
-             *
-             * 2023-12-30_09:29:40.74555 [2023-12-30T09:29:40Z WARN  retina::client]
-             * Connecting via TCP to known-broken RTSP server "TP-LINK Streaming Media v2015.05.12".
-             * See <https://github.com/scottlamb/retina/issues/17>.
-             * Consider using UDP instead!
-             *
-             * This is why all the settings below are being set as such.
-             *
-             * If you have a different camera, please take that into consideration.
-             *
-             * These properties may be loaded from env vars in the future to allow
-             * for more flexibility.
-             */
+            initial_timestamp,
             transport,
-            teardown: TeardownPolicy::Always,
+            teardown,
             allow_loss: is_udp,
-        }
+            connect_timeout: camera.connect_timeout.map(Duration::from_secs),
+            snapshot_timeout: Duration::from_secs(camera.snapshot_timeout),
+            layout: if camera.fragmented {
+                Layout::Fragmented
+            } else {
+                Layout::Progressive
+            },
+            h265_parameter_sets,
+        })
     }
 }
 
-fn get_camera_configs() -> Result<CameraConfig, anyhow::Error> {
-    let config_json_path: PathBuf = env::var("CAMERA_CONFIG_PATH")
-        .expect("CAMERA_CONFIG not set")
-        .into();
-    let config_json = fs::read_to_string(config_json_path)?;
-    let config: CameraConfig = serde_json::from_str(config_json.as_str())
-        .expect("JSON in CAMERA_CONFIG env var is malformed.");
+/// The loaded camera config, with the roles granted through the bot added to it.
+pub fn get_camera_configs(store: &Store) -> Result<CameraConfig, BotError> {
+    let mut config = CameraConfig::clone(&*config::current()?);
+
+    store.roles.read(|roles| config.access.add_roles(roles));
 
     Ok(config)
 }
 
+/// Telegram file id of the video in `message`, used to re-send it without uploading again.
+pub(crate) fn video_file_id(message: &Message) -> Option<String> {
+    match message.kind {
+        MessageKind::Video { ref data, .. } => Some(data.file_id.clone()),
+        MessageKind::Document { ref data, .. } => Some(data.file_id.clone()),
+        _ => None,
+    }
+}
+
+/// Records the camera and sends the video, adding the outcome to `history`.
 pub async fn send_video_for_camera(
     camera: Camera,
     api: Api,
-    command_msg: Message,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let options: Mp4RecorderOptions = camera.clone().into();
+    destination: Destination,
+    duration: Option<u64>,
+    coordinator: Arc<RecordingCoordinator>,
+    history: &History,
+) -> Result<(), BotError> {
+    let mut entry = HistoryEntry::new(
+        camera.name.clone(),
+        destination.requester(),
+        i64::from(destination.chat_id()),
+    );
 
-    let feedback_msg = api
-        .send(command_msg.text_reply(format!(
-            "Recording {} sec video for camera {}..",
-            options.duration, camera.name
-        )))
-        .await?;
+    let started = Instant::now();
+    let result =
+        record_and_send_video(camera, api, destination, duration, coordinator, &mut entry).await;
 
-    let recording_result = mp4::start_recording(options.clone()).compat().await;
+    entry.elapsed_ms = started.elapsed().as_millis() as u64;
+    if let Err(err) = &result {
+        entry.outcome = Outcome::Failed {
+            reason: err.to_string(),
+        };
+    }
+    history.record(entry).await;
 
-    if let Err(recorder_error) = recording_result {
-        log::error!(
-            "Recording for camera {} has failed. Reason in the next message.",
-            camera.name
-        );
-        log::error!("{:?}", recorder_error);
+    result
+}
+
+async fn record_and_send_video(
+    camera: Camera,
+    api: Api,
+    destination: Destination,
+    duration: Option<u64>,
+    coordinator: Arc<RecordingCoordinator>,
+    entry: &mut HistoryEntry,
+) -> Result<(), BotError> {
+    let mut options = Mp4RecorderOptions::try_from(camera.clone())?;
+    options.duration = camera.effective_duration(duration);
+    entry.duration = options.duration;
+    let (recording, start) = coordinator.record(&camera.name, options.clone());
+
+    let capped_note = match duration {
+        Some(requested) if requested > options.duration => {
+            format!(" (capped from {} sec)", requested)
+        }
+        _ => String::new(),
+    };
 
-        let set_error_feedback_msg =
-            feedback_msg.edit_text("Recording has failed. Please try again later.");
+    let pre_roll_note = match camera.pre_roll {
+        Some(pre_roll) => format!(" plus up to {} sec before now", pre_roll),
+        None => String::new(),
+    };
 
-        api.send(set_error_feedback_msg).await?;
-        return Ok(());
-    }
+    let feedback_text = match start {
+        RecordingStart::New => format!(
+            "Recording {} sec video{} for camera {}{}..",
+            options.duration, pre_roll_note, camera.name, capped_note
+        ),
+        RecordingStart::Joined { duration } => {
+            entry.duration = duration;
+
+            // The ongoing recording keeps the duration it was started for.
+            let length_note = if duration != options.duration {
+                format!(", instead of {} sec", options.duration)
+            } else {
+                String::new()
+            };
+
+            format!(
+                "Joining the ongoing {} sec recording for camera {}{}..",
+                duration, camera.name, length_note
+            )
+        }
+    };
+
+    let feedback_msg = api.send(destination.text(feedback_text)).await?;
+
+    let recording = match recording.compat().await {
+        Ok(recording) => recording,
+        Err(recorder_error) => {
+            let recording_error = BotError::Recording {
+                camera: camera.name.clone(),
+                source: recorder_error,
+            };
+
+            log::error!("{}", recording_error);
+            entry.outcome = Outcome::Failed {
+                reason: recording_error.to_string(),
+            };
+
+            let set_error_feedback_msg = feedback_msg.edit_text(format!(
+                "{} Please try again later.",
+                recording_error.user_message()
+            ));
+
+            api.send(set_error_feedback_msg).await?;
+            return Ok(());
+        }
+    };
+
+    entry.set_recording(&recording).await;
+
+    // Audio can't be recorded along with H.265 video yet, see `mp4::setup_audio_stream`.
+    let audio_note = if recording.stats.hevc && !camera.no_audio {
+        " H.265 video is recorded without audio."
+    } else {
+        ""
+    };
 
     let set_success_feedback_msg = api
         .send(feedback_msg.edit_text(format!(
-            "Recording for camera {} done. Uploading.",
-            camera.name
+            "Recording for camera {} done. Uploading.{}",
+            camera.name, audio_note
         )))
         .await?;
 
-    let recording_input_file = InputFileUpload::with_path(
-        options
-            .output
-            .clone()
-            .into_os_string()
-            .into_string()
-            .unwrap(),
-    );
+    // The first requester to get here uploads the file, everyone else
+    // re-sends it by file id once the upload is done.
+    // `Err(None)` means the video was sent, but its file id couldn't be read.
+    let mut uploaded_here = false;
+    let file_id = recording
+        .file_id
+        .get_or_try_init(|| async {
+            uploaded_here = true;
 
-    let _video_reply = api
-        .send(command_msg.video_reply(recording_input_file))
-        .await?;
+            let recording_input_file = InputFileUpload::with_path(
+                recording
+                    .output
+                    .clone()
+                    .into_os_string()
+                    .into_string()
+                    .unwrap(),
+            );
+
+            let video_reply = api
+                .send(destination.video(recording_input_file))
+                .await
+                .map_err(|upload_error| Some(BotError::from(upload_error)))?;
+
+            video_file_id(&video_reply).ok_or(None)
+        })
+        .await;
+
+    match file_id {
+        Ok(file_id) if !uploaded_here => {
+            api.send(destination.video(InputFileRef::new(file_id.clone())))
+                .await?;
+        }
+        Ok(_) => {}
+        Err(None) => {
+            log::warn!(
+                "Uploaded video for camera {} has no file id, other requesters will upload it again",
+                camera.name
+            );
+        }
+        Err(Some(upload_error)) => return Err(upload_error),
+    }
 
     let delete_feedback_msg = api.send(set_success_feedback_msg.delete()).await;
 
@@ -148,35 +546,168 @@ pub async fn send_video_for_camera(
         log::error!("{:?}", delete_feedback_msg_error);
     }
 
-    let file_exists = tokio::fs::try_exists(options.output.clone()).await?;
+    Ok(())
+}
 
-    if file_exists {
-        let remove_file_result = tokio::fs::remove_file(options.output.clone()).await;
+/// Picks the cameras matching `names` by `Camera.name`, or every camera when `names` is empty.
+/// Cameras the user isn't allowed to see are treated as if they didn't exist.
+///
+/// Fails with a user facing message listing the valid names when any of `names` is unknown.
+pub(crate) fn select_cameras(
+    camera_config: CameraConfig,
+    names: &[String],
+    user_id: i64,
+) -> Result<Vec<Camera>, String> {
+    let access = camera_config.access;
+    let visible_cameras: Vec<Camera> = camera_config
+        .cameras
+        .into_iter()
+        .filter(|camera| access.can_view_camera(camera, user_id))
+        .collect();
 
-        if let Err(remove_file_error) = remove_file_result {
-            log::error!("Failed to delete file at '{}'", options.output.display());
-            log::error!("{:?}", remove_file_error);
-        }
+    if names.is_empty() {
+        return Ok(visible_cameras);
     }
 
-    Ok(())
+    let unknown_names: Vec<&str> = names
+        .iter()
+        .filter(|name| !visible_cameras.iter().any(|camera| &camera.name == *name))
+        .map(String::as_str)
+        .collect();
+
+    if !unknown_names.is_empty() {
+        let valid_names: Vec<&str> = visible_cameras
+            .iter()
+            .map(|camera| camera.name.as_str())
+            .collect();
+
+        return Err(format!(
+            "Unknown camera(s): {}. Valid cameras are: {}.",
+            unknown_names.join(", "),
+            valid_names.join(", ")
+        ));
+    }
+
+    Ok(visible_cameras
+        .into_iter()
+        .filter(|camera| names.contains(&camera.name))
+        .collect())
+}
+
+/// Drops the disarmed cameras, telling the user which ones were skipped.
+/// Nothing is dropped when `force` is set, which only admins may do.
+pub(crate) async fn skip_disarmed(
+    api: &Api,
+    command_msg: &Message,
+    cameras: Vec<Camera>,
+    arming: &Arming,
+    force: bool,
+) -> Result<Vec<Camera>, BotError> {
+    if force {
+        return Ok(cameras);
+    }
+
+    let (armed, disarmed) = arming.partition(cameras);
+
+    if !disarmed.is_empty() {
+        let names: Vec<&str> = disarmed.iter().map(|camera| camera.name.as_str()).collect();
+
+        api.send(command_msg.text_reply(format!(
+            "Skipping disarmed camera(s): {}. Admins may add 'force' to the command to use them anyway.",
+            names.join(", ")
+        )))
+        .await?;
+    }
+
+    Ok(armed)
 }
 
 pub async fn send_video_command(
     api: Api,
     command_msg: Message,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let camera_config = get_camera_configs()?;
+    camera_names: Vec<String>,
+    duration: Option<u64>,
+    force: bool,
+    coordinator: Arc<RecordingCoordinator>,
+    store: Arc<Store>,
+) -> Result<(), BotError> {
+    let camera_config = get_camera_configs(&store)?;
+    let user_id = i64::from(command_msg.from.id);
+    let is_admin = camera_config.access.is_admin(user_id);
 
-    let _ = future::try_join_all(
-        camera_config
-            .cameras
-            .into_iter()
-            .map(|camera| send_video_for_camera(camera, api.clone(), command_msg.clone())),
+    // Chat preferences fill in what the command leaves out.
+    let preferences = store.chat_preferences(i64::from(command_msg.chat.id()));
+    let camera_names = if camera_names.is_empty() {
+        preferences.cameras
+    } else {
+        camera_names
+    };
+    let duration = duration.or(preferences.duration);
+
+    let cameras = match select_cameras(camera_config, &camera_names, user_id) {
+        Ok(cameras) => cameras,
+        Err(reason) => {
+            api.send(command_msg.text_reply(reason)).await?;
+            return Ok(());
+        }
+    };
+
+    let cameras = skip_disarmed(
+        &api,
+        &command_msg,
+        cameras,
+        &store.arming,
+        force && is_admin,
     )
+    .await?;
+
+    // Every camera is recorded independently, so a flaky camera
+    // doesn't prevent the others from being delivered.
+    let results = future::join_all(cameras.into_iter().map(|camera| {
+        let camera_name = camera.name.clone();
+        let result = send_video_for_camera(
+            camera,
+            api.clone(),
+            Destination::Reply(command_msg.clone()),
+            duration,
+            coordinator.clone(),
+            &store.history,
+        );
+        async move { (camera_name, result.await) }
+    }))
     .compat()
-    .await
-    .unwrap();
+    .await;
+
+    report_camera_errors(&api, &Destination::Reply(command_msg), results).await;
 
     Ok(())
 }
+
+/// Logs and sends a user facing message for every camera that failed.
+pub(crate) async fn report_camera_errors(
+    api: &Api,
+    destination: &Destination,
+    results: Vec<(String, Result<(), BotError>)>,
+) {
+    for (camera_name, result) in results {
+        if let Err(err) = result {
+            log::error!("Failed to reply for camera {}: {}", camera_name, err);
+
+            let reply = api
+                .send(destination.text(format!(
+                    "Failed to reply for camera {}. {}",
+                    camera_name,
+                    err.user_message()
+                )))
+                .await;
+
+            if let Err(reply_error) = reply {
+                log::error!(
+                    "Failed to report error for camera {}: {}",
+                    camera_name,
+                    reply_error
+                );
+            }
+        }
+    }
+}
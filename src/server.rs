@@ -1,14 +1,364 @@
+use anyhow::anyhow;
+use chrono::NaiveTime;
 use futures::StreamExt;
 use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio::time::sleep;
 use tokio_compat_02::FutureExt;
 
-use telegram_bot::{Api, MessageKind, UpdateKind};
+use telegram_bot::{prelude::*, Api, MessageKind, UpdateKind};
 
-use crate::send_video_command::send_video_command;
+use crate::alerts::run_alerts;
+use crate::auth::report_unauthorized;
+use crate::camera_tasks::CameraTasks;
+use crate::dispatcher::Dispatcher;
+use crate::error::BotError;
+use crate::recording_coordinator::RecordingCoordinator;
+use crate::scheduler::{run_scheduler, CronExpr, ScheduleKind};
+use crate::send_arm_command::{send_arm_command, send_status_command};
+use crate::send_clip_command::send_clip_command;
+use crate::send_history_command::send_history_command;
+use crate::send_prefs_command::{send_prefs_command, PrefsAction};
+use crate::send_reload_command::send_reload_command;
+use crate::send_role_command::{send_role_command, RoleAction};
+use crate::send_schedule_command::{send_schedule_command, ScheduleAction};
+use crate::send_snapshot_command::send_snapshot_command;
+use crate::send_subscribe_command::send_subscribe_command;
+use crate::send_video_command::{get_camera_configs, send_video_command};
+use crate::store::{Role, Store};
+
+/// Delay before polling updates again after the first stream error.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Upper bound for the exponential backoff between stream errors.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
 
 #[derive(Debug)]
 enum Command {
-    GetRecordNow,
+    /// Records from the cameras named in `cameras`, or from all of them when empty.
+    /// `duration` overrides the configured recording duration, in seconds.
+    /// `force` includes disarmed cameras, for admins.
+    GetRecordNow {
+        cameras: Vec<String>,
+        duration: Option<u64>,
+        force: bool,
+    },
+
+    /// Sends a still keyframe from the named cameras, or from all of them when empty.
+    Snapshot { cameras: Vec<String>, force: bool },
+
+    /// Sends stored footage of `camera` starting at the most recent `at` local time.
+    /// `force` allows a disarmed camera, for admins.
+    Clip {
+        camera: String,
+        at: NaiveTime,
+        duration: Option<u64>,
+        force: bool,
+    },
+
+    /// Subscribes the chat to motion alerts of the named cameras, or of all of them when empty.
+    Subscribe { cameras: Vec<String> },
+
+    /// Stops motion alerts of the named cameras in the chat, or of all of them when empty.
+    Unsubscribe { cameras: Vec<String> },
+
+    /// Adds, lists or removes scheduled recordings of the chat.
+    Schedule { action: ScheduleAction },
+
+    /// Arms the named cameras, or every camera when empty.
+    Arm { cameras: Vec<String> },
+
+    /// Disarms the named cameras, or every camera when empty.
+    Disarm { cameras: Vec<String> },
+
+    /// Shows whether cameras are armed.
+    Status,
+
+    /// Reloads the camera config and reports the cameras that changed, for admins.
+    Reload,
+
+    /// Lists or changes roles granted on top of the camera config.
+    Role { action: RoleAction },
+
+    /// Shows or changes the preferences of the chat.
+    Prefs { action: PrefsAction },
+
+    /// Lists the last `count` recordings of `camera`, or of every camera.
+    History {
+        camera: Option<String>,
+        count: Option<usize>,
+    },
+
+    /// A known command with invalid arguments; `reason` is replied to the user.
+    Invalid { reason: String },
+}
+
+/// Splits a comma separated list of camera names, e.g. `cam1,cam3`.
+fn parse_camera_names(arg: Option<&str>) -> Vec<String> {
+    arg.map(|names| {
+        names
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(String::from)
+            .collect()
+    })
+    .unwrap_or_default()
+}
+
+/// Takes the `force` argument out of `args`, returning whether it was there.
+fn take_force_arg(args: &mut Vec<&str>) -> bool {
+    let count = args.len();
+    args.retain(|arg| *arg != "force");
+    args.len() != count
+}
+
+/// Parses `[cameras] [duration] [force]` for the record command, e.g. `cam1,cam3 20` or just `20`.
+fn parse_record_args<'a>(args: impl Iterator<Item = &'a str>) -> Command {
+    let mut args: Vec<&str> = args.collect();
+    let force = take_force_arg(&mut args);
+    let mut args = args.into_iter();
+
+    let first_arg = args.next();
+    let second_arg = args.next();
+
+    let (cameras_arg, duration_arg) = match (first_arg, second_arg) {
+        (Some(arg), None) if arg.parse::<u64>().is_ok() => (None, Some(arg)),
+        (cameras_arg, duration_arg) => (cameras_arg, duration_arg),
+    };
+
+    let duration = match duration_arg.map(str::parse::<u64>) {
+        None => None,
+        Some(Ok(duration)) if duration > 0 => Some(duration),
+        Some(_) => {
+            return Command::Invalid {
+                reason: format!(
+                    "Invalid duration '{}'. It should be a number of seconds, e.g. 20.",
+                    duration_arg.unwrap_or_default()
+                ),
+            }
+        }
+    };
+
+    Command::GetRecordNow {
+        cameras: parse_camera_names(cameras_arg),
+        duration,
+        force,
+    }
+}
+
+/// Parses a duration in seconds, like `30`, `30s` or `2m`.
+/// The error says what's wrong with it, for the reply.
+fn parse_duration(arg: &str) -> Result<u64, &'static str> {
+    let (number, multiplier) = if let Some(minutes) = arg.strip_suffix('m') {
+        (minutes, 60)
+    } else {
+        (arg.strip_suffix('s').unwrap_or(arg), 1)
+    };
+
+    let Ok(duration) = number.parse::<u64>() else {
+        return Err("it should be a number of seconds or minutes, e.g. 30s or 2m");
+    };
+
+    if duration == 0 {
+        return Err("it should be greater than 0");
+    }
+
+    duration
+        .checked_mul(multiplier)
+        .ok_or("it is too long to be recorded")
+}
+
+/// Parses `<camera> <HH:MM[:SS]> [duration] [force]` for the clip command, e.g. `cam1 14:32 30s`.
+fn parse_clip_args<'a>(args: impl Iterator<Item = &'a str>) -> Command {
+    const USAGE: &str =
+        "Usage: /clip <camera> <HH:MM> [duration] [force], e.g. /clip camera1 14:32 30s";
+
+    let mut args: Vec<&str> = args.collect();
+    let force = take_force_arg(&mut args);
+    let mut args = args.into_iter();
+
+    let (Some(camera), Some(at_arg)) = (args.next(), args.next()) else {
+        return Command::Invalid {
+            reason: USAGE.to_string(),
+        };
+    };
+
+    let at = NaiveTime::parse_from_str(at_arg, "%H:%M:%S")
+        .or_else(|_| NaiveTime::parse_from_str(at_arg, "%H:%M"));
+
+    let Ok(at) = at else {
+        return Command::Invalid {
+            reason: format!("Invalid time '{}'. {}", at_arg, USAGE),
+        };
+    };
+
+    let duration = match args.next() {
+        None => None,
+        Some(duration_arg) => match parse_duration(duration_arg) {
+            Ok(duration) => Some(duration),
+            Err(problem) => {
+                return Command::Invalid {
+                    reason: format!(
+                        "Invalid duration '{}', {}. {}",
+                        duration_arg, problem, USAGE
+                    ),
+                }
+            }
+        },
+    };
+
+    Command::Clip {
+        camera: camera.to_string(),
+        at,
+        duration,
+        force,
+    }
+}
+
+/// Parses `add <camera> <cron> [video|snapshot] [duration]`, `list` or `rm <id>` for the
+/// schedule command, e.g. `add camera1 0 8,20 * * * video 30s`.
+fn parse_schedule_args<'a>(mut args: impl Iterator<Item = &'a str>) -> Command {
+    const USAGE: &str = "Usage: /schedule add <camera> <minute> <hour> <day> <month> <weekday> \
+        [video|snapshot] [duration], /schedule list or /schedule rm <id>. \
+        E.g. /schedule add camera1 0 8,20 * * *";
+
+    let invalid = |reason: String| Command::Invalid { reason };
+
+    let action = match args.next() {
+        Some("list") => ScheduleAction::List,
+        Some("rm") => match args.next().map(str::parse::<u64>) {
+            Some(Ok(id)) => ScheduleAction::Remove { id },
+            _ => return invalid(USAGE.to_string()),
+        },
+        Some("add") => {
+            let Some(camera) = args.next() else {
+                return invalid(USAGE.to_string());
+            };
+
+            let cron_fields: Vec<&str> = args.by_ref().take(5).collect();
+            let cron = match cron_fields.join(" ").parse::<CronExpr>() {
+                Ok(cron) => cron,
+                Err(reason) => return invalid(format!("Invalid schedule: {}. {}", reason, USAGE)),
+            };
+
+            let mut kind = ScheduleKind::Video;
+            let mut duration = None;
+
+            for arg in args {
+                match arg {
+                    "video" => kind = ScheduleKind::Video,
+                    "snapshot" => kind = ScheduleKind::Snapshot,
+                    _ => match parse_duration(arg) {
+                        Ok(arg_duration) => duration = Some(arg_duration),
+                        Err(problem) => {
+                            return invalid(format!(
+                                "Invalid argument '{}', {}. {}",
+                                arg, problem, USAGE
+                            ))
+                        }
+                    },
+                }
+            }
+
+            ScheduleAction::Add {
+                camera: camera.to_string(),
+                cron,
+                kind,
+                duration,
+            }
+        }
+        _ => return invalid(USAGE.to_string()),
+    };
+
+    Command::Schedule { action }
+}
+
+/// Parses `[<user id> <admin|user|none>]` for the role command.
+fn parse_role_args<'a>(mut args: impl Iterator<Item = &'a str>) -> Command {
+    const USAGE: &str = "Usage: /role to list roles, or /role <user id> <admin|user|none>";
+
+    let action = match (args.next(), args.next()) {
+        (None, _) => RoleAction::List,
+        (Some(user_id), Some(role)) => {
+            let role = match role {
+                "admin" => Some(Role::Admin),
+                "user" => Some(Role::User),
+                "none" => None,
+                _ => {
+                    return Command::Invalid {
+                        reason: USAGE.to_string(),
+                    }
+                }
+            };
+
+            match user_id.parse::<i64>() {
+                Ok(user_id) => RoleAction::Set { user_id, role },
+                Err(_) => {
+                    return Command::Invalid {
+                        reason: format!("Invalid user id '{}'. {}", user_id, USAGE),
+                    }
+                }
+            }
+        }
+        _ => {
+            return Command::Invalid {
+                reason: USAGE.to_string(),
+            }
+        }
+    };
+
+    Command::Role { action }
+}
+
+/// Parses `[cameras <names>|duration <duration>|reset]` for the prefs command.
+fn parse_prefs_args<'a>(mut args: impl Iterator<Item = &'a str>) -> Command {
+    const USAGE: &str =
+        "Usage: /prefs, /prefs cameras <camera1,camera2>, /prefs duration <duration> or /prefs reset";
+
+    let action = match (args.next(), args.next()) {
+        (None, _) => PrefsAction::Show,
+        (Some("reset"), None) => PrefsAction::Reset,
+        (Some("cameras"), Some(names)) => PrefsAction::Cameras(parse_camera_names(Some(names))),
+        (Some("duration"), Some(duration)) => match parse_duration(duration) {
+            Ok(duration) => PrefsAction::Duration(duration),
+            Err(problem) => {
+                return Command::Invalid {
+                    reason: format!("Invalid duration '{}', {}. {}", duration, problem, USAGE),
+                }
+            }
+        },
+        _ => {
+            return Command::Invalid {
+                reason: USAGE.to_string(),
+            }
+        }
+    };
+
+    Command::Prefs { action }
+}
+
+/// Parses `[camera] [count]` for the history command, e.g. `camera1 20` or just `20`.
+fn parse_history_args<'a>(mut args: impl Iterator<Item = &'a str>) -> Command {
+    let (camera, count_arg) = match args.next() {
+        Some(arg) if arg.parse::<usize>().is_ok() => (None, Some(arg)),
+        Some(camera) => (Some(camera.to_string()), args.next()),
+        None => (None, None),
+    };
+
+    let count = match count_arg.map(str::parse::<usize>) {
+        None => None,
+        Some(Ok(count)) if count > 0 => Some(count),
+        Some(_) => {
+            return Command::Invalid {
+                reason: "Usage: /history [camera] [count], e.g. /history camera1 20".to_string(),
+            }
+        }
+    };
+
+    Command::History { camera, count }
 }
 
 fn get_command(message: &str, bot_name: &str) -> Option<Command> {
@@ -16,8 +366,10 @@ fn get_command(message: &str, bot_name: &str) -> Option<Command> {
         return None;
     }
 
+    let mut args = message.split_whitespace();
+    let mut cmd = args.next()?;
+
     // splits the bot name from the command, in case it is there
-    let mut cmd = message;
     if cmd.ends_with(bot_name) {
         cmd = cmd.rsplit_once('@').unwrap().0;
     }
@@ -26,20 +378,129 @@ fn get_command(message: &str, bot_name: &str) -> Option<Command> {
         env::var("GET_RECORD_COMMAND").unwrap_or("/camera_now".to_string());
 
     if cmd == get_record_now_command {
-        return Some(Command::GetRecordNow);
+        return Some(parse_record_args(args));
+    }
+
+    let snapshot_command = env::var("SNAPSHOT_COMMAND").unwrap_or("/snapshot".to_string());
+
+    if cmd == snapshot_command {
+        let mut args: Vec<&str> = args.collect();
+        let force = take_force_arg(&mut args);
+
+        return Some(Command::Snapshot {
+            cameras: parse_camera_names(args.first().copied()),
+            force,
+        });
+    }
+
+    let clip_command = env::var("CLIP_COMMAND").unwrap_or("/clip".to_string());
+
+    if cmd == clip_command {
+        return Some(parse_clip_args(args));
+    }
+
+    let subscribe_command = env::var("SUBSCRIBE_COMMAND").unwrap_or("/subscribe".to_string());
+
+    if cmd == subscribe_command {
+        return Some(Command::Subscribe {
+            cameras: parse_camera_names(args.next()),
+        });
+    }
+
+    let unsubscribe_command = env::var("UNSUBSCRIBE_COMMAND").unwrap_or("/unsubscribe".to_string());
+
+    if cmd == unsubscribe_command {
+        return Some(Command::Unsubscribe {
+            cameras: parse_camera_names(args.next()),
+        });
+    }
+
+    let arm_command = env::var("ARM_COMMAND").unwrap_or("/arm".to_string());
+
+    if cmd == arm_command {
+        return Some(Command::Arm {
+            cameras: parse_camera_names(args.next()),
+        });
+    }
+
+    let disarm_command = env::var("DISARM_COMMAND").unwrap_or("/disarm".to_string());
+
+    if cmd == disarm_command {
+        return Some(Command::Disarm {
+            cameras: parse_camera_names(args.next()),
+        });
+    }
+
+    let status_command = env::var("STATUS_COMMAND").unwrap_or("/status".to_string());
+
+    if cmd == status_command {
+        return Some(Command::Status);
+    }
+
+    let reload_command = env::var("RELOAD_COMMAND").unwrap_or("/reload".to_string());
+
+    if cmd == reload_command {
+        return Some(Command::Reload);
+    }
+
+    let role_command = env::var("ROLE_COMMAND").unwrap_or("/role".to_string());
+
+    if cmd == role_command {
+        return Some(parse_role_args(args));
+    }
+
+    let prefs_command = env::var("PREFS_COMMAND").unwrap_or("/prefs".to_string());
+
+    if cmd == prefs_command {
+        return Some(parse_prefs_args(args));
+    }
+
+    let history_command = env::var("HISTORY_COMMAND").unwrap_or("/history".to_string());
+
+    if cmd == history_command {
+        return Some(parse_history_args(args));
+    }
+
+    let schedule_command = env::var("SCHEDULE_COMMAND").unwrap_or("/schedule".to_string());
+
+    if cmd == schedule_command {
+        return Some(parse_schedule_args(args));
     }
 
     None
 }
 
-pub async fn start_telegram_server() -> Result<(), Box<dyn std::error::Error>> {
+pub async fn start_telegram_server(
+    dispatcher: Arc<Dispatcher>,
+    coordinator: Arc<RecordingCoordinator>,
+    store: Arc<Store>,
+    camera_tasks: Arc<CameraTasks>,
+) -> Result<(), BotError> {
     log::info!("Starting telegram server..");
 
-    let bot_name = env::var("TELEGRAM_BOT_NAME").expect("TELEGRAM_BOT_NAME not set");
-    let token = env::var("TELEGRAM_BOT_TOKEN").expect("TELEGRAM_BOT_TOKEN not set");
+    let bot_name = env::var("TELEGRAM_BOT_NAME")
+        .map_err(|_| BotError::Config(anyhow!("TELEGRAM_BOT_NAME not set")))?;
+    let token = env::var("TELEGRAM_BOT_TOKEN")
+        .map_err(|_| BotError::Config(anyhow!("TELEGRAM_BOT_TOKEN not set")))?;
 
     let api = Api::new(token);
     let mut stream = api.stream();
+    let mut backoff = INITIAL_BACKOFF;
+
+    // Alerts and schedules stop along with the server, the set aborts them on drop.
+    let mut background_tasks = JoinSet::new();
+    background_tasks.spawn(run_alerts(
+        api.clone(),
+        camera_tasks.motion_events(),
+        coordinator.clone(),
+        store.clone(),
+        dispatcher.clone(),
+    ));
+    background_tasks.spawn(run_scheduler(
+        api.clone(),
+        coordinator.clone(),
+        store.clone(),
+    ));
 
     // .compat() is needed here
     // because reqwest uses tokio 0.2
@@ -49,26 +510,211 @@ pub async fn start_telegram_server() -> Result<(), Box<dyn std::error::Error>> {
     // tokio-compat-02 package to allow different libraries using
     // different tokio runtimes to work in the same process
     while let Some(update) = stream.next().compat().await {
-        if let Err(err) = update {
-            log::error!("Intercepting error from stream. Panicking the process. Reason:");
-            log::error!("{:?}", err);
+        let update = match update {
+            Ok(update) => {
+                backoff = INITIAL_BACKOFF;
+                update
+            }
+            Err(err) => {
+                log::error!(
+                    "Intercepting error from stream. Retrying in {:?}. Reason:",
+                    backoff
+                );
+                log::error!("{:?}", err);
 
-            panic!("{:?}", err);
+                sleep(backoff).await;
+                backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+                continue;
+            }
         };
 
-        if let UpdateKind::Message(message) = update?.kind {
+        if let UpdateKind::Message(message) = update.kind {
             if let MessageKind::Text { ref data, .. } = message.kind {
                 let command = get_command(data.as_str(), bot_name.as_str());
                 let api = api.clone();
 
-                if let Some(Command::GetRecordNow) = command {
-                    log::debug!("Triggering {:?} command", Command::GetRecordNow);
-                    let result = send_video_command(api, message).compat().await;
+                if command.is_none() {
+                    continue;
+                }
 
-                    if let Err(err) = result {
-                        log::error!("{:?}", err);
-                        panic!("Failed to reply send video command. Panicking server so that a reboot happens.");
+                let camera_config = match get_camera_configs(&store) {
+                    Ok(camera_config) => camera_config,
+                    Err(config_error) => {
+                        log::error!("Failed to load camera config. Ignoring command.");
+                        log::error!("{:?}", config_error);
+                        continue;
                     }
+                };
+
+                if !camera_config.access.is_authorized(&message) {
+                    report_unauthorized(&api, &camera_config.access, &message, data)
+                        .compat()
+                        .await;
+                    continue;
+                }
+
+                match command {
+                    Some(Command::GetRecordNow {
+                        cameras,
+                        duration,
+                        force,
+                    }) => {
+                        log::debug!(
+                            "Triggering GetRecordNow command for cameras {:?} with duration {:?}",
+                            cameras,
+                            duration
+                        );
+                        let task = send_video_command(
+                            api.clone(),
+                            message.clone(),
+                            cameras,
+                            duration,
+                            force,
+                            coordinator.clone(),
+                            store.clone(),
+                        );
+
+                        dispatcher.dispatch(api, message, async move {
+                            if let Err(err) = task.compat().await {
+                                log::error!("Failed to reply send video command: {}", err);
+                            }
+                        });
+                    }
+                    Some(Command::Snapshot { cameras, force }) => {
+                        log::debug!("Triggering Snapshot command for cameras {:?}", cameras);
+                        let task = send_snapshot_command(
+                            api.clone(),
+                            message.clone(),
+                            cameras,
+                            force,
+                            coordinator.clone(),
+                            store.clone(),
+                        );
+
+                        dispatcher.dispatch(api, message, async move {
+                            if let Err(err) = task.compat().await {
+                                log::error!("Failed to reply snapshot command: {}", err);
+                            }
+                        });
+                    }
+                    Some(Command::Clip {
+                        camera,
+                        at,
+                        duration,
+                        force,
+                    }) => {
+                        log::debug!(
+                            "Triggering Clip command for camera {} at {} with duration {:?}",
+                            camera,
+                            at,
+                            duration
+                        );
+                        let task = send_clip_command(
+                            api.clone(),
+                            message.clone(),
+                            camera,
+                            at,
+                            duration,
+                            force,
+                            store.clone(),
+                        );
+
+                        dispatcher.dispatch(api, message, async move {
+                            if let Err(err) = task.compat().await {
+                                log::error!("Failed to reply clip command: {}", err);
+                            }
+                        });
+                    }
+                    Some(Command::Subscribe { cameras }) => {
+                        log::debug!("Triggering Subscribe command for cameras {:?}", cameras);
+                        let result = send_subscribe_command(api, message, cameras, true, &store);
+
+                        if let Err(err) = result.compat().await {
+                            log::error!("Failed to reply subscribe command: {}", err);
+                        }
+                    }
+                    Some(Command::Unsubscribe { cameras }) => {
+                        log::debug!("Triggering Unsubscribe command for cameras {:?}", cameras);
+                        let result = send_subscribe_command(api, message, cameras, false, &store);
+
+                        if let Err(err) = result.compat().await {
+                            log::error!("Failed to reply unsubscribe command: {}", err);
+                        }
+                    }
+                    Some(Command::Schedule { action }) => {
+                        log::debug!("Triggering Schedule command {:?}", action);
+                        let result = send_schedule_command(api, message, action, &store);
+
+                        if let Err(err) = result.compat().await {
+                            log::error!("Failed to reply schedule command: {}", err);
+                        }
+                    }
+                    Some(Command::Arm { cameras }) => {
+                        log::debug!("Triggering Arm command for cameras {:?}", cameras);
+                        let result = send_arm_command(api, message, cameras, true, &store);
+
+                        if let Err(err) = result.compat().await {
+                            log::error!("Failed to reply arm command: {}", err);
+                        }
+                    }
+                    Some(Command::Disarm { cameras }) => {
+                        log::debug!("Triggering Disarm command for cameras {:?}", cameras);
+                        let result = send_arm_command(api, message, cameras, false, &store);
+
+                        if let Err(err) = result.compat().await {
+                            log::error!("Failed to reply disarm command: {}", err);
+                        }
+                    }
+                    Some(Command::Status) => {
+                        log::debug!("Triggering Status command");
+                        let result = send_status_command(api, message, &store);
+
+                        if let Err(err) = result.compat().await {
+                            log::error!("Failed to reply status command: {}", err);
+                        }
+                    }
+                    Some(Command::Reload) => {
+                        log::debug!("Triggering Reload command");
+                        let result = send_reload_command(api, message, &store, &camera_tasks);
+
+                        if let Err(err) = result.compat().await {
+                            log::error!("Failed to reply reload command: {}", err);
+                        }
+                    }
+                    Some(Command::Role { action }) => {
+                        log::debug!("Triggering Role command {:?}", action);
+                        let result = send_role_command(api, message, action, &store);
+
+                        if let Err(err) = result.compat().await {
+                            log::error!("Failed to reply role command: {}", err);
+                        }
+                    }
+                    Some(Command::Prefs { action }) => {
+                        log::debug!("Triggering Prefs command {:?}", action);
+                        let result = send_prefs_command(api, message, action, &store);
+
+                        if let Err(err) = result.compat().await {
+                            log::error!("Failed to reply prefs command: {}", err);
+                        }
+                    }
+                    Some(Command::History { camera, count }) => {
+                        log::debug!(
+                            "Triggering History command for camera {:?} with count {:?}",
+                            camera,
+                            count
+                        );
+                        let result = send_history_command(api, message, camera, count, &store);
+
+                        if let Err(err) = result.compat().await {
+                            log::error!("Failed to reply history command: {}", err);
+                        }
+                    }
+                    Some(Command::Invalid { reason }) => {
+                        if let Err(err) = api.send(message.text_reply(reason)).compat().await {
+                            log::error!("Failed to reply invalid command: {:?}", err);
+                        }
+                    }
+                    None => {}
                 }
             }
         }
@@ -76,3 +722,26 @@ pub async fn start_telegram_server() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_duration_reads_seconds_and_minutes() {
+        assert_eq!(parse_duration("30"), Ok(30));
+        assert_eq!(parse_duration("30s"), Ok(30));
+        assert_eq!(parse_duration("2m"), Ok(120));
+    }
+
+    #[test]
+    fn parse_duration_rejects_zero_garbage_and_overflows() {
+        assert!(parse_duration("0s").is_err());
+        assert!(parse_duration("soon").is_err());
+        assert!(parse_duration("-5").is_err());
+        assert_eq!(
+            parse_duration(&format!("{}m", u64::MAX / 2)),
+            Err("it is too long to be recorded")
+        );
+    }
+}
@@ -0,0 +1,174 @@
+//! Bot state that survives restarts: subscriptions, schedules, arming, roles,
+//! chat preferences and recording history, kept as JSON files in `STATE_DIR`.
+
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::{env, fs, io};
+
+use crate::arming::Arming;
+use crate::error::BotError;
+use crate::history::History;
+use crate::scheduler::Schedules;
+use crate::subscriptions::Subscriptions;
+
+/// Directory of the state files, from `STATE_DIR` (default `state`).
+pub fn state_dir() -> PathBuf {
+    env::var("STATE_DIR").unwrap_or("state".to_string()).into()
+}
+
+/// Reads a JSON file, or the default value when it doesn't exist yet.
+pub fn read_json_file<T: DeserializeOwned + Default>(path: &Path) -> Result<T, BotError> {
+    match fs::read_to_string(path) {
+        Ok(json) => serde_json::from_str(&json)
+            .with_context(|| format!("state file at '{}' is malformed", path.display()))
+            .map_err(BotError::Config),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(T::default()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// A value kept in memory and written to a JSON file on every update.
+pub struct JsonFile<T> {
+    path: PathBuf,
+    value: Mutex<T>,
+
+    /// Held while saving, so concurrent updates are saved one after the other.
+    saving: tokio::sync::Mutex<()>,
+}
+
+impl<T: Serialize + DeserializeOwned + Default + Clone> JsonFile<T> {
+    pub fn open(path: PathBuf) -> Result<Self, BotError> {
+        let value = read_json_file(&path)?;
+
+        Ok(JsonFile {
+            path,
+            value: Mutex::new(value),
+            saving: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    pub fn read<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        f(&self.value.lock().unwrap())
+    }
+
+    /// Changes the value and saves it. Returns what `f` returns.
+    /// The value in memory only changes once it's saved, so it never diverges from the file.
+    pub async fn update<R>(&self, f: impl FnOnce(&mut T) -> R) -> Result<R, BotError> {
+        let _saving = self.saving.lock().await;
+
+        let mut updated = self.read(T::clone);
+        let result = f(&mut updated);
+
+        let json = serde_json::to_string_pretty(&updated)
+            .with_context(|| format!("failed to serialize '{}'", self.path.display()))
+            .map_err(BotError::Config)?;
+
+        // Written next to its final path first, so a crash never leaves it half written.
+        let tmp_path = self.path.with_extension("json.tmp");
+        tokio::fs::write(&tmp_path, json).await?;
+        tokio::fs::rename(&tmp_path, &self.path).await?;
+
+        *self.value.lock().unwrap() = updated;
+        Ok(result)
+    }
+}
+
+/// Copies the state file named by the `legacy_var` env var to `path`, unless `path`
+/// already exists. Subscriptions, schedules and arming were kept at `SUBSCRIPTIONS_PATH`,
+/// `SCHEDULES_PATH` and `ARMING_PATH` before `STATE_DIR`.
+fn migrate_legacy_file(path: &Path, legacy_var: &str) -> Result<(), BotError> {
+    let Ok(legacy_path) = env::var(legacy_var) else {
+        return Ok(());
+    };
+
+    if path.exists() {
+        return Ok(());
+    }
+
+    let tmp_path = path.with_extension("json.tmp");
+    match fs::copy(&legacy_path, &tmp_path) {
+        Ok(_) => {}
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err.into()),
+    }
+    fs::rename(&tmp_path, path)?;
+
+    log::info!(
+        "Copied '{}' from {} to '{}', the old file can be removed",
+        legacy_path,
+        legacy_var,
+        path.display()
+    );
+
+    Ok(())
+}
+
+/// What a user may do, on top of the `access` section of the camera config.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    /// Same as being listed in `adminIds`.
+    Admin,
+    /// Same as being listed in `allowedUserIds`.
+    User,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Admin => f.write_str("admin"),
+            Role::User => f.write_str("user"),
+        }
+    }
+}
+
+pub type Roles = BTreeMap<i64, Role>;
+
+/// Defaults of a chat for the record command, used when it doesn't name cameras or a duration.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ChatPreferences {
+    pub cameras: Vec<String>,
+    pub duration: Option<u64>,
+}
+
+pub struct Store {
+    pub subscriptions: Subscriptions,
+    pub schedules: Schedules,
+    pub arming: Arming,
+    pub roles: JsonFile<Roles>,
+    pub preferences: JsonFile<BTreeMap<i64, ChatPreferences>>,
+    pub history: History,
+}
+
+impl Store {
+    /// Opens every state file in `STATE_DIR`, creating the directory if needed.
+    pub fn open() -> Result<Self, BotError> {
+        let dir = state_dir();
+        fs::create_dir_all(&dir)?;
+
+        migrate_legacy_file(&dir.join("subscriptions.json"), "SUBSCRIPTIONS_PATH")?;
+        migrate_legacy_file(&dir.join("schedules.json"), "SCHEDULES_PATH")?;
+        migrate_legacy_file(&dir.join("arming.json"), "ARMING_PATH")?;
+
+        Ok(Store {
+            subscriptions: Subscriptions::open(dir.join("subscriptions.json"))?,
+            schedules: Schedules::open(dir.join("schedules.json"))?,
+            arming: Arming::open(dir.join("arming.json"))?,
+            roles: JsonFile::open(dir.join("roles.json"))?,
+            preferences: JsonFile::open(dir.join("preferences.json"))?,
+            history: History::open(dir.join("history.jsonl"))?,
+        })
+    }
+
+    pub fn chat_preferences(&self, chat_id: i64) -> ChatPreferences {
+        self.preferences
+            .read(|preferences| preferences.get(&chat_id).cloned())
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,79 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use crate::error::BotError;
+use crate::store::JsonFile;
+
+/// A chat getting motion alerts of a camera, and the user who subscribed it.
+/// Alerts stop when that user loses access to the camera.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Subscription {
+    pub chat_id: i64,
+    pub user_id: i64,
+}
+
+/// Motion alert subscriptions by camera name.
+pub struct Subscriptions {
+    by_camera: JsonFile<BTreeMap<String, Vec<Subscription>>>,
+}
+
+impl Subscriptions {
+    pub fn open(path: PathBuf) -> Result<Self, BotError> {
+        Ok(Subscriptions {
+            by_camera: JsonFile::open(path)?,
+        })
+    }
+
+    /// Subscriptions to the motion alerts of `camera_name`.
+    pub fn for_camera(&self, camera_name: &str) -> Vec<Subscription> {
+        self.by_camera
+            .read(|by_camera| by_camera.get(camera_name).cloned())
+            .unwrap_or_default()
+    }
+
+    /// Subscribes the chat of `subscription` to `camera_name`. Returns false when it already was.
+    pub async fn subscribe(
+        &self,
+        camera_name: &str,
+        subscription: Subscription,
+    ) -> Result<bool, BotError> {
+        self.by_camera
+            .update(|by_camera| {
+                let subscriptions = by_camera.entry(camera_name.to_string()).or_default();
+
+                if subscriptions
+                    .iter()
+                    .any(|existing| existing.chat_id == subscription.chat_id)
+                {
+                    return false;
+                }
+
+                subscriptions.push(subscription);
+                true
+            })
+            .await
+    }
+
+    /// Unsubscribes `chat_id` from `camera_name`. Returns false when it wasn't subscribed.
+    pub async fn unsubscribe(&self, camera_name: &str, chat_id: i64) -> Result<bool, BotError> {
+        self.by_camera
+            .update(|by_camera| {
+                let Some(subscriptions) = by_camera.get_mut(camera_name) else {
+                    return false;
+                };
+
+                let count = subscriptions.len();
+                subscriptions.retain(|subscription| subscription.chat_id != chat_id);
+                let was_subscribed = subscriptions.len() != count;
+
+                if subscriptions.is_empty() {
+                    by_camera.remove(camera_name);
+                }
+
+                was_subscribed
+            })
+            .await
+    }
+}